@@ -0,0 +1,477 @@
+// Browser-valid certificates for operator-owned decoy domains from an ACME CA
+// such as Let's Encrypt (RFC 8555). Validation uses TLS-ALPN-01 (RFC 8737),
+// answered by the persona's own TLS listener, so the domains must resolve to
+// the honeypot and port 443 must be reachable. Until the first certificate is
+// issued the persona keeps serving its self-signed one.
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rcgen::{CertificateParams, CustomExtension, KeyPair};
+use reqwest::header::{HeaderMap, CONTENT_TYPE, LOCATION};
+use reqwest::{Client, Response};
+use ring::digest::{digest, SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use time::{Date, Month, OffsetDateTime, PrimitiveDateTime, Time};
+use tokio::time::sleep;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use crate::der;
+use crate::prelude::*;
+use crate::tls::{self, CertResolver};
+
+// Renew once the certificate has less than this left.
+const RENEW_BEFORE: time::Duration = time::Duration::days(30);
+// How often the certificate's expiry is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+// Back-off after a failed issuance, to stay well inside CA rate limits.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: u32 = 30;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AcmeConfig {
+	#[serde(default)]
+	pub enabled: bool,
+	// The persona whose TLS listeners present the certificate.
+	#[serde(default = "AcmeConfig::default_persona")]
+	pub persona: String,
+	// Decoy domains to put on the certificate. All must point at the persona.
+	#[serde(default)]
+	pub domains: Vec<String>,
+	// Email the CA uses for expiry and policy notices.
+	#[serde(default)]
+	pub contact: Option<String>,
+	#[serde(default = "AcmeConfig::default_directory_url")]
+	pub directory_url: String,
+	// Account key and issued certificates are kept here.
+	#[serde(default = "AcmeConfig::default_cache_dir")]
+	pub cache_dir: String,
+}
+
+impl AcmeConfig {
+	fn default_persona() -> String {
+		"default".to_string()
+	}
+
+	fn default_directory_url() -> String {
+		"https://acme-v02.api.letsencrypt.org/directory".to_string()
+	}
+
+	fn default_cache_dir() -> String {
+		"./certs/acme".to_string()
+	}
+}
+
+impl Default for AcmeConfig {
+	fn default() -> Self {
+		AcmeConfig {
+			enabled: false,
+			persona: Self::default_persona(),
+			domains: vec![],
+			contact: None,
+			directory_url: Self::default_directory_url(),
+			cache_dir: Self::default_cache_dir(),
+		}
+	}
+}
+
+// Keep the persona's certificate issued and renewed for as long as the process runs.
+pub fn spawn(config: AcmeConfig, client: Client, resolver: Arc<CertResolver>) {
+	if config.domains.is_empty() {
+		error!("ACME is enabled for persona {} but no domains are configured", config.persona);
+		return;
+	}
+	task::spawn(async move {
+		loop {
+			let wait = match renew_if_needed(&config, &client, &resolver).await {
+				Ok(()) => CHECK_INTERVAL,
+				Err(e) => {
+					error!("ACME certificate issuance for {:?} failed: {}", config.domains, e);
+					RETRY_INTERVAL
+				}
+			};
+			sleep(wait).await;
+		}
+	});
+}
+
+fn certificate_paths(config: &AcmeConfig) -> (PathBuf, PathBuf) {
+	let dir = Path::new(&config.cache_dir);
+	(dir.join(format!("{}.crt", config.persona)), dir.join(format!("{}.key", config.persona)))
+}
+
+// Serve the cached certificate if it is still good for a while, otherwise
+// order a new one.
+async fn renew_if_needed(config: &AcmeConfig, client: &Client, resolver: &CertResolver) -> Result<(), Box<dyn Error + Send + Sync>> {
+	let (cert_path, key_path) = certificate_paths(config);
+	if let (Ok(cert_pem), Ok(key_pem)) = (fs::read(&cert_path), fs::read(&key_path)) {
+		let expires = CertificateDer::pem_slice_iter(&cert_pem).next().and_then(|cert| not_after(&cert.ok()?));
+		if expires.is_some_and(|expires| expires - OffsetDateTime::now_utc() > RENEW_BEFORE) {
+			resolver.set_certificate(tls::certified_key(&cert_pem, &key_pem)?);
+			return Ok(());
+		}
+	}
+
+	info!("Requesting a certificate for {:?} from {}", config.domains, config.directory_url);
+	fs::create_dir_all(&config.cache_dir)?;
+	let account_key = load_or_create_account_key(&Path::new(&config.cache_dir).join("account.pk8"))?;
+	let mut acme = AcmeClient::new(client, &config.directory_url, &account_key).await?;
+	let (cert_pem, key_pem) = acme.issue(&config.domains, config.contact.as_deref(), resolver).await?;
+	fs::write(&cert_path, &cert_pem)?;
+	fs::write(&key_path, &key_pem)?;
+	resolver.set_certificate(tls::certified_key(cert_pem.as_bytes(), key_pem.as_bytes())?);
+	info!("Installed ACME certificate for {:?}", config.domains);
+	Ok(())
+}
+
+fn load_or_create_account_key(path: &Path) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+	if let Ok(pkcs8) = fs::read(path) {
+		return Ok(pkcs8);
+	}
+	let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+		.map_err(|_| "failed to generate ACME account key")?;
+	fs::write(path, pkcs8.as_ref())?;
+	Ok(pkcs8.as_ref().to_vec())
+}
+
+fn b64(data: impl AsRef<[u8]>) -> String {
+	URL_SAFE_NO_PAD.encode(data)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+	new_nonce: String,
+	new_account: String,
+	new_order: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Order {
+	status: String,
+	#[serde(default)]
+	authorizations: Vec<String>,
+	finalize: String,
+	certificate: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Authorization {
+	status: String,
+	identifier: Identifier,
+	challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Identifier {
+	value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Challenge {
+	#[serde(rename = "type")]
+	kind: String,
+	url: String,
+	token: String,
+}
+
+// One conversation with the CA, signing every request with the account key.
+struct AcmeClient<'a> {
+	client: &'a Client,
+	directory: Directory,
+	key: EcdsaKeyPair,
+	rng: SystemRandom,
+	// Account URL, used instead of the public key once the account exists.
+	kid: Option<String>,
+	nonce: Option<String>,
+}
+
+impl<'a> AcmeClient<'a> {
+	async fn new(client: &'a Client, directory_url: &str, account_key: &[u8]) -> Result<AcmeClient<'a>, Box<dyn Error + Send + Sync>> {
+		let rng = SystemRandom::new();
+		let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, account_key, &rng)
+			.map_err(|_| "invalid ACME account key")?;
+		let directory = client.get(directory_url).send().await?.error_for_status()?.json().await?;
+		Ok(AcmeClient { client, directory, key, rng, kid: None, nonce: None })
+	}
+
+	fn jwk(&self) -> Value {
+		// Uncompressed P-256 point: 0x04 || x || y
+		let point = self.key.public_key().as_ref();
+		json!({ "crv": "P-256", "kty": "EC", "x": b64(&point[1..33]), "y": b64(&point[33..65]) })
+	}
+
+	// RFC 7638 thumbprint: SHA-256 over the JWK's required members, sorted, without whitespace.
+	fn thumbprint(&self) -> String {
+		let jwk = self.jwk();
+		let canonical = format!(r#"{{"crv":"P-256","kty":"EC","x":{},"y":{}}}"#, jwk["x"], jwk["y"]);
+		b64(digest(&SHA256, canonical.as_bytes()))
+	}
+
+	async fn new_nonce(&self) -> Result<String, Box<dyn Error + Send + Sync>> {
+		let response = self.client.head(&self.directory.new_nonce).send().await?;
+		replay_nonce(response.headers()).ok_or_else(|| "CA did not return a nonce".into())
+	}
+
+	// POST a JWS to `url`. No payload means POST-as-GET.
+	async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<Response, Box<dyn Error + Send + Sync>> {
+		for attempt in 0..2 {
+			let nonce = match self.nonce.take() {
+				Some(nonce) => nonce,
+				None => self.new_nonce().await?,
+			};
+			let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+			match &self.kid {
+				Some(kid) => protected["kid"] = json!(kid),
+				None => protected["jwk"] = self.jwk(),
+			}
+			let protected = b64(protected.to_string());
+			let payload = payload.map(|p| b64(p.to_string())).unwrap_or_default();
+			let signature = self.key
+				.sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+				.map_err(|_| "failed to sign ACME request")?;
+			let body = json!({ "protected": protected, "payload": payload, "signature": b64(signature) });
+
+			let response = self.client
+				.post(url)
+				.header(CONTENT_TYPE, "application/jose+json")
+				.body(body.to_string())
+				.send()
+				.await?;
+			self.nonce = replay_nonce(response.headers());
+			if response.status().is_success() {
+				return Ok(response);
+			}
+			let problem: Value = response.json().await.unwrap_or_default();
+			if problem["type"] == "urn:ietf:params:acme:error:badNonce" && attempt == 0 {
+				continue;
+			}
+			return Err(format!("ACME request to {} failed: {}", url, problem).into());
+		}
+		unreachable!()
+	}
+
+	// POST-as-GET `url` until the resource's status is "valid".
+	async fn poll(&mut self, url: &str) -> Result<Value, Box<dyn Error + Send + Sync>> {
+		for _ in 0..POLL_ATTEMPTS {
+			let resource: Value = self.post(url, None).await?.json().await?;
+			match resource["status"].as_str() {
+				Some("valid") => return Ok(resource),
+				Some("invalid") => return Err(format!("{} became invalid: {}", url, resource).into()),
+				_ => sleep(POLL_INTERVAL).await,
+			}
+		}
+		Err(format!("gave up waiting for {} to become valid", url).into())
+	}
+
+	// Tell the CA the challenge is ready and wait for the authorization to pass.
+	async fn validate(&mut self, challenge_url: &str, authorization_url: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+		self.post(challenge_url, Some(&json!({}))).await?;
+		self.poll(authorization_url).await?;
+		Ok(())
+	}
+
+	// Run the whole RFC 8555 flow and return the certificate chain and its key as PEM.
+	async fn issue(&mut self, domains: &[String], contact: Option<&str>, resolver: &CertResolver) -> Result<(String, String), Box<dyn Error + Send + Sync>> {
+		let new_account = self.directory.new_account.clone();
+		let mut account = json!({ "termsOfServiceAgreed": true });
+		if let Some(contact) = contact {
+			account["contact"] = json!([format!("mailto:{}", contact)]);
+		}
+		let response = self.post(&new_account, Some(&account)).await?;
+		self.kid = Some(location(&response)?);
+
+		let new_order = self.directory.new_order.clone();
+		let identifiers: Vec<Value> = domains.iter().map(|d| json!({ "type": "dns", "value": d })).collect();
+		let response = self.post(&new_order, Some(&json!({ "identifiers": identifiers }))).await?;
+		let order_url = location(&response)?;
+		let order: Order = response.json().await?;
+
+		for authorization_url in &order.authorizations {
+			let authorization: Authorization = self.post(authorization_url, None).await?.json().await?;
+			if authorization.status == "valid" {
+				continue;
+			}
+			let domain = authorization.identifier.value;
+			let challenge = authorization
+				.challenges
+				.iter()
+				.find(|c| c.kind == "tls-alpn-01")
+				.ok_or_else(|| format!("CA offered no tls-alpn-01 challenge for {}", domain))?;
+			let key_authorization = format!("{}.{}", challenge.token, self.thumbprint());
+			resolver.set_challenge(&domain, Some(challenge_certificate(&domain, &key_authorization)?));
+
+			let challenge_url = challenge.url.clone();
+			let result = self.validate(&challenge_url, authorization_url).await;
+			resolver.set_challenge(&domain, None);
+			result?;
+		}
+
+		let key = KeyPair::generate()?;
+		let csr = CertificateParams::new(domains.to_vec())?.serialize_request(&key)?;
+		self.post(&order.finalize, Some(&json!({ "csr": b64(csr.der()) }))).await?;
+		let order: Order = serde_json::from_value(self.poll(&order_url).await?)?;
+		let certificate_url = order.certificate.ok_or_else(|| format!("order {} is {} without a certificate", order_url, order.status))?;
+		let chain = self.post(&certificate_url, None).await?.text().await?;
+		Ok((chain, key.serialize_pem()))
+	}
+}
+
+fn replay_nonce(headers: &HeaderMap) -> Option<String> {
+	headers.get("replay-nonce")?.to_str().ok().map(str::to_string)
+}
+
+fn location(response: &Response) -> Result<String, Box<dyn Error + Send + Sync>> {
+	Ok(response.headers().get(LOCATION).ok_or("CA response has no Location")?.to_str()?.to_string())
+}
+
+// The self-signed certificate that proves control of `domain` to a
+// TLS-ALPN-01 validator: the domain as its only name and the SHA-256 of the
+// key authorization in the critical acmeIdentifier extension.
+fn challenge_certificate(domain: &str, key_authorization: &str) -> Result<tokio_rustls::rustls::sign::CertifiedKey, Box<dyn Error + Send + Sync>> {
+	let mut params = CertificateParams::new(vec![domain.to_string()])?;
+	params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest(&SHA256, key_authorization.as_bytes()).as_ref())];
+	let key = KeyPair::generate()?;
+	let cert = params.self_signed(&key)?;
+	tls::certified_key(cert.pem().as_bytes(), key.serialize_pem().as_bytes())
+}
+
+// A DER certificate's notAfter. Certificate ::= SEQUENCE { tbsCertificate
+// SEQUENCE { [0] version, serialNumber, signature, issuer, validity SEQUENCE
+// { notBefore, notAfter }, ... }, ... }
+fn not_after(encoded: &[u8]) -> Option<OffsetDateTime> {
+	let (_, certificate, _) = der::element(encoded)?;
+	let (_, tbs, _) = der::element(certificate)?;
+	let (tag, _, after_version) = der::element(tbs)?;
+	let mut fields = if tag == 0xa0 { after_version } else { tbs };
+	for _ in 0..3 {
+		fields = der::element(fields)?.2;
+	}
+	let (_, validity, _) = der::element(fields)?;
+	let (_, _, after_not_before) = der::element(validity)?;
+	let (tag, not_after, _) = der::element(after_not_before)?;
+	parse_der_time(tag, not_after)
+}
+
+// UTCTime (YYMMDDHHMMSSZ) or GeneralizedTime (YYYYMMDDHHMMSSZ).
+fn parse_der_time(tag: u8, contents: &[u8]) -> Option<OffsetDateTime> {
+	let text = std::str::from_utf8(contents).ok()?.strip_suffix('Z')?;
+	let (year, rest) = match tag {
+		0x17 => {
+			let year: i32 = text.get(..2)?.parse().ok()?;
+			(if year >= 50 { 1900 + year } else { 2000 + year }, text.get(2..)?)
+		}
+		0x18 => (text.get(..4)?.parse().ok()?, text.get(4..)?),
+		_ => return None,
+	};
+	let field = |at: usize| -> Option<u8> { rest.get(at..at + 2)?.parse().ok() };
+	let date = Date::from_calendar_date(year, Month::try_from(field(0)?).ok()?, field(2)?).ok()?;
+	let time = Time::from_hms(field(4)?, field(6)?, field(8)?).ok()?;
+	Some(PrimitiveDateTime::new(date, time).assume_utc())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+	use crate::mock_http::{MockHttpServer, MockResponse};
+	use crate::tls::CertResolver;
+
+	fn self_signed(domain: &str, not_after: OffsetDateTime) -> (String, String) {
+		let mut params = CertificateParams::new(vec![domain.to_string()]).unwrap();
+		params.not_after = not_after;
+		let key = KeyPair::generate().unwrap();
+		(params.self_signed(&key).unwrap().pem(), key.serialize_pem())
+	}
+
+	fn decode(part: &Value) -> Value {
+		serde_json::from_slice(&URL_SAFE_NO_PAD.decode(part.as_str().unwrap()).unwrap()).unwrap_or(Value::Null)
+	}
+
+	#[test]
+	fn expiry_is_read_from_the_certificate() {
+		let expires = OffsetDateTime::from_unix_timestamp(2_000_000_000).unwrap();
+		let (cert_pem, _) = self_signed("decoy.example", expires);
+		let cert = CertificateDer::from_pem_slice(cert_pem.as_bytes()).unwrap();
+		assert_eq!(not_after(&cert), Some(expires));
+	}
+
+	#[tokio::test]
+	async fn certificates_are_issued_through_tls_alpn_challenges() {
+		let ca = MockHttpServer::start().await;
+		let base = ca.base_url();
+		let json = |status: u16, body: Value| MockResponse::new(status, &body.to_string());
+		let (issued_pem, _) = self_signed("decoy.example", OffsetDateTime::now_utc() + time::Duration::days(90));
+		let authorization = |status: &str| json!({
+			"status": status,
+			"identifier": { "type": "dns", "value": "decoy.example" },
+			"challenges": [
+				{ "type": "http-01", "url": format!("{}/chall/http", base), "token": "t0" },
+				{ "type": "tls-alpn-01", "url": format!("{}/chall/alpn", base), "token": "tok" },
+			],
+		});
+
+		ca.respond_with(json(200, json!({ "newNonce": format!("{}/nonce", base), "newAccount": format!("{}/account", base), "newOrder": format!("{}/order", base) })));
+		ca.respond_with(json(200, json!({})).with_header("Replay-Nonce", "n1"));
+		ca.respond_with(json(201, json!({ "status": "valid" })).with_header("Replay-Nonce", "n2").with_header("Location", &format!("{}/acct/1", base)));
+		ca.respond_with(
+			json(201, json!({ "status": "pending", "authorizations": [format!("{}/authz/1", base)], "finalize": format!("{}/finalize/1", base) }))
+				.with_header("Replay-Nonce", "n3")
+				.with_header("Location", &format!("{}/order/1", base)),
+		);
+		ca.respond_with(json(200, authorization("pending")).with_header("Replay-Nonce", "n4"));
+		ca.respond_with(json(200, json!({ "status": "processing" })).with_header("Replay-Nonce", "n5"));
+		ca.respond_with(json(200, authorization("valid")).with_header("Replay-Nonce", "n6"));
+		ca.respond_with(json(200, json!({ "status": "processing", "finalize": format!("{}/finalize/1", base) })).with_header("Replay-Nonce", "n7"));
+		ca.respond_with(
+			json(200, json!({ "status": "valid", "finalize": format!("{}/finalize/1", base), "certificate": format!("{}/cert/1", base) }))
+				.with_header("Replay-Nonce", "n8"),
+		);
+		ca.respond_with(MockResponse::new(200, &issued_pem).with_header("Replay-Nonce", "n9"));
+
+		let (placeholder_cert, placeholder_key) = self_signed("placeholder", OffsetDateTime::now_utc());
+		let resolver = CertResolver::new(tls::certified_key(placeholder_cert.as_bytes(), placeholder_key.as_bytes()).unwrap());
+		let account_key = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new()).unwrap();
+		let client = Client::new();
+		let mut acme = AcmeClient::new(&client, &format!("{}/directory", base), account_key.as_ref()).await.unwrap();
+		let (chain, _) = acme.issue(&["decoy.example".to_string()], Some("ops@decoy.example"), &resolver).await.unwrap();
+		assert_eq!(chain, issued_pem);
+
+		let requests = ca.requests();
+		let paths: Vec<&str> = requests.iter().map(|r| r.path.as_str()).collect();
+		assert_eq!(paths, ["/directory", "/nonce", "/account", "/order", "/authz/1", "/chall/alpn", "/authz/1", "/finalize/1", "/order/1", "/cert/1"]);
+		assert_eq!(requests[1].method, "HEAD");
+
+		// The account is created with the public key and used by URL afterwards,
+		// each request spending the nonce handed out by the previous one.
+		let account = decode(&requests[2].body["protected"]);
+		assert_eq!(account["nonce"], "n1");
+		assert_eq!(account["jwk"]["crv"], "P-256");
+		assert!(account.get("kid").is_none());
+		assert_eq!(decode(&requests[2].body["payload"])["contact"][0], "mailto:ops@decoy.example");
+		let order = decode(&requests[3].body["protected"]);
+		assert_eq!(order["kid"], format!("{}/acct/1", base));
+		assert_eq!(order["nonce"], "n2");
+		assert_eq!(order["url"], format!("{}/order", base));
+		assert_eq!(decode(&requests[3].body["payload"])["identifiers"][0]["value"], "decoy.example");
+		assert_eq!(requests[4].body["payload"], "");
+		assert!(decode(&requests[7].body["payload"])["csr"].is_string());
+
+		// Signatures verify against the account's public key
+		let jwk = &account["jwk"];
+		let mut point = vec![4];
+		point.extend(URL_SAFE_NO_PAD.decode(jwk["x"].as_str().unwrap()).unwrap());
+		point.extend(URL_SAFE_NO_PAD.decode(jwk["y"].as_str().unwrap()).unwrap());
+		let body = &requests[3].body;
+		let signed = format!("{}.{}", body["protected"].as_str().unwrap(), body["payload"].as_str().unwrap());
+		let signature = URL_SAFE_NO_PAD.decode(body["signature"].as_str().unwrap()).unwrap();
+		UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, &point).verify(signed.as_bytes(), &signature).unwrap();
+	}
+}
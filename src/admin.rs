@@ -0,0 +1,147 @@
+// A line-based control socket for operating a running honeypot without a
+// restart. Connect with e.g. `nc 127.0.0.1 9899` and type `help`. It is
+// unauthenticated, so it only ever listens on loopback; a `listen` address
+// anywhere else is refused at startup.
+use serde::Deserialize;
+use std::error::Error;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::{lookup_host, TcpStream};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+use crate::prelude::*;
+use crate::scripting;
+
+pub type LogFilterHandle = reload::Handle<EnvFilter, Registry>;
+
+const HELP: &str = "commands:\r\n  log-level               show the current log filter\r\n  log-level <directives>  replace it, e.g. `log-level warn,rustbucket::handler=debug`\r\n  reload-scripts          recompile the payload hook script\r\n  quit\r\n";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AdminConfig {
+	#[serde(default)]
+	pub enabled: bool,
+	// Must be a loopback address, e.g. 127.0.0.1:9899 or [::1]:9899.
+	#[serde(default = "AdminConfig::default_listen")]
+	pub listen: String,
+}
+
+impl AdminConfig {
+	fn default_listen() -> String {
+		"127.0.0.1:9899".to_string()
+	}
+}
+
+impl Default for AdminConfig {
+	fn default() -> Self {
+		AdminConfig {
+			enabled: false,
+			listen: Self::default_listen(),
+		}
+	}
+}
+
+// Swap the active log filter, using the same directive syntax as RUST_LOG.
+pub fn set_log_filter(handle: &LogFilterHandle, directives: &str) -> Result<(), Box<dyn Error>> {
+	let filter = EnvFilter::try_new(directives)?;
+	handle.reload(filter)?;
+	Ok(())
+}
+
+fn current_log_filter(handle: &LogFilterHandle) -> String {
+	handle
+		.with_current(|filter| filter.to_string())
+		.unwrap_or_else(|e| format!("unavailable: {}", e))
+}
+
+// Start the control socket in the background if it is enabled.
+pub async fn start(config: &AdminConfig, log_filter: LogFilterHandle) -> Result<(), Box<dyn Error>> {
+	if !config.enabled {
+		return Ok(());
+	}
+	let addrs: Vec<_> = lookup_host(&config.listen).await?.collect();
+	if let Some(addr) = addrs.iter().find(|addr| !addr.ip().is_loopback()) {
+		return Err(format!("[admin] listen = {:?} resolves to {}, but the unauthenticated control socket only listens on loopback", config.listen, addr.ip()).into());
+	}
+	let listener = TcpListener::bind(&addrs[..]).await?;
+	info!("Admin control socket listening on {}", listener.local_addr()?);
+	task::spawn(serve(listener, log_filter));
+	Ok(())
+}
+
+async fn serve(listener: TcpListener, log_filter: LogFilterHandle) {
+	loop {
+		match listener.accept().await {
+			Ok((stream, addr)) => {
+				info!("Admin connection from {}", addr);
+				task::spawn(handle_admin(stream, log_filter.clone()));
+			}
+			Err(e) => error!("Failed to accept admin connection: {}", e),
+		}
+	}
+}
+
+async fn handle_admin(stream: TcpStream, log_filter: LogFilterHandle) {
+	let (reader, mut writer) = stream.into_split();
+	let mut lines = BufReader::new(reader).lines();
+	while let Ok(Some(line)) = lines.next_line().await {
+		let line = line.trim();
+		let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+		let reply = match (command, argument.trim()) {
+			("", _) => continue,
+			("log-level", "") => format!("{}\r\n", current_log_filter(&log_filter)),
+			("log-level", directives) => match set_log_filter(&log_filter, directives) {
+				Ok(()) => {
+					warn!("Log filter changed to {} via admin socket", directives);
+					"ok\r\n".to_string()
+				}
+				Err(e) => format!("error: {}\r\n", e),
+			},
+			("reload-scripts", _) => match scripting::reload() {
+				Ok(()) => {
+					warn!("Payload hook script reloaded via admin socket");
+					"ok\r\n".to_string()
+				}
+				Err(e) => format!("error: {}\r\n", e),
+			},
+			("help", _) => HELP.to_string(),
+			("quit", _) => break,
+			(other, _) => format!("unknown command {}, try help\r\n", other),
+		};
+		if writer.write_all(reply.as_bytes()).await.is_err() {
+			break;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn log_level_can_be_changed_over_the_socket() {
+		let (_layer, handle) = reload::Layer::<EnvFilter, Registry>::new(EnvFilter::new("info"));
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		task::spawn(serve(listener, handle.clone()));
+
+		let mut client = TcpStream::connect(addr).await.unwrap();
+		client.write_all(b"log-level warn,rustbucket::handler=debug\nlog-level not=a=filter\nquit\n").await.unwrap();
+		let mut replies = String::new();
+		client.read_to_string(&mut replies).await.unwrap();
+
+		let mut replies = replies.lines();
+		assert_eq!(replies.next(), Some("ok"));
+		assert!(replies.next().unwrap().starts_with("error:"));
+		let current = current_log_filter(&handle);
+		assert!(current.contains("rustbucket::handler=debug") && current.contains("warn"), "{}", current);
+	}
+
+	#[tokio::test]
+	async fn only_loopback_addresses_are_listened_on() {
+		let (_layer, handle) = reload::Layer::<EnvFilter, Registry>::new(EnvFilter::new("info"));
+		for listen in ["0.0.0.0:0", "[::]:0", "192.0.2.1:9899"] {
+			let config = AdminConfig { enabled: true, listen: listen.to_string() };
+			let error = start(&config, handle.clone()).await.unwrap_err();
+			assert!(error.to_string().contains("only listens on loopback"), "{}: {}", listen, error);
+		}
+		start(&AdminConfig { enabled: true, listen: "127.0.0.1:0".to_string() }, handle).await.unwrap();
+	}
+}
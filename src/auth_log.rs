@@ -0,0 +1,253 @@
+// An auxiliary log of login attempts written in the formats fail2ban's stock
+// filters already understand, so operators can ban actors at the host
+// firewall. FTP attempts are logged like vsftpd (`filter.d/vsftpd.conf`),
+// credentials seen on any other protocol like sshd (`filter.d/sshd.conf`).
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Deserialize;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use time::macros::format_description;
+use time::OffsetDateTime;
+use crate::events::SessionEvent;
+
+// Longest username copied into a log line.
+const MAX_USER_LEN: usize = 64;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AuthLogConfig {
+	#[serde(default)]
+	pub enabled: bool,
+	#[serde(default = "AuthLogConfig::default_path")]
+	pub path: String,
+}
+
+impl AuthLogConfig {
+	fn default_path() -> String {
+		"./logs/auth.log".to_string()
+	}
+}
+
+impl Default for AuthLogConfig {
+	fn default() -> Self {
+		AuthLogConfig {
+			enabled: false,
+			path: Self::default_path(),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthLogStyle {
+	Vsftpd,
+	Sshd,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthFailure {
+	pub user: String,
+	pub style: AuthLogStyle,
+}
+
+impl AuthFailure {
+	fn vsftpd(user: &str) -> AuthFailure {
+		AuthFailure { user: sanitize(user), style: AuthLogStyle::Vsftpd }
+	}
+
+	fn sshd(user: &str) -> AuthFailure {
+		AuthFailure { user: sanitize(user), style: AuthLogStyle::Sshd }
+	}
+
+	// Render the failure as one log line, with syslog or vsftpd timestamps in UTC.
+	fn format(&self, at: OffsetDateTime, actor: SocketAddr, hostname: &str) -> String {
+		let pid = std::process::id();
+		match self.style {
+			AuthLogStyle::Vsftpd => {
+				let timestamp = at
+					.format(format_description!("[weekday repr:short] [month repr:short] [day padding:space] [hour]:[minute]:[second] [year]"))
+					.unwrap_or_default();
+				format!("{} [pid {}] [{}] FAIL LOGIN: Client \"{}\"", timestamp, pid, self.user, actor.ip())
+			}
+			AuthLogStyle::Sshd => {
+				let timestamp = at
+					.format(format_description!("[month repr:short] [day padding:space] [hour]:[minute]:[second]"))
+					.unwrap_or_default();
+				format!(
+					"{} {} sshd[{}]: Failed password for invalid user {} from {} port {} ssh2",
+					timestamp,
+					hostname,
+					pid,
+					self.user,
+					actor.ip(),
+					actor.port()
+				)
+			}
+		}
+	}
+}
+
+// Usernames are attacker controlled: keep them on one line, free of spaces
+// and brackets that would confuse the filters, and short.
+fn sanitize(user: &str) -> String {
+	let user: String = user
+		.chars()
+		.take(MAX_USER_LEN)
+		.map(|c| if c.is_ascii_graphic() && c != '[' && c != ']' && c != '"' { c } else { '?' })
+		.collect();
+	if user.is_empty() { "unknown".to_string() } else { user }
+}
+
+fn decode(encoded: &str) -> Option<String> {
+	STANDARD.decode(encoded.trim()).ok().map(|bytes| String::from_utf8_lossy(&bytes).to_string())
+}
+
+const BASIC_AUTH: &str = "authorization: basic ";
+
+// Every login attempt the actor made during a session on `service`.
+pub fn auth_failures(service: &str, events: &[SessionEvent]) -> Vec<AuthFailure> {
+	let mut failures = vec![];
+	let mut ftp_user: Option<String> = None;
+	let mut smtp_auth_login = false;
+
+	let lines = events
+		.iter()
+		.filter_map(|event| match event {
+			SessionEvent::Received { data } => Some(data.lines()),
+			_ => None,
+		})
+		.flatten()
+		.map(str::trim);
+	for line in lines {
+		let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+		let argument = argument.trim();
+
+		// `get`, as the offset may fall inside a character of the actor's text
+		let basic = line.get(..BASIC_AUTH.len()).is_some_and(|prefix| prefix.eq_ignore_ascii_case(BASIC_AUTH));
+		if basic && line.len() > BASIC_AUTH.len() {
+			if let Some(credentials) = decode(&line[BASIC_AUTH.len()..]) {
+				let user = credentials.split_once(':').map_or(credentials.as_str(), |(user, _)| user);
+				failures.push(AuthFailure::sshd(user));
+			}
+			continue;
+		}
+
+		match service {
+			"ftp" => {
+				if command.eq_ignore_ascii_case("USER") {
+					ftp_user = Some(argument.to_string());
+				} else if command.eq_ignore_ascii_case("PASS") {
+					failures.push(AuthFailure::vsftpd(ftp_user.as_deref().unwrap_or("")));
+				}
+			}
+			"smtp" => {
+				if smtp_auth_login {
+					// AUTH LOGIN sends the username on its own line after the command
+					smtp_auth_login = false;
+					failures.push(AuthFailure::sshd(&decode(line).unwrap_or_default()));
+				} else if command.eq_ignore_ascii_case("AUTH") {
+					let (mechanism, initial) = argument.split_once(' ').unwrap_or((argument, ""));
+					if mechanism.eq_ignore_ascii_case("PLAIN") && !initial.is_empty() {
+						// authzid \0 authcid \0 password
+						let credentials = decode(initial).unwrap_or_default();
+						failures.push(AuthFailure::sshd(credentials.split('\0').nth(1).unwrap_or("")));
+					} else if mechanism.eq_ignore_ascii_case("LOGIN") {
+						match decode(initial).filter(|_| !initial.is_empty()) {
+							Some(user) => failures.push(AuthFailure::sshd(&user)),
+							None => smtp_auth_login = true,
+						}
+					} else {
+						failures.push(AuthFailure::sshd(""));
+					}
+				}
+			}
+			_ => {}
+		}
+	}
+	failures
+}
+
+#[derive(Clone)]
+pub struct AuthLog {
+	file: Arc<Mutex<File>>,
+}
+
+impl AuthLog {
+	// Open the auth log for appending if it is enabled.
+	pub fn open(config: &AuthLogConfig) -> Result<Option<AuthLog>, Box<dyn Error>> {
+		if !config.enabled {
+			return Ok(None);
+		}
+		if let Some(parent) = Path::new(&config.path).parent() {
+			fs::create_dir_all(parent)?;
+		}
+		let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+		Ok(Some(AuthLog { file: Arc::new(Mutex::new(file)) }))
+	}
+
+	pub fn record(&self, failures: &[AuthFailure], actor: SocketAddr, hostname: &str) -> std::io::Result<()> {
+		if failures.is_empty() {
+			return Ok(());
+		}
+		let now = OffsetDateTime::now_utc();
+		let mut lines = String::new();
+		for failure in failures {
+			lines.push_str(&failure.format(now, actor, hostname));
+			lines.push('\n');
+		}
+		let mut file = self.file.lock().unwrap();
+		file.write_all(lines.as_bytes())?;
+		file.flush()
+	}
+
+	// Get everything written so far onto disk.
+	pub fn flush(&self) -> std::io::Result<()> {
+		self.file.lock().unwrap().sync_all()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use time::macros::datetime;
+
+	fn received(data: &str) -> SessionEvent {
+		SessionEvent::Received { data: data.to_string() }
+	}
+
+	#[test]
+	fn login_attempts_are_extracted_per_protocol() {
+		let ftp = [received("USER admin\r\n"), received("PASS hunter2\r\n"), received("PASS letmein\r\n")];
+		assert_eq!(auth_failures("ftp", &ftp), vec![AuthFailure::vsftpd("admin"), AuthFailure::vsftpd("admin")]);
+
+		// "\0root\0toor" and "postmaster"
+		let smtp = [received("EHLO x\r\nAUTH PLAIN AHJvb3QAdG9vcg==\r\n"), received("AUTH LOGIN\r\n"), received("cG9zdG1hc3Rlcg==\r\n")];
+		assert_eq!(auth_failures("smtp", &smtp), vec![AuthFailure::sshd("root"), AuthFailure::sshd("postmaster")]);
+
+		// "admin:admin"
+		let http = [received("GET / HTTP/1.1\r\nAuthorization: Basic YWRtaW46YWRtaW4=\r\n\r\n")];
+		assert_eq!(auth_failures("http", &http), vec![AuthFailure::sshd("admin")]);
+
+		// A multibyte character across the prefix length
+		let http = [received("GET /012345678901234€ HTTP/1.1\r\nX-Note: ünïcödé\r\n\r\n")];
+		assert_eq!(auth_failures("http", &http), vec![]);
+	}
+
+	#[test]
+	fn lines_match_the_fail2ban_formats() {
+		let at = datetime!(2024-03-05 07:08:09 UTC);
+		let actor: SocketAddr = "203.0.113.7:50123".parse().unwrap();
+		let pid = std::process::id();
+		assert_eq!(
+			AuthFailure::vsftpd("admin").format(at, actor, "host"),
+			format!("Tue Mar  5 07:08:09 2024 [pid {}] [admin] FAIL LOGIN: Client \"203.0.113.7\"", pid)
+		);
+		assert_eq!(
+			AuthFailure::sshd("bad user]\n").format(at, actor, "host"),
+			format!("Mar  5 07:08:09 host sshd[{}]: Failed password for invalid user bad?user?? from 203.0.113.7 port 50123 ssh2", pid)
+		);
+	}
+}
@@ -0,0 +1,268 @@
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+use crate::chat_service::MockChatService;
+use crate::config::AppConfig;
+use crate::prelude::*;
+use crate::shutdown::Shutdown;
+use crate::sinks::SessionSinks;
+use crate::tls::Certificates;
+use crate::simulate::{self, Scenario, READ_TIMEOUT};
+
+// Knobs for `rustbucket bench`, parsed from the command line.
+#[derive(Debug)]
+struct BenchOptions {
+	scenario_file: String,
+	connections: usize,
+	rounds: u32,
+	llm_latency: Duration,
+}
+
+impl BenchOptions {
+	fn parse(args: &[String]) -> Result<BenchOptions, String> {
+		let mut options = BenchOptions {
+			scenario_file: "scenarios.toml".to_string(),
+			connections: 100,
+			rounds: 1,
+			llm_latency: Duration::ZERO,
+		};
+
+		let mut args = args.iter();
+		while let Some(arg) = args.next() {
+			let mut value = |name: &str| -> Result<u64, String> {
+				args.next()
+					.and_then(|v| v.parse().ok())
+					.ok_or_else(|| format!("{} expects a number", name))
+			};
+			match arg.as_str() {
+				"--connections" => options.connections = value(arg)? as usize,
+				"--rounds" => options.rounds = value(arg)? as u32,
+				"--llm-latency-ms" => options.llm_latency = Duration::from_millis(value(arg)?),
+				flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+				file => options.scenario_file = file.to_string(),
+			}
+		}
+		Ok(options)
+	}
+
+	// How long to wait for an answer: the mock LLM's latency, plus the
+	// slack simulate allows a service that doesn't call it.
+	fn read_timeout(&self) -> Duration {
+		self.llm_latency + READ_TIMEOUT
+	}
+}
+
+// Timings collected by one benchmark connection.
+#[derive(Debug, Default)]
+struct Samples {
+	connect: Vec<Duration>,
+	first_byte: Vec<Duration>,
+	exchange: Vec<Duration>,
+	errors: u32,
+}
+
+impl Samples {
+	fn merge(&mut self, other: Samples) {
+		self.connect.extend(other.connect);
+		self.first_byte.extend(other.first_byte);
+		self.exchange.extend(other.exchange);
+		self.errors += other.errors;
+	}
+}
+
+// Open many concurrent local connections against the real listeners, with a
+// mock LLM, and report throughput, per-stage latency and memory use.
+pub async fn run(args: &[String], app_config: AppConfig) {
+	let options = match BenchOptions::parse(args) {
+		Ok(options) => options,
+		Err(e) => {
+			println!("{}", e);
+			println!("Usage: rustbucket bench [scenario file] [--connections N] [--rounds N] [--llm-latency-ms N]");
+			return;
+		}
+	};
+	let scenarios = match simulate::load_scenarios(&options.scenario_file) {
+		Ok(scenarios) if !scenarios.is_empty() => scenarios,
+		Ok(_) => {
+			println!("No scenarios in {}", options.scenario_file);
+			return;
+		}
+		Err(e) => {
+			println!("Failed to load scenario file {}: {}", options.scenario_file, e);
+			return;
+		}
+	};
+
+	let chat = MockChatService::new("benchmark response").with_latency(options.llm_latency);
+	let shutdown = Shutdown::new();
+	let handles = crate::spawn_listeners(&app_config, &shutdown, &SessionSinks::default(), &Certificates::load(&app_config, None), |_, _| chat.clone());
+	let memory_before = memory_usage();
+	info!("Benchmarking {} connections x {} rounds from {}", options.connections, options.rounds, options.scenario_file);
+
+	// Spread the connections across the scenarios to get the payload mix
+	let scenarios: Vec<Arc<Scenario>> = scenarios.into_iter().map(Arc::new).collect();
+	let started = Instant::now();
+	let mut tasks = vec![];
+	for i in 0..options.connections {
+		let scenario = scenarios[i % scenarios.len()].clone();
+		let Some(target) = simulate::resolve_target(&scenario, &app_config) else {
+			println!("[{}] SKIPPED: no persona listens on port {}", scenario.name, scenario.port);
+			continue;
+		};
+		let expect_banner = app_config
+			.ports
+			.find(scenario.port)
+			.map(|s| s.banner.is_some())
+			.unwrap_or(false);
+		let rounds = options.rounds;
+		let read_timeout = options.read_timeout();
+		tasks.push(task::spawn(async move {
+			bench_connection(&scenario, &target, expect_banner, rounds, read_timeout).await
+		}));
+	}
+
+	let mut samples = Samples::default();
+	for task in tasks {
+		match task.await {
+			Ok(s) => samples.merge(s),
+			Err(_) => samples.errors += 1,
+		}
+	}
+	let elapsed = started.elapsed();
+	let memory_after = memory_usage();
+
+	shutdown.trigger();
+	for handle in handles {
+		let _ = handle.await;
+	}
+
+	println!(
+		"{} connections, {} exchanges in {:?} ({:.1} exchanges/sec), {} errors",
+		samples.connect.len(),
+		samples.exchange.len(),
+		elapsed,
+		samples.exchange.len() as f64 / elapsed.as_secs_f64(),
+		samples.errors
+	);
+	report("connect", &mut samples.connect);
+	report("banner", &mut samples.first_byte);
+	report("exchange", &mut samples.exchange);
+	match (memory_before, memory_after) {
+		(Some((rss_before, _)), Some((rss_after, peak))) => {
+			println!("memory: rss {} kB -> {} kB, peak {} kB", rss_before, rss_after, peak);
+		}
+		_ => println!("memory: unavailable on this platform"),
+	}
+}
+
+async fn bench_connection(scenario: &Scenario, target: &str, expect_banner: bool, rounds: u32, read_timeout: Duration) -> Samples {
+	let mut samples = Samples::default();
+	let mut buffer = [0; 4096];
+
+	let started = Instant::now();
+	let mut stream = match simulate::connect(target).await {
+		Ok(stream) => stream,
+		Err(_) => {
+			samples.errors += 1;
+			return samples;
+		}
+	};
+	samples.connect.push(started.elapsed());
+
+	if expect_banner {
+		match timeout(read_timeout, stream.read(&mut buffer)).await {
+			Ok(Ok(n)) if n > 0 => samples.first_byte.push(started.elapsed()),
+			_ => samples.errors += 1,
+		}
+	}
+
+	for _ in 0..rounds {
+		for step in &scenario.steps {
+			let sent = Instant::now();
+			if stream.write_all(step.as_bytes()).await.is_err() {
+				samples.errors += 1;
+				return samples;
+			}
+			match timeout(read_timeout, stream.read(&mut buffer)).await {
+				Ok(Ok(n)) if n > 0 => samples.exchange.push(sent.elapsed()),
+				_ => {
+					samples.errors += 1;
+					return samples;
+				}
+			}
+		}
+	}
+
+	samples
+}
+
+fn report(stage: &str, samples: &mut [Duration]) {
+	if samples.is_empty() {
+		println!("{:>8}: no samples", stage);
+		return;
+	}
+	println!("{:>8}: {}", stage, percentiles(samples));
+}
+
+// p50, p95, p99 and max, nearest rank below. Sorts the samples.
+fn percentiles(samples: &mut [Duration]) -> String {
+	samples.sort();
+	let percentile = |p: usize| samples[(samples.len() - 1) * p / 100];
+	format!(
+		"p50 {:?}  p95 {:?}  p99 {:?}  max {:?}",
+		percentile(50),
+		percentile(95),
+		percentile(99),
+		samples[samples.len() - 1]
+	)
+}
+
+// Current and peak resident set size in kB, read from /proc on Linux.
+fn memory_usage() -> Option<(u64, u64)> {
+	let status = fs::read_to_string("/proc/self/status").ok()?;
+	let field = |name: &str| -> Option<u64> {
+		status
+			.lines()
+			.find(|line| line.starts_with(name))?
+			.split_whitespace()
+			.nth(1)?
+			.parse()
+			.ok()
+	};
+	Some((field("VmRSS:")?, field("VmHWM:")?))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn args(args: &[&str]) -> Vec<String> {
+		args.iter().map(|a| a.to_string()).collect()
+	}
+
+	#[test]
+	fn options_are_parsed_from_the_command_line() {
+		let options = BenchOptions::parse(&args(&["mix.toml", "--connections", "500", "--rounds", "3", "--llm-latency-ms", "250"])).unwrap();
+		assert_eq!(options.scenario_file, "mix.toml");
+		assert_eq!((options.connections, options.rounds), (500, 3));
+		assert_eq!(options.llm_latency, Duration::from_millis(250));
+		// A slow mock LLM must not turn every exchange into a timeout
+		assert_eq!(options.read_timeout(), Duration::from_millis(250) + READ_TIMEOUT);
+
+		let defaults = BenchOptions::parse(&[]).unwrap();
+		assert_eq!((defaults.scenario_file.as_str(), defaults.connections, defaults.rounds), ("scenarios.toml", 100, 1));
+		assert_eq!(defaults.read_timeout(), READ_TIMEOUT);
+
+		assert_eq!(BenchOptions::parse(&args(&["--rounds"])).unwrap_err(), "--rounds expects a number");
+		assert_eq!(BenchOptions::parse(&args(&["--connections", "many"])).unwrap_err(), "--connections expects a number");
+		assert_eq!(BenchOptions::parse(&args(&["--verbose"])).unwrap_err(), "unknown option --verbose");
+	}
+
+	#[test]
+	fn percentiles_are_reported_from_sorted_samples() {
+		let mut samples: Vec<Duration> = (1..=100).rev().map(Duration::from_millis).collect();
+		assert_eq!(percentiles(&mut samples), "p50 50ms  p95 95ms  p99 99ms  max 100ms");
+		assert_eq!(percentiles(&mut [Duration::from_millis(7)]), "p50 7ms  p95 7ms  p99 7ms  max 7ms");
+	}
+}
@@ -0,0 +1,321 @@
+// Sources that attacked the honeypot, kept as a timed ban list and exported
+// as an nftables or ipset set file. Reference the set from your own rules (or
+// ship the file to peers) to drop confirmed attackers before they reach real
+// services. With `apply` the file is loaded into the kernel after every change.
+// Only the two sets are redefined when it is loaded, so rules the operator
+// keeps in the same nftables table stay put. A source that keeps coming back
+// while banned doesn't rewrite the file on every session; its ban is renewed
+// once it is half way through. Bans still running when rustbucket stops are
+// read back from the file at the next start.
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+use crate::prelude::*;
+use crate::sinks::Offense;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BlocklistFormat {
+	Nftables,
+	Ipset,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct BlocklistConfig {
+	#[serde(default)]
+	pub enabled: bool,
+	#[serde(default = "BlocklistConfig::default_format")]
+	pub format: BlocklistFormat,
+	#[serde(default = "BlocklistConfig::default_path")]
+	pub path: String,
+	// nftables table / ipset set name. IPv6 sources go in a sibling with a "6" suffix.
+	#[serde(default = "BlocklistConfig::default_set_name")]
+	pub set_name: String,
+	#[serde(default = "BlocklistConfig::default_ban_secs")]
+	pub ban_secs: u64,
+	// Also ban sources that only probed, not just those that tried to log in.
+	#[serde(default)]
+	pub ban_on_probe: bool,
+	// Ban sources whose session picked up one of these tags, from a script,
+	// the countermeasures or an emulation, even if they only probed.
+	#[serde(default = "BlocklistConfig::default_ban_tags")]
+	pub ban_tags: Vec<String>,
+	// Load the file with `nft -f` / `ipset restore` after each change. Needs CAP_NET_ADMIN.
+	#[serde(default)]
+	pub apply: bool,
+}
+
+impl BlocklistConfig {
+	fn default_format() -> BlocklistFormat {
+		BlocklistFormat::Nftables
+	}
+
+	fn default_path() -> String {
+		"./blocklist.nft".to_string()
+	}
+
+	fn default_set_name() -> String {
+		"rustbucket".to_string()
+	}
+
+	fn default_ban_secs() -> u64 {
+		4 * 60 * 60
+	}
+
+	fn default_ban_tags() -> Vec<String> {
+		vec![crate::countermeasures::TAG.to_string()]
+	}
+}
+
+impl Default for BlocklistConfig {
+	fn default() -> Self {
+		BlocklistConfig {
+			enabled: false,
+			format: Self::default_format(),
+			path: Self::default_path(),
+			set_name: Self::default_set_name(),
+			ban_secs: Self::default_ban_secs(),
+			ban_on_probe: false,
+			ban_tags: Self::default_ban_tags(),
+			apply: false,
+		}
+	}
+}
+
+#[derive(Clone)]
+pub struct Blocklist {
+	config: BlocklistConfig,
+	// Banned source -> when the ban lapses, as written to the set file.
+	bans: Arc<Mutex<HashMap<IpAddr, Instant>>>,
+}
+
+impl Blocklist {
+	pub fn new(config: &BlocklistConfig) -> Option<Blocklist> {
+		if !config.enabled {
+			return None;
+		}
+		let bans = match load(config) {
+			Ok(bans) => bans,
+			Err(e) => {
+				warn!("Failed to read the bans in {}, starting with none: {}", config.path, e);
+				HashMap::new()
+			}
+		};
+		Some(Blocklist {
+			config: config.clone(),
+			bans: Arc::new(Mutex::new(bans)),
+		})
+	}
+
+	// Ban `ip` for an offense, or the threat tags its session picked up, if
+	// the config calls for it, rewriting (and optionally applying) the set
+	// file. Returns whether the set changed.
+	pub async fn ban(&self, ip: IpAddr, offense: Offense, tags: &[&str]) -> Result<bool, Box<dyn Error>> {
+		let tagged = tags.iter().any(|tag| self.config.ban_tags.iter().any(|t| t == tag));
+		if matches!(offense, Offense::Probe(_)) && !self.config.ban_on_probe && !tagged {
+			return Ok(false);
+		}
+		if !self.write(ip)? {
+			return Ok(false);
+		}
+		if self.config.apply {
+			self.apply().await?;
+		}
+		Ok(true)
+	}
+
+	// False, without touching the file, if `ip` is banned for a while yet.
+	fn write(&self, ip: IpAddr) -> Result<bool, Box<dyn Error>> {
+		let mut bans = self.bans.lock().unwrap();
+		let now = Instant::now();
+		let ban = Duration::from_secs(self.config.ban_secs);
+		if bans.get(&ip).is_some_and(|until| *until > now + ban / 2) {
+			return Ok(false);
+		}
+		bans.retain(|_, until| *until > now);
+		bans.insert(ip, now + ban);
+
+		let mut entries: Vec<(IpAddr, u64)> = bans
+			.iter()
+			.map(|(ip, until)| (*ip, until.duration_since(now).as_secs().max(1)))
+			.collect();
+		entries.sort();
+		let contents = render(self.config.format, &self.config.set_name, self.config.ban_secs, &entries);
+
+		// Write then rename so a concurrent `nft -f` never reads half a file
+		let path = Path::new(&self.config.path);
+		let tmp = path.with_extension("tmp");
+		fs::write(&tmp, contents)?;
+		fs::rename(&tmp, path)?;
+		Ok(true)
+	}
+
+	async fn apply(&self) -> Result<(), Box<dyn Error>> {
+		let output = match self.config.format {
+			BlocklistFormat::Nftables => Command::new("nft").arg("-f").arg(&self.config.path).output().await?,
+			BlocklistFormat::Ipset => Command::new("ipset").arg("restore").arg("-f").arg(&self.config.path).output().await?,
+		};
+		if !output.status.success() {
+			return Err(String::from_utf8_lossy(&output.stderr).trim().to_string().into());
+		}
+		Ok(())
+	}
+}
+
+// The bans a previous run left in the set file that haven't lapsed yet.
+fn load(config: &BlocklistConfig) -> std::io::Result<HashMap<IpAddr, Instant>> {
+	let contents = match fs::read_to_string(&config.path) {
+		Ok(contents) => contents,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+		Err(e) => return Err(e),
+	};
+	// Timeouts count from when the file was written
+	let age = fs::metadata(&config.path)?.modified()?.elapsed().unwrap_or_default();
+	let now = Instant::now();
+	Ok(parse(config.format, &contents)
+		.into_iter()
+		.filter_map(|(ip, secs)| Some((ip, now + Duration::from_secs(secs).checked_sub(age).filter(|left| !left.is_zero())?)))
+		.collect())
+}
+
+// The entries in a rendered file, with their timeouts in seconds.
+fn parse(format: BlocklistFormat, contents: &str) -> Vec<(IpAddr, u64)> {
+	let entry = |ip: &str, secs: &str| Some((ip.parse().ok()?, secs.trim_end_matches('s').parse().ok()?));
+	contents
+		.lines()
+		.flat_map(|line| -> Vec<(IpAddr, u64)> {
+			match format {
+				// add element inet <table> <set> { <ip> timeout <n>s, ... }
+				BlocklistFormat::Nftables => line
+					.strip_prefix("add element ")
+					.and_then(|rest| rest.split_once('{'))
+					.map(|(_, elements)| elements.trim_end_matches(['}', ' ']).split(',').filter_map(|e| {
+						let words: Vec<&str> = e.split_whitespace().collect();
+						match words[..] {
+							[ip, "timeout", secs] => entry(ip, secs),
+							_ => None,
+						}
+					}).collect())
+					.unwrap_or_default(),
+				// add <set> <ip> timeout <n> -exist
+				BlocklistFormat::Ipset => match line.split_whitespace().collect::<Vec<_>>()[..] {
+					["add", _, ip, "timeout", secs, ..] => entry(ip, secs).into_iter().collect(),
+					_ => vec![],
+				},
+			}
+		})
+		.collect()
+}
+
+// Render the ban list as a file that replaces the previous entries when
+// loaded, with each entry's remaining ban time as its timeout. The nftables
+// table is only declared, so anything else in it is left alone.
+fn render(format: BlocklistFormat, name: &str, ban_secs: u64, entries: &[(IpAddr, u64)]) -> String {
+	let family = |v4: bool| entries.iter().filter(move |(ip, _)| ip.is_ipv4() == v4);
+
+	let mut out = String::from("# Generated by rustbucket. Do not edit, it is rewritten on every ban.\n");
+	match format {
+		BlocklistFormat::Nftables => {
+			let sets = [("banned_ipv4", "ipv4_addr", true), ("banned_ipv6", "ipv6_addr", false)];
+			out.push_str(&format!("table inet {} {{\n", name));
+			for (set, addr_type, _) in sets {
+				out.push_str(&format!("\tset {} {{\n\t\ttype {}\n\t\tflags timeout\n\t}}\n", set, addr_type));
+			}
+			out.push_str("}\n");
+			for (set, _, v4) in sets {
+				out.push_str(&format!("flush set inet {} {}\n", name, set));
+				let elements: Vec<String> = family(v4).map(|(ip, secs)| format!("{} timeout {}s", ip, secs)).collect();
+				if !elements.is_empty() {
+					out.push_str(&format!("add element inet {} {} {{ {} }}\n", name, set, elements.join(", ")));
+				}
+			}
+		}
+		BlocklistFormat::Ipset => {
+			for (set, inet, v4) in [(name.to_string(), "inet", true), (format!("{}6", name), "inet6", false)] {
+				out.push_str(&format!("create {} hash:ip family {} timeout {} -exist\nflush {}\n", set, inet, ban_secs, set));
+				for (ip, secs) in family(v4) {
+					out.push_str(&format!("add {} {} timeout {} -exist\n", set, ip, secs));
+				}
+			}
+		}
+	}
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entries() -> Vec<(IpAddr, u64)> {
+		vec![("192.0.2.1".parse().unwrap(), 60), ("198.51.100.9".parse().unwrap(), 14400), ("2001:db8::1".parse().unwrap(), 30)]
+	}
+
+	#[test]
+	fn renders_timed_nftables_sets_without_touching_the_table() {
+		let rendered = render(BlocklistFormat::Nftables, "rustbucket", 14400, &entries());
+		let lines: Vec<&str> = rendered.lines().skip(1).collect();
+		assert_eq!(lines, [
+			"table inet rustbucket {",
+			"\tset banned_ipv4 {",
+			"\t\ttype ipv4_addr",
+			"\t\tflags timeout",
+			"\t}",
+			"\tset banned_ipv6 {",
+			"\t\ttype ipv6_addr",
+			"\t\tflags timeout",
+			"\t}",
+			"}",
+			"flush set inet rustbucket banned_ipv4",
+			"add element inet rustbucket banned_ipv4 { 192.0.2.1 timeout 60s, 198.51.100.9 timeout 14400s }",
+			"flush set inet rustbucket banned_ipv6",
+			"add element inet rustbucket banned_ipv6 { 2001:db8::1 timeout 30s }",
+		]);
+		assert!(!rendered.contains("delete"));
+		assert_eq!(parse(BlocklistFormat::Nftables, &rendered), entries());
+	}
+
+	#[tokio::test]
+	async fn repeat_offenders_and_threat_tags() {
+		let path = std::env::temp_dir().join(format!("rustbucket-blocklist-{}.nft", std::process::id()));
+		let config = BlocklistConfig { enabled: true, path: path.to_string_lossy().to_string(), ..BlocklistConfig::default() };
+		let blocklist = Blocklist::new(&config).unwrap();
+		let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+		assert!(!blocklist.ban(ip, Offense::Probe(1), &[]).await.unwrap());
+		assert!(blocklist.ban(ip, Offense::Probe(1), &["probe:cross-protocol", crate::countermeasures::TAG]).await.unwrap());
+		// Already banned, so the file isn't rewritten
+		fs::remove_file(&path).unwrap();
+		assert!(!blocklist.ban(ip, Offense::LoginAttempts(3), &[]).await.unwrap());
+		assert!(!path.exists());
+		assert!(blocklist.ban("192.0.2.2".parse().unwrap(), Offense::LoginAttempts(3), &[]).await.unwrap());
+		assert!(fs::read_to_string(&path).unwrap().contains("192.0.2.1 timeout"));
+
+		// A restart picks the running bans back up from the file
+		let restarted = Blocklist::new(&config).unwrap();
+		assert!(!restarted.ban(ip, Offense::LoginAttempts(3), &[]).await.unwrap());
+		assert_eq!(restarted.bans.lock().unwrap().len(), 2);
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn renders_an_ipset_restore_script() {
+		let rendered = render(BlocklistFormat::Ipset, "rustbucket", 14400, &entries());
+		let lines: Vec<&str> = rendered.lines().skip(1).collect();
+		assert_eq!(lines, [
+			"create rustbucket hash:ip family inet timeout 14400 -exist",
+			"flush rustbucket",
+			"add rustbucket 192.0.2.1 timeout 60 -exist",
+			"add rustbucket 198.51.100.9 timeout 14400 -exist",
+			"create rustbucket6 hash:ip family inet6 timeout 14400 -exist",
+			"flush rustbucket6",
+			"add rustbucket6 2001:db8::1 timeout 30 -exist",
+		]);
+		assert_eq!(parse(BlocklistFormat::Ipset, &rendered), entries());
+	}
+}
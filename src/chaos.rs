@@ -0,0 +1,108 @@
+// Test-only fault injection, compiled into `cargo test --features chaos`. Scripted
+// faults let retry and error handling around the LLM client and the S3
+// uploader be exercised deterministically instead of waiting for OpenAI or
+// AWS to misbehave.
+use std::collections::VecDeque;
+use std::error::Error;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::sleep;
+
+#[derive(Debug, Clone)]
+pub enum Fault {
+	// Let the call through untouched.
+	Healthy,
+	// Delay the call, then let it through.
+	Latency(Duration),
+	// Fail as if the API answered 429 Too Many Requests.
+	RateLimited,
+	// Fail as if the connection was reset mid-request.
+	ConnectionReset,
+}
+
+// A script of faults, consumed one per call. Once it runs out every call is healthy.
+#[derive(Debug, Clone, Default)]
+pub struct Chaos {
+	faults: Arc<Mutex<VecDeque<Fault>>>,
+}
+
+impl Chaos {
+	pub fn new() -> Chaos {
+		Chaos::default()
+	}
+	
+	pub fn schedule(&self, faults: impl IntoIterator<Item = Fault>) {
+		self.faults.lock().unwrap().extend(faults);
+	}
+	
+	// Apply the next scripted fault to the call about to be made.
+	pub async fn inject(&self) -> Result<(), Box<dyn Error>> {
+		let fault = self.faults.lock().unwrap().pop_front();
+		match fault {
+			None | Some(Fault::Healthy) => Ok(()),
+			Some(Fault::Latency(delay)) => {
+				sleep(delay).await;
+				Ok(())
+			}
+			Some(Fault::RateLimited) => Err(Box::new(io::Error::other("chaos: 429 Too Many Requests"))),
+			Some(Fault::ConnectionReset) => Err(Box::new(io::Error::new(
+				io::ErrorKind::ConnectionReset,
+				"chaos: connection reset by peer",
+			))),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Instant;
+	use tokio::net::{TcpListener, TcpStream};
+	use crate::chatgpt::ChatGPT;
+	use crate::config::{InteractionLevel, LimitsConfig};
+	use crate::events::SessionEvent;
+	use crate::handler::handle_client;
+	use crate::prelude::*;
+
+	#[tokio::test]
+	async fn faults_are_applied_in_order() {
+		let chaos = Chaos::new();
+		chaos.schedule([Fault::RateLimited, Fault::Healthy, Fault::Latency(Duration::from_millis(50)), Fault::ConnectionReset]);
+
+		assert!(chaos.inject().await.unwrap_err().to_string().contains("429"));
+		chaos.inject().await.unwrap();
+		let started = Instant::now();
+		chaos.inject().await.unwrap();
+		assert!(started.elapsed() >= Duration::from_millis(50));
+		assert!(chaos.inject().await.unwrap_err().to_string().contains("reset"));
+		chaos.inject().await.unwrap();
+	}
+
+	// An LLM failure must not take the session down; the actor gets the fallback reply.
+	#[tokio::test]
+	async fn session_survives_llm_faults() {
+		let chaos = Chaos::new();
+		chaos.schedule([Fault::RateLimited, Fault::ConnectionReset]);
+		let chatgpt = ChatGPT::new().unwrap().with_chaos(chaos);
+
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		let server = task::spawn(async move {
+			let (stream, _) = listener.accept().await.unwrap();
+			handle_client(stream, None, InteractionLevel::High.into(), LimitsConfig::default(), &chatgpt).await
+		});
+
+		let mut client = TcpStream::connect(addr).await.unwrap();
+		let mut buffer = [0; 1024];
+		for input in ["uname -a\n", "id\n"] {
+			client.write_all(input.as_bytes()).await.unwrap();
+			let n = client.read(&mut buffer).await.unwrap();
+			assert_eq!(&buffer[..n], b"Error processing request");
+		}
+		drop(client);
+
+		let events = server.await.unwrap();
+		assert_eq!(events.last(), Some(&SessionEvent::Closed));
+	}
+}
@@ -0,0 +1,76 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::chatgpt::ChatGPT;
+
+// Anything that can answer an actor's input. ChatGPT is the real backend;
+// MockChatService stands in for it when we don't want to spend API credits,
+// e.g. in `rustbucket simulate`.
+pub trait ChatService: Clone + Send + Sync + 'static {
+	fn send_message(
+		&self,
+		history: &[(String, String)],
+		user_message: &str,
+	) -> impl Future<Output = Result<String, Box<dyn Error>>> + Send;
+}
+
+impl ChatService for ChatGPT {
+	fn send_message(
+		&self,
+		history: &[(String, String)],
+		user_message: &str,
+	) -> impl Future<Output = Result<String, Box<dyn Error>>> + Send {
+		ChatGPT::send_message(self, history, user_message)
+	}
+}
+
+// Answers inputs without leaving the box: queued responses are handed out in
+// order (e.g. the recorded replies of a session fixture), then every further
+// input gets the same canned reply.
+#[derive(Debug, Clone)]
+pub struct MockChatService {
+	response: String,
+	queued: Arc<Mutex<VecDeque<String>>>,
+	latency: Duration,
+}
+
+impl MockChatService {
+	pub fn new(response: &str) -> MockChatService {
+		MockChatService {
+			response: response.to_string(),
+			queued: Arc::new(Mutex::new(VecDeque::new())),
+			latency: Duration::ZERO,
+		}
+	}
+	
+	// Wait this long before every reply, to stand in for a real LLM round trip.
+	pub fn with_latency(mut self, latency: Duration) -> MockChatService {
+		self.latency = latency;
+		self
+	}
+	
+	#[cfg(test)]
+	pub fn with_responses(responses: Vec<String>) -> MockChatService {
+		MockChatService {
+			response: "Invalid Command".to_string(),
+			queued: Arc::new(Mutex::new(responses.into())),
+			latency: Duration::ZERO,
+		}
+	}
+}
+
+impl ChatService for MockChatService {
+	async fn send_message(
+		&self,
+		_history: &[(String, String)],
+		_user_message: &str,
+	) -> Result<String, Box<dyn Error>> {
+		if !self.latency.is_zero() {
+			tokio::time::sleep(self.latency).await;
+		}
+		let queued = self.queued.lock().unwrap().pop_front();
+		Ok(queued.unwrap_or_else(|| format!("{}\n", self.response)))
+	}
+}
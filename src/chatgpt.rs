@@ -1,20 +1,32 @@
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
-use config::{Config, File};
+use config::{Config, ConfigError, File};
+use crate::http_client::{self, HttpConfig};
 use crate::prelude::*;
+#[cfg(all(test, feature = "chaos"))]
+use crate::chaos::Chaos;
 
 // Struct for loading configuration
 #[derive(Debug, Deserialize)]
 struct OpenAIConfig {
 	api_key: String,
+	// Chat completions endpoint, for OpenAI-compatible APIs or a local mock.
+	#[serde(default = "OpenAIConfig::default_api_url")]
+	api_url: String,
 	static_messages: StaticMessages,
 }
 
+impl OpenAIConfig {
+	fn default_api_url() -> String {
+		"https://api.openai.com/v1/chat/completions".to_string()
+	}
+}
+
 #[derive(Debug, Deserialize, Clone)]
-struct StaticMessages {
-	message1: String,
-	message2: String,
+pub struct StaticMessages {
+	pub message1: String,
+	pub message2: String,
 }
 
 #[derive(Serialize, Debug)]
@@ -47,12 +59,17 @@ struct MessageResponse {
 #[derive(Debug, Clone)]
 pub struct ChatGPT {
 	api_key: String,
+	api_url: String,
+	model: String,
 	static_messages: StaticMessages,
 	client: Client,
+	#[cfg(all(test, feature = "chaos"))]
+	chaos: Chaos,
 }
 
 impl ChatGPT {
 	const CONFIG_FILE: &'static str = "Config.toml";
+	pub const DEFAULT_MODEL: &'static str = "gpt-3.5-turbo";
 	
 	pub fn new() -> Result<ChatGPT, Box<dyn Error>> {
 		Self::from_config(Self::CONFIG_FILE)
@@ -61,30 +78,74 @@ impl ChatGPT {
 	pub fn from_config(config_file: &str) -> Result<ChatGPT, Box<dyn Error>> {
 		// Load configuration from the specified config file
 		let settings = Config::builder()
-			.add_source(File::with_name(Self::CONFIG_FILE))
+			.add_source(File::with_name(config_file))
 			.build()?;
 		
 		let openai_config: OpenAIConfig = settings.get::<OpenAIConfig>("openai")?;
+		// Timeouts and proxy from [http], even before a shared client is swapped in
+		let http_config = match settings.get::<HttpConfig>("http") {
+			Err(ConfigError::NotFound(_)) => HttpConfig::default(),
+			http_config => http_config?,
+		};
 		
 		Ok(ChatGPT {
 			api_key: openai_config.api_key,
+			api_url: openai_config.api_url,
+			model: Self::DEFAULT_MODEL.to_string(),
 			static_messages: openai_config.static_messages,
-			client: Client::new(),
+			client: http_client::build_client(&http_config)?,
+			#[cfg(all(test, feature = "chaos"))]
+			chaos: Chaos::new(),
 		})
 	}
 	
+	// Inject scripted faults into every request this client makes.
+	#[cfg(all(test, feature = "chaos"))]
+	pub fn with_chaos(mut self, chaos: Chaos) -> ChatGPT {
+		self.chaos = chaos;
+		self
+	}
+	
+	// Swap in a different set of prompts, e.g. so a persona can play a router
+	// instead of an Ubuntu server.
+	pub fn with_static_messages(mut self, static_messages: StaticMessages) -> ChatGPT {
+		self.static_messages = static_messages;
+		self
+	}
+	
+	// Share one connection pool (and its timeouts and proxy settings) with the rest of the process.
+	pub fn with_client(mut self, client: Client) -> ChatGPT {
+		self.client = client;
+		self
+	}
+	
+	// Send requests somewhere other than the configured endpoint, e.g. a local mock server.
+	#[cfg(test)]
+	pub fn with_api_url(mut self, api_url: String) -> ChatGPT {
+		self.api_url = api_url;
+		self
+	}
+	
+	// Use a different OpenAI model, e.g. a cheap one for ports that only see bot floods.
+	pub fn with_model(mut self, model: String) -> ChatGPT {
+		self.model = model;
+		self
+	}
+	
+	// `history` holds earlier (actor input, reply) pairs from the same session
+	// so ChatGPT can keep its story straight. Pass an empty slice to send the
+	// message on its own.
 	pub async fn send_message(
 		&self,
+		history: &[(String, String)],
 		user_message: &str,
 	) -> Result<String, Box<dyn Error>> {
-		let url = "https://api.openai.com/v1/chat/completions";
-		
 		// We prompt ChatGPT with several messages before we deliver the user's
 		// input. Our goal is to make ChatGPT respond as if it were an Ubuntu
 		// server. ChatGPT does this well about 60% of the time so far.
 		// Since most "users" that connect to this rustbucket are bots
 		// this is an acceptable hit rate.
-		let messages = vec![
+		let mut messages = vec![
 			Message {
 				role: "system",
 				content: &self.static_messages.message1,
@@ -93,21 +154,34 @@ impl ChatGPT {
 				role: "system",
 				content: &self.static_messages.message2,
 			},
-			Message {
-				role: "user",
-				content: user_message,
-			},
 		];
+		for (input, reply) in history {
+			messages.push(Message {
+				role: "user",
+				content: input,
+			});
+			messages.push(Message {
+				role: "assistant",
+				content: reply,
+			});
+		}
+		messages.push(Message {
+			role: "user",
+			content: user_message,
+		});
 		
 		let request_body = ChatGPTRequest {
-			model: "gpt-3.5-turbo", //@todo Move this to config.rs
+			model: &self.model,
 			messages,
 		};
 		
+		#[cfg(all(test, feature = "chaos"))]
+		self.chaos.inject().await?;
+		
 		// Send our request to ChatGPT.
 		let response = self
 			.client
-			.post(url)
+			.post(&self.api_url)
 			.header("Authorization", format!("Bearer {}", self.api_key))
 			.json(&request_body)
 			.send()
@@ -118,17 +192,100 @@ impl ChatGPT {
 			// Most likely issues: invalid API key, rate limiting, quota exceeded, etc.
 			let error_text = response.text().await?;
 			error!("Error response from ChatGPT: {}", error_text);
-			return Err(Box::new(std::io::Error::new(
-				std::io::ErrorKind::Other,
+			return Err(Box::new(std::io::Error::other(
 				"Failed to get a successful response from ChatGPT",
 			)));
 		}
 		//@todo Change the format of the log message to be more parseable.
 		info!("We sent this to ChatGPT: {:?}", request_body);
 		let response_json: ChatGPTResponse = response.json().await?;
-		let reply = format!("{}\n", &response_json.choices[0].message.content);
+		let choice = response_json.choices.first().ok_or("ChatGPT returned no choices")?;
+		let reply = format!("{}\n", choice.message.content);
 		info!("ChatGPT responded: {}", reply);
 		
 		Ok(reply.to_string())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::mock_http::{MockHttpServer, MockResponse};
+
+	fn client_for(mock: &MockHttpServer) -> ChatGPT {
+		ChatGPT::new().unwrap().with_api_url(mock.completions_url())
+	}
+
+	#[test]
+	fn loads_the_config_file_it_is_given() {
+		let config_file = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/Config.toml");
+		let chatgpt = ChatGPT::from_config(config_file).unwrap();
+		assert_eq!(chatgpt.api_key, "fixture-api-key");
+		assert!(ChatGPT::from_config("/nonexistent/Config.toml").is_err());
+	}
+
+	#[tokio::test]
+	async fn returns_first_choice_with_trailing_newline() {
+		let mock = MockHttpServer::start().await;
+		mock.respond_with(MockResponse::completion("Linux web01 5.15.0-91-generic x86_64"));
+		let chatgpt = client_for(&mock);
+
+		let reply = chatgpt.send_message(&[], "uname -a").await.unwrap();
+		assert_eq!(reply, "Linux web01 5.15.0-91-generic x86_64\n");
+	}
+
+	#[tokio::test]
+	async fn sends_prompts_history_and_model() {
+		let mock = MockHttpServer::start().await;
+		mock.respond_with(MockResponse::completion("root"));
+		let chatgpt = client_for(&mock).with_model("gpt-4o-mini".to_string());
+
+		let history = vec![("id\n".to_string(), "uid=0(root) gid=0(root)\n".to_string())];
+		chatgpt.send_message(&history, "whoami").await.unwrap();
+
+		let requests = mock.requests();
+		assert_eq!(requests.len(), 1);
+		let request = &requests[0];
+		assert_eq!(request.method, "POST");
+		assert_eq!(request.path, "/v1/chat/completions");
+		assert_eq!(request.headers["authorization"], format!("Bearer {}", chatgpt.api_key));
+		assert_eq!(request.body["model"], "gpt-4o-mini");
+
+		let roles: Vec<&str> = request.body["messages"]
+			.as_array()
+			.unwrap()
+			.iter()
+			.map(|m| m["role"].as_str().unwrap())
+			.collect();
+		assert_eq!(roles, ["system", "system", "user", "assistant", "user"]);
+		assert_eq!(request.body["messages"][4]["content"], "whoami");
+	}
+
+	#[tokio::test]
+	async fn api_errors_are_returned_as_errors() {
+		let mock = MockHttpServer::start().await;
+		mock.respond_with(MockResponse::error(429, "Rate limit reached"));
+		let chatgpt = client_for(&mock);
+
+		assert!(chatgpt.send_message(&[], "ls").await.is_err());
+		assert_eq!(mock.requests().len(), 1);
+	}
+
+	#[tokio::test]
+	async fn malformed_responses_are_errors() {
+		let mock = MockHttpServer::start().await;
+		mock.respond_with(MockResponse::new(200, "{\"choices\": \"nope\"}"));
+		let chatgpt = client_for(&mock);
+
+		assert!(chatgpt.send_message(&[], "ls").await.is_err());
+	}
+
+	#[tokio::test]
+	async fn empty_choices_are_errors() {
+		let mock = MockHttpServer::start().await;
+		mock.respond_with(MockResponse::new(200, "{\"choices\": []}"));
+		let chatgpt = client_for(&mock);
+
+		assert!(chatgpt.send_message(&[], "ls").await.is_err());
+	}
+}
@@ -0,0 +1,516 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use config::{Config, File};
+use crate::admin::AdminConfig;
+use crate::auth_log::AuthLogConfig;
+use crate::blocklist::BlocklistConfig;
+use crate::countermeasures::CountermeasuresConfig;
+use crate::crowdsec::CrowdSecConfig;
+use crate::exposure::ExposureConfig;
+use crate::chatgpt::{ChatGPT, StaticMessages};
+use crate::fingerprint::{Fingerprint, FingerprintConfig};
+use crate::flow_export::FlowExportConfig;
+use crate::git::GitConfig;
+use crate::http_client::HttpConfig;
+use crate::kerberos::KerberosConfig;
+use crate::log_batcher::LogUploadConfig;
+use crate::payload_fetch::PayloadFetchConfig;
+use crate::plugins::PluginsConfig;
+use crate::rsync::RsyncConfig;
+use crate::scripting::ScriptingConfig;
+use crate::updater::UpdateConfig;
+use crate::telemetry::MetricsConfig;
+use crate::acme::AcmeConfig;
+use crate::tls::TlsConfig;
+use crate::zeek::ZeekConfig;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PortConfig {
+	// The service's key in [ports], e.g. "ftp". Filled in when the config is loaded.
+	#[serde(default)]
+	pub name: String,
+	pub enabled: bool,
+	pub port: u16,
+	// Serve the service over TLS with the persona's certificate.
+	#[serde(default)]
+	pub tls: bool,
+	// Greeting written to the actor as soon as they connect, e.g.
+	// "220 ProFTPD 1.3.5 Server". Leave unset for protocols where the
+	// client speaks first (HTTP).
+	#[serde(default)]
+	pub banner: Option<String>,
+	// Canned reply used whenever a payload isn't handed to the LLM.
+	#[serde(default)]
+	pub static_response: Option<String>,
+}
+
+impl PortConfig {
+	pub fn is_web(&self) -> bool {
+		matches!(self.name.as_str(), "http" | "https")
+	}
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Ports {
+	pub ssh: PortConfig,
+	pub http: PortConfig,
+	#[serde(default = "Ports::default_https")]
+	pub https: PortConfig,
+	pub ftp: PortConfig,
+	pub sftp: PortConfig,
+	pub smtp: PortConfig,
+	pub dns: PortConfig,
+	pub sms: PortConfig,
+	#[serde(default = "Ports::default_kerberos")]
+	pub kerberos: PortConfig,
+	#[serde(default = "Ports::default_rsync")]
+	pub rsync: PortConfig,
+	#[serde(default = "Ports::default_git")]
+	pub git: PortConfig,
+}
+
+impl Ports {
+	// Older configs predate https, so it falls back to a disabled listener.
+	fn default_https() -> PortConfig {
+		PortConfig {
+			name: String::new(),
+			enabled: false,
+			port: 443,
+			tls: true,
+			banner: None,
+			static_response: None,
+		}
+	}
+
+	fn default_kerberos() -> PortConfig {
+		PortConfig {
+			name: String::new(),
+			enabled: false,
+			port: 88,
+			tls: false,
+			banner: None,
+			static_response: None,
+		}
+	}
+
+	fn default_rsync() -> PortConfig {
+		PortConfig {
+			name: String::new(),
+			enabled: false,
+			port: 873,
+			tls: false,
+			banner: None,
+			static_response: None,
+		}
+	}
+
+	fn default_git() -> PortConfig {
+		PortConfig {
+			name: String::new(),
+			enabled: false,
+			port: 9418,
+			tls: false,
+			banner: None,
+			static_response: None,
+		}
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = &PortConfig> {
+		[
+			&self.ssh,
+			&self.http,
+			&self.https,
+			&self.ftp,
+			&self.sftp,
+			&self.smtp,
+			&self.dns,
+			&self.sms,
+			&self.kerberos,
+			&self.rsync,
+			&self.git,
+		]
+		.into_iter()
+	}
+
+	// Label each service with its key in [ports], for logs and metrics.
+	fn assign_names(&mut self) {
+		for (name, service) in [
+			("ssh", &mut self.ssh),
+			("http", &mut self.http),
+			("https", &mut self.https),
+			("ftp", &mut self.ftp),
+			("sftp", &mut self.sftp),
+			("smtp", &mut self.smtp),
+			("dns", &mut self.dns),
+			("sms", &mut self.sms),
+			("kerberos", &mut self.kerberos),
+			("rsync", &mut self.rsync),
+			("git", &mut self.git),
+		] {
+			service.name = name.to_string();
+		}
+	}
+
+	// Look up the service configured for a given port number.
+	pub fn find(&self, port: u16) -> Option<&PortConfig> {
+		self.iter().find(|p| p.port == port)
+	}
+}
+
+// Bundles of behavior so operators can pick how deep the deception goes
+// without having to understand every individual knob.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InteractionLevel {
+	// Banners and static responses only. ChatGPT is never called.
+	Low,
+	// Static responses, with a sample of payloads answered by ChatGPT.
+	Medium,
+	// Every payload goes to ChatGPT, which remembers the rest of the session,
+	// and files the actor downloads are fetched for real (see payload_fetch).
+	#[default]
+	High,
+}
+
+// The level a session runs at, with the share of payloads ChatGPT answers
+// at the medium level. Set in [general] and overridable per persona.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Interaction {
+	#[serde(rename = "interaction_level")]
+	pub level: InteractionLevel,
+	#[serde(default = "GeneralConfig::default_medium_llm_sample_rate")]
+	pub medium_llm_sample_rate: f64,
+}
+
+impl Interaction {
+	// Fraction of actor payloads that are sent to ChatGPT.
+	pub fn llm_sample_rate(&self) -> f64 {
+		match self.level {
+			InteractionLevel::Low => 0.0,
+			InteractionLevel::Medium => self.medium_llm_sample_rate,
+			InteractionLevel::High => 1.0,
+		}
+	}
+	
+	// Whether earlier exchanges in the session are replayed to ChatGPT.
+	pub fn session_memory(&self) -> bool {
+		self.level == InteractionLevel::High
+	}
+	
+	// Whether files the actor tells the shell to download are fetched.
+	pub fn fetches_payloads(&self) -> bool {
+		self.level == InteractionLevel::High
+	}
+	
+	fn validate(&self) -> Result<(), String> {
+		// Written this way round so NaN is refused too
+		if !(0.0..=1.0).contains(&self.medium_llm_sample_rate) {
+			return Err(format!("medium_llm_sample_rate must be between 0 and 1, not {}", self.medium_llm_sample_rate));
+		}
+		Ok(())
+	}
+}
+
+impl From<InteractionLevel> for Interaction {
+	fn from(level: InteractionLevel) -> Interaction {
+		Interaction { level, medium_llm_sample_rate: GeneralConfig::default_medium_llm_sample_rate() }
+	}
+}
+
+// Caps that keep thousands of concurrent slow sessions (a realistic attack on
+// a honeypot) from ballooning memory.
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct LimitsConfig {
+	// Payload bytes a single session may hold on to before it is disconnected.
+	#[serde(default = "LimitsConfig::default_max_session_bytes")]
+	pub max_session_bytes: usize,
+	// Payloads longer than this are truncated in the log.
+	#[serde(default = "LimitsConfig::default_max_logged_bytes")]
+	pub max_logged_bytes: usize,
+}
+
+impl LimitsConfig {
+	fn default_max_session_bytes() -> usize {
+		256 * 1024
+	}
+	
+	fn default_max_logged_bytes() -> usize {
+		4096
+	}
+}
+
+impl Default for LimitsConfig {
+	fn default() -> Self {
+		LimitsConfig {
+			max_session_bytes: Self::default_max_session_bytes(),
+			max_logged_bytes: Self::default_max_logged_bytes(),
+		}
+	}
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct GeneralConfig {
+	// Initial log filter in RUST_LOG syntax. Can be changed at runtime over the admin socket.
+	#[serde(default = "GeneralConfig::default_log_level")]
+	pub log_level: String,
+	#[serde(default)]
+	pub interaction_level: InteractionLevel,
+	// Fraction of payloads ChatGPT answers at the medium level, from 0 to 1.
+	#[serde(default = "GeneralConfig::default_medium_llm_sample_rate")]
+	pub medium_llm_sample_rate: f64,
+	// If set, every finished session is saved here as a replayable test fixture.
+	#[serde(default)]
+	pub record_sessions_dir: Option<String>,
+	// How long in-flight sessions get to finish after a shutdown signal.
+	#[serde(default = "GeneralConfig::default_shutdown_grace_secs")]
+	pub shutdown_grace_secs: u64,
+}
+
+impl GeneralConfig {
+	fn default_log_level() -> String {
+		"info".to_string()
+	}
+
+	fn default_medium_llm_sample_rate() -> f64 {
+		0.25
+	}
+
+	fn default_shutdown_grace_secs() -> u64 {
+		10
+	}
+}
+
+impl Default for GeneralConfig {
+	fn default() -> GeneralConfig {
+		GeneralConfig {
+			log_level: GeneralConfig::default_log_level(),
+			interaction_level: InteractionLevel::default(),
+			medium_llm_sample_rate: GeneralConfig::default_medium_llm_sample_rate(),
+			record_sessions_dir: None,
+			shutdown_grace_secs: GeneralConfig::default_shutdown_grace_secs(),
+		}
+	}
+}
+
+// Which OpenAI model answers each listener, so spend can be concentrated
+// where response quality actually matters.
+#[derive(Debug, Deserialize, Clone)]
+pub struct LlmConfig {
+	#[serde(default = "LlmConfig::default_model")]
+	pub default_model: String,
+	// Port number -> model name overrides.
+	#[serde(default)]
+	pub models: HashMap<String, String>,
+}
+
+impl LlmConfig {
+	fn default_model() -> String {
+		ChatGPT::DEFAULT_MODEL.to_string()
+	}
+	
+	pub fn model_for(&self, port: u16) -> String {
+		self.models
+			.get(&port.to_string())
+			.cloned()
+			.unwrap_or_else(|| self.default_model.clone())
+	}
+}
+
+impl Default for LlmConfig {
+	fn default() -> Self {
+		LlmConfig {
+			default_model: Self::default_model(),
+			models: HashMap::new(),
+		}
+	}
+}
+
+// One identity the honeypot presents: a bind address, the ports it exposes,
+// a hostname, and optionally its own ChatGPT prompts. Several personas bound
+// to different secondary IPs let one VM look like a web server, a mail
+// server and a router at once.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Persona {
+	pub name: String,
+	#[serde(default = "Persona::default_bind_address")]
+	pub bind_address: String,
+	#[serde(default = "Persona::default_ports")]
+	pub ports: Vec<u16>,
+	// Substituted for `{hostname}` in banners and static responses.
+	#[serde(default)]
+	pub hostname: Option<String>,
+	// Overrides [openai.static_messages] for this persona.
+	#[serde(default)]
+	pub static_messages: Option<StaticMessages>,
+	// TLS sessions asking for one of these names by SNI, on any persona's
+	// listener, are served as this persona.
+	#[serde(default)]
+	pub server_names: Vec<String>,
+	// Override [general] interaction_level and medium_llm_sample_rate.
+	#[serde(default)]
+	pub interaction_level: Option<InteractionLevel>,
+	#[serde(default)]
+	pub medium_llm_sample_rate: Option<f64>,
+}
+
+impl Persona {
+	fn default_bind_address() -> String {
+		"0.0.0.0".to_string()
+	}
+	
+	fn default_ports() -> Vec<u16> {
+		vec![25, 23, 21, 80]
+	}
+	
+	// The persona used when none are configured: every listener on all interfaces.
+	pub fn default_persona() -> Persona {
+		Persona {
+			name: "default".to_string(),
+			bind_address: Self::default_bind_address(),
+			ports: Self::default_ports(),
+			hostname: None,
+			static_messages: None,
+			server_names: vec![],
+			interaction_level: None,
+			medium_llm_sample_rate: None,
+		}
+	}
+	
+	// How deep this persona's sessions go.
+	pub fn interaction(&self, general: &GeneralConfig) -> Interaction {
+		Interaction {
+			level: self.interaction_level.unwrap_or(general.interaction_level),
+			medium_llm_sample_rate: self.medium_llm_sample_rate.unwrap_or(general.medium_llm_sample_rate),
+		}
+	}
+	
+	pub fn address(&self, port: u16) -> String {
+		// IPv6 addresses need brackets before a port can be appended
+		if self.bind_address.contains(':') {
+			format!("[{}]:{}", self.bind_address, port)
+		} else {
+			format!("{}:{}", self.bind_address, port)
+		}
+	}
+	
+	// Fill in this persona's identity in a service's banner and static response.
+	pub fn customize(&self, mut service: PortConfig) -> PortConfig {
+		if let Some(hostname) = &self.hostname {
+			service.banner = service.banner.map(|b| b.replace("{hostname}", hostname));
+			service.static_response = service.static_response.map(|r| r.replace("{hostname}", hostname));
+		}
+		service
+	}
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AppConfig {
+	#[serde(default)]
+	pub general: GeneralConfig,
+	pub ports: Ports,
+	#[serde(default)]
+	pub llm: LlmConfig,
+	#[serde(default)]
+	pub http: HttpConfig,
+	#[serde(default)]
+	pub limits: LimitsConfig,
+	#[serde(default)]
+	pub metrics: MetricsConfig,
+	#[serde(default)]
+	pub admin: AdminConfig,
+	#[serde(default)]
+	pub auth_log: AuthLogConfig,
+	#[serde(default)]
+	pub crowdsec: CrowdSecConfig,
+	#[serde(default)]
+	pub blocklist: BlocklistConfig,
+	#[serde(default)]
+	pub flow_export: FlowExportConfig,
+	#[serde(default)]
+	pub zeek: ZeekConfig,
+	#[serde(default)]
+	pub log_upload: LogUploadConfig,
+	#[serde(default)]
+	pub tls: TlsConfig,
+	#[serde(default)]
+	pub acme: AcmeConfig,
+	#[serde(default)]
+	pub plugins: PluginsConfig,
+	#[serde(default)]
+	pub scripting: ScriptingConfig,
+	#[serde(default)]
+	pub countermeasures: CountermeasuresConfig,
+	#[serde(default)]
+	pub payload_fetch: PayloadFetchConfig,
+	#[serde(default)]
+	pub update: UpdateConfig,
+	#[serde(default)]
+	pub exposure: ExposureConfig,
+	#[serde(default)]
+	pub kerberos: KerberosConfig,
+	#[serde(default)]
+	pub rsync: RsyncConfig,
+	#[serde(default)]
+	pub git: GitConfig,
+	#[serde(default)]
+	pub fingerprint: FingerprintConfig,
+	#[serde(default)]
+	pub personas: Vec<Persona>,
+}
+
+impl AppConfig {
+	const CONFIG_FILE: &'static str = "Config.toml";
+
+	pub fn new() -> Result<AppConfig, Box<dyn Error>> {
+		Self::from_config(Self::CONFIG_FILE)
+	}
+
+	pub fn from_config(config_file: &str) -> Result<AppConfig, Box<dyn Error>> {
+		let settings = Config::builder()
+			.add_source(File::with_name(config_file))
+			.build()?;
+
+		let mut app_config = settings.try_deserialize::<AppConfig>()?;
+		app_config.ports.assign_names();
+		for persona in app_config.personas() {
+			persona.interaction(&app_config.general).validate().map_err(|e| format!("persona {}: {}", persona.name, e))?;
+		}
+		if app_config.fingerprint.randomize {
+			let fingerprint = Fingerprint::load_or_generate(&app_config.fingerprint.state_file)?;
+			fingerprint.apply(&mut app_config);
+		}
+		
+		Ok(app_config)
+	}
+	
+	// The configured personas, or a single default one if there are none.
+	pub fn personas(&self) -> Vec<Persona> {
+		if self.personas.is_empty() {
+			vec![Persona::default_persona()]
+		} else {
+			self.personas.clone()
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn personas_override_the_interaction_and_bad_rates_are_refused() {
+		let general = GeneralConfig { interaction_level: InteractionLevel::Medium, medium_llm_sample_rate: 0.5, ..GeneralConfig::default() };
+		let mut persona = Persona::default_persona();
+		assert_eq!(persona.interaction(&general).llm_sample_rate(), 0.5);
+		persona.medium_llm_sample_rate = Some(0.1);
+		assert_eq!(persona.interaction(&general).llm_sample_rate(), 0.1);
+		persona.interaction_level = Some(InteractionLevel::High);
+		assert!(persona.interaction(&general).fetches_payloads());
+
+		for rate in [f64::NAN, f64::INFINITY, -0.1, 1.5] {
+			assert!(Interaction { level: InteractionLevel::Medium, medium_llm_sample_rate: rate }.validate().is_err(), "{}", rate);
+		}
+		assert!(Interaction::from(InteractionLevel::Medium).validate().is_ok());
+	}
+}
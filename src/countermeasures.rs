@@ -0,0 +1,247 @@
+// Answers to the checks scanners run to tell a honeypot from a real host.
+// An LLM or a canned response tends to flunk them: it answers the HTTP
+// request, TLS ClientHello or SSH version string Shodan's crawler sends to
+// every port (and scores honeypots on) as if the mail server spoke that
+// protocol, knows Cowrie's default users, gets `echo -e` escapes and busybox
+// applets wrong, returns from `sleep 5` at once and gives a different answer
+// every time the same command is repeated. The LLM plays a shell on every
+// port, so the shell probes are checked everywhere. When a payload looks like
+// one of these probes it gets the answer a real host would give instead, and
+// the session is tagged as a honeypot-aware scanner so we know who is
+// checking.
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+pub const TAG: &str = "honeypot-aware scanner";
+// How many times a payload may repeat before it counts as a consistency probe.
+const REPEAT_PROBE_THRESHOLD: u32 = 3;
+// Distinct payloads remembered per session for repeat answers.
+const MAX_REMEMBERED: usize = 64;
+// Usernames and hostnames Cowrie and Kippo ship with.
+const DEFAULT_ARTIFACTS: &[&str] = &["richard", "phil", "svr04", "nas3"];
+// Applets a BusyBox build really has, of the ones bots call.
+const BUSYBOX_APPLETS: &[&str] = &["cat", "chmod", "cp", "echo", "ftpget", "kill", "ls", "mkdir", "ps", "rm", "sh", "tftp", "wget"];
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CountermeasuresConfig {
+	#[serde(default = "CountermeasuresConfig::default_enabled")]
+	pub enabled: bool,
+	// Longest `sleep` probe that is actually slept through.
+	#[serde(default = "CountermeasuresConfig::default_max_sleep_secs")]
+	pub max_sleep_secs: u64,
+}
+
+impl CountermeasuresConfig {
+	fn default_enabled() -> bool {
+		true
+	}
+
+	fn default_max_sleep_secs() -> u64 {
+		10
+	}
+}
+
+impl Default for CountermeasuresConfig {
+	fn default() -> Self {
+		CountermeasuresConfig {
+			enabled: Self::default_enabled(),
+			max_sleep_secs: Self::default_max_sleep_secs(),
+		}
+	}
+}
+
+static CONFIG: OnceLock<CountermeasuresConfig> = OnceLock::new();
+
+// Until installed, every payload is answered as usual.
+pub fn install(config: &CountermeasuresConfig) {
+	if config.enabled {
+		let _ = CONFIG.set(config.clone());
+	}
+}
+
+// The answer to a recognised probe.
+#[derive(Debug, PartialEq)]
+pub struct Answer {
+	pub probe: &'static str,
+	pub reply: String,
+	// Wait this long before replying, as the real command would take.
+	pub delay: Duration,
+	// The real server hangs up after this reply.
+	pub hang_up: bool,
+}
+
+impl Answer {
+	fn new(probe: &'static str, reply: String) -> Answer {
+		Answer { probe, reply, delay: Duration::ZERO, hang_up: false }
+	}
+}
+
+// The real host's answer, if the payload is a known probe.
+pub fn check(service: &str, payload: &str) -> Option<Answer> {
+	CONFIG.get().and_then(|config| detect(config, service, payload))
+}
+
+fn detect(config: &CountermeasuresConfig, service: &str, payload: &str) -> Option<Answer> {
+	if let Some(answer) = cross_protocol(service, payload) {
+		return Some(answer);
+	}
+	// Shell probes come one command per line or chained with ;
+	payload
+		.lines()
+		.flat_map(|line| line.split(';'))
+		.map(str::trim)
+		.filter(|command| !command.is_empty())
+		.find_map(|command| shell_probe(config, command))
+}
+
+// Another protocol's opening sent to a mail or file server, which a honeypot
+// answering everything with the same engine gets wrong.
+fn cross_protocol(service: &str, payload: &str) -> Option<Answer> {
+	let first_line = payload.lines().next()?;
+	let is_http = ["GET ", "POST ", "HEAD ", "OPTIONS "].iter().any(|m| first_line.starts_with(m)) && first_line.contains(" HTTP/1.");
+	// A handshake record, decoded lossily from the raw bytes
+	let is_tls = payload.starts_with("\u{16}\u{3}");
+	let is_ssh = first_line.starts_with("SSH-2.0-") || first_line.starts_with("SSH-1.99-");
+	let answer = match service {
+		// What Postfix says before hanging up on a web client
+		"smtp" if is_http => Answer { hang_up: true, ..Answer::new("cross-protocol", "221 2.7.0 Error: I can break rules, too. Goodbye.\r\n".to_string()) },
+		"smtp" if is_tls || is_ssh => Answer::new("cross-protocol", "502 5.5.2 Error: command not recognized\r\n".to_string()),
+		"ftp" if is_http || is_tls || is_ssh => Answer::new("cross-protocol", "500 Unknown command.\r\n".to_string()),
+		_ => return None,
+	};
+	Some(answer)
+}
+
+fn shell_probe(config: &CountermeasuresConfig, command: &str) -> Option<Answer> {
+	let mut words = command.split_whitespace();
+	let program = words.next()?;
+	let args: Vec<&str> = words.collect();
+	let name = program.rsplit('/').next().unwrap_or(program);
+
+	if name == "busybox" {
+		let applet = *args.first()?;
+		// Bots check for a made-up upper-case applet, which real BusyBox doesn't have
+		if !BUSYBOX_APPLETS.contains(&applet) && applet.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()) {
+			return Some(Answer::new("busybox-applet", format!("{}: applet not found\n", applet)));
+		}
+		return None;
+	}
+	if name == "echo" && args.first().is_some_and(|flag| matches!(*flag, "-e" | "-ne" | "-en")) && command.contains('\\') {
+		let text = args[1..].join(" ");
+		let newline = if args[0] == "-e" { "\n" } else { "" };
+		return Some(Answer::new("echo-escapes", format!("{}{}", unescape(unquote(&text)), newline)));
+	}
+	if name == "sleep" {
+		let secs = args.first()?.parse::<f64>().ok().filter(|s| s.is_finite() && *s >= 0.0)?;
+		let delay = Duration::from_secs_f64(secs.min(config.max_sleep_secs as f64));
+		return Some(Answer { delay, ..Answer::new("sleep-timing", String::new()) });
+	}
+	let artifact = args.iter().find(|arg| DEFAULT_ARTIFACTS.iter().any(|a| arg.split('/').any(|part| part == *a)))?;
+	let reply = match name {
+		"ls" => format!("ls: cannot access '{}': No such file or directory\n", artifact),
+		"cd" => format!("-bash: cd: {}: No such file or directory\n", artifact),
+		"id" | "groups" | "finger" => format!("{}: '{}': no such user\n", name, artifact),
+		_ => format!("{}: {}: No such file or directory\n", name, artifact),
+	};
+	Some(Answer::new("default-artifacts", reply))
+}
+
+fn unquote(text: &str) -> &str {
+	let text = text.trim();
+	for quote in ['"', '\''] {
+		if let Some(inner) = text.strip_prefix(quote).and_then(|t| t.strip_suffix(quote)) {
+			return inner;
+		}
+	}
+	text
+}
+
+// Expand the escapes `echo -e` understands.
+fn unescape(text: &str) -> String {
+	let mut out = String::new();
+	let mut chars = text.chars().peekable();
+	while let Some(c) = chars.next() {
+		if c != '\\' {
+			out.push(c);
+			continue;
+		}
+		match chars.next() {
+			Some('n') => out.push('\n'),
+			Some('t') => out.push('\t'),
+			Some('r') => out.push('\r'),
+			Some('\\') => out.push('\\'),
+			Some('x') => {
+				let hex: String = std::iter::from_fn(|| chars.next_if(|c| c.is_ascii_hexdigit())).take(2).collect();
+				match u8::from_str_radix(&hex, 16) {
+					Ok(byte) => out.push(byte as char),
+					Err(_) => out.push_str("\\x"),
+				}
+			}
+			Some('0') => {
+				let octal: String = std::iter::from_fn(|| chars.next_if(|c| ('0'..='7').contains(c))).take(3).collect();
+				out.push(u8::from_str_radix(&octal, 8).unwrap_or(0) as char);
+			}
+			Some(other) => {
+				out.push('\\');
+				out.push(other);
+			}
+			None => out.push('\\'),
+		}
+	}
+	out
+}
+
+// Earlier answers in a session, so a repeated payload gets the same one. A
+// real host is consistent; an LLM rarely is, and scanners check.
+#[derive(Default)]
+pub struct Consistency {
+	answers: HashMap<String, (String, u32)>,
+}
+
+impl Consistency {
+	// The earlier answer to `payload`, and whether it has now repeated often enough to be a probe.
+	pub fn recall(&mut self, payload: &str) -> Option<(String, bool)> {
+		CONFIG.get()?;
+		let (reply, seen) = self.answers.get_mut(payload)?;
+		*seen += 1;
+		Some((reply.clone(), *seen == REPEAT_PROBE_THRESHOLD))
+	}
+
+	pub fn remember(&mut self, payload: &str, reply: &str) {
+		if CONFIG.get().is_some() && self.answers.len() < MAX_REMEMBERED {
+			self.answers.insert(payload.to_string(), (reply.to_string(), 1));
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn known_probes_get_a_real_hosts_answer() {
+		let config = CountermeasuresConfig::default();
+		let probe = |service, payload| detect(&config, service, payload);
+
+		let goodbye = probe("smtp", "GET / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+		assert_eq!((goodbye.reply.as_str(), goodbye.hang_up), ("221 2.7.0 Error: I can break rules, too. Goodbye.\r\n", true));
+		assert!(probe("http", "GET / HTTP/1.1\r\nHost: x\r\n\r\n").is_none());
+		// Shodan's crawler opens with TLS and SSH on every port too
+		let client_hello = String::from_utf8_lossy(b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03").to_string();
+		assert_eq!(probe("smtp", &client_hello).unwrap().reply, "502 5.5.2 Error: command not recognized\r\n");
+		assert_eq!(probe("ftp", "SSH-2.0-Go\r\n").unwrap().reply, "500 Unknown command.\r\n");
+		assert!(!probe("ftp", "SSH-2.0-Go\r\n").unwrap().hang_up);
+		// The LLM plays a shell on the web port as well
+		assert_eq!(probe("http", "ls /home/richard").unwrap().probe, "default-artifacts");
+		assert_eq!(probe("ftp", "enable\r\nsystem\r\nshell\r\nsh\r\n/bin/busybox ECCHI\r\n").unwrap().reply, "ECCHI: applet not found\n");
+		assert!(probe("ftp", "/bin/busybox wget http://x/a").is_none());
+		assert_eq!(probe("ftp", r#"echo -e "\x6F\x6B\0101""#).unwrap().reply, "okA\n");
+		let sleep = probe("ftp", "uname -a; sleep 30").unwrap();
+		assert_eq!((sleep.probe, sleep.delay), ("sleep-timing", Duration::from_secs(10)));
+		assert_eq!(probe("ftp", "ls /home/richard").unwrap().reply, "ls: cannot access '/home/richard': No such file or directory\n");
+		assert!(probe("ftp", "ls /home/richardson").is_none());
+		assert!(probe("smtp", "EHLO mail.example.com\r\n").is_none());
+	}
+}
@@ -0,0 +1,227 @@
+// Push alerts for attacking sources to a CrowdSec Local API, registered as a
+// watcher machine (`cscli machines add rustbucket --password ...`). Each alert
+// carries a ban decision, so local bouncers act on it and, with the console
+// enrolled, it is shared with the community blocklist.
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+use crate::sinks::Offense;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CrowdSecConfig {
+	#[serde(default)]
+	pub enabled: bool,
+	#[serde(default = "CrowdSecConfig::default_lapi_url")]
+	pub lapi_url: String,
+	#[serde(default)]
+	pub machine_id: String,
+	#[serde(default)]
+	pub password: String,
+	// How long bouncers should block the source, in CrowdSec duration syntax.
+	#[serde(default = "CrowdSecConfig::default_ban_duration")]
+	pub ban_duration: String,
+	// Also alert on sources that only probed (sent payloads without logging in).
+	#[serde(default = "CrowdSecConfig::default_alert_on_probe")]
+	pub alert_on_probe: bool,
+}
+
+impl CrowdSecConfig {
+	fn default_lapi_url() -> String {
+		"http://127.0.0.1:8080".to_string()
+	}
+
+	fn default_ban_duration() -> String {
+		"4h".to_string()
+	}
+
+	fn default_alert_on_probe() -> bool {
+		true
+	}
+}
+
+impl Default for CrowdSecConfig {
+	fn default() -> Self {
+		CrowdSecConfig {
+			enabled: false,
+			lapi_url: Self::default_lapi_url(),
+			machine_id: String::new(),
+			password: String::new(),
+			ban_duration: Self::default_ban_duration(),
+			alert_on_probe: Self::default_alert_on_probe(),
+		}
+	}
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginResponse {
+	token: String,
+}
+
+#[derive(Clone)]
+pub struct CrowdSec {
+	config: CrowdSecConfig,
+	client: Client,
+	// JWT from /v1/watchers/login, refreshed when the LAPI rejects it.
+	token: Arc<Mutex<Option<String>>>,
+}
+
+impl CrowdSec {
+	pub fn new(config: &CrowdSecConfig, client: Client) -> Option<CrowdSec> {
+		if !config.enabled {
+			return None;
+		}
+		Some(CrowdSec {
+			config: config.clone(),
+			client,
+			token: Arc::default(),
+		})
+	}
+
+	// Build the alert for a finished session, if it warrants one.
+	pub fn alert(&self, service: &str, actor: SocketAddr, started_at: OffsetDateTime, offense: Offense) -> Option<Value> {
+		let (scenario, message, count) = match offense {
+			Offense::LoginAttempts(count) => (format!("rustbucket/{}-bf", service), format!("{} login attempts on honeypot {} service", count, service), count),
+			Offense::Probe(count) if self.config.alert_on_probe => (format!("rustbucket/{}-probe", service), format!("{} payloads sent to honeypot {} service", count, service), count),
+			Offense::Probe(_) => return None,
+		};
+
+		let ip = actor.ip().to_string();
+		let start_at = started_at.format(&Rfc3339).ok()?;
+		let stop_at = OffsetDateTime::now_utc().format(&Rfc3339).ok()?;
+		Some(json!({
+			"scenario": scenario,
+			"scenario_hash": "",
+			"scenario_version": "",
+			"message": message,
+			"events_count": count,
+			"start_at": start_at,
+			"stop_at": stop_at,
+			"capacity": 0,
+			"leakspeed": "0",
+			"simulated": false,
+			"remediation": true,
+			"events": [{
+				"timestamp": stop_at,
+				"meta": [
+					{ "key": "service", "value": service },
+					{ "key": "source_ip", "value": ip },
+					{ "key": "source_port", "value": actor.port().to_string() },
+				],
+			}],
+			"source": { "scope": "Ip", "value": ip, "ip": ip },
+			"decisions": [{
+				"origin": "rustbucket",
+				"type": "ban",
+				"scope": "Ip",
+				"value": ip,
+				"duration": self.config.ban_duration,
+				"scenario": scenario,
+			}],
+		}))
+	}
+
+	pub async fn push(&self, alert: Value) -> Result<(), Box<dyn Error>> {
+		let alerts = json!([alert]);
+		let mut token = self.token.lock().await;
+		if token.is_none() {
+			*token = Some(self.login().await?);
+		}
+		let mut response = self.post_alerts(token.as_deref().unwrap_or_default(), &alerts).await?;
+		if response.status() == StatusCode::UNAUTHORIZED {
+			*token = Some(self.login().await?);
+			response = self.post_alerts(token.as_deref().unwrap_or_default(), &alerts).await?;
+		}
+		response.error_for_status()?;
+		Ok(())
+	}
+
+	async fn post_alerts(&self, token: &str, alerts: &Value) -> reqwest::Result<reqwest::Response> {
+		self.client
+			.post(format!("{}/v1/alerts", self.config.lapi_url.trim_end_matches('/')))
+			.bearer_auth(token)
+			.json(alerts)
+			.send()
+			.await
+	}
+
+	async fn login(&self) -> Result<String, Box<dyn Error>> {
+		let response = self.client
+			.post(format!("{}/v1/watchers/login", self.config.lapi_url.trim_end_matches('/')))
+			.json(&json!({
+				"machine_id": self.config.machine_id,
+				"password": self.config.password,
+			}))
+			.send()
+			.await?
+			.error_for_status()?;
+		Ok(response.json::<LoginResponse>().await?.token)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::mock_http::{MockHttpServer, MockResponse};
+
+	fn crowdsec(lapi_url: &str) -> CrowdSec {
+		let config = CrowdSecConfig {
+			enabled: true,
+			lapi_url: lapi_url.to_string(),
+			machine_id: "rustbucket".to_string(),
+			password: "secret".to_string(),
+			..CrowdSecConfig::default()
+		};
+		CrowdSec::new(&config, Client::new()).unwrap()
+	}
+
+	fn ok(body: Value) -> MockResponse {
+		MockResponse::new(200, &body.to_string())
+	}
+
+	#[test]
+	fn alerts_carry_a_ban_decision_for_the_source() {
+		let crowdsec = crowdsec("http://127.0.0.1:1");
+		let actor: SocketAddr = "198.51.100.4:40000".parse().unwrap();
+		let now = OffsetDateTime::now_utc();
+
+		let alert = crowdsec.alert("ftp", actor, now, Offense::LoginAttempts(2)).unwrap();
+		assert_eq!(alert["scenario"], "rustbucket/ftp-bf");
+		assert_eq!(alert["events_count"], 2);
+		assert_eq!(alert["decisions"][0]["value"], "198.51.100.4");
+		assert_eq!(alert["decisions"][0]["duration"], "4h");
+
+		let alert = crowdsec.alert("http", actor, now, Offense::Probe(1)).unwrap();
+		assert_eq!(alert["scenario"], "rustbucket/http-probe");
+
+		let quiet = CrowdSec { config: CrowdSecConfig { alert_on_probe: false, ..crowdsec.config.clone() }, ..crowdsec };
+		assert!(quiet.alert("http", actor, now, Offense::Probe(1)).is_none());
+	}
+
+	#[tokio::test]
+	async fn alerts_are_pushed_with_a_fresh_token_after_expiry() {
+		let lapi = MockHttpServer::start().await;
+		lapi.respond_with(ok(json!({ "code": 200, "expire": "", "token": "first" })));
+		lapi.respond_with(ok(json!(["1"])));
+		lapi.respond_with(MockResponse::error(401, "token expired"));
+		lapi.respond_with(ok(json!({ "code": 200, "expire": "", "token": "second" })));
+		lapi.respond_with(ok(json!(["2"])));
+
+		let crowdsec = crowdsec(&lapi.base_url());
+		crowdsec.push(json!({ "scenario": "one" })).await.unwrap();
+		crowdsec.push(json!({ "scenario": "two" })).await.unwrap();
+
+		let requests = lapi.requests();
+		let paths: Vec<&str> = requests.iter().map(|r| r.path.as_str()).collect();
+		assert_eq!(paths, ["/v1/watchers/login", "/v1/alerts", "/v1/alerts", "/v1/watchers/login", "/v1/alerts"]);
+		assert_eq!(requests[0].body["machine_id"], "rustbucket");
+		assert_eq!(requests[1].headers["authorization"], "Bearer first");
+		assert_eq!(requests[4].headers["authorization"], "Bearer second");
+		assert_eq!(requests[4].body[0]["scenario"], "two");
+	}
+}
@@ -0,0 +1,82 @@
+// Just enough DER for the ASN.1 rustbucket reads and writes itself: Kerberos
+// messages and the validity of ACME certificates. Only definite lengths of up
+// to four octets are accepted, which covers anything an actor can send within
+// the session limits.
+
+// Split one element off `input` as (tag, contents, rest), or None if it is
+// truncated or its length can't be read.
+pub fn element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+	let tag = *input.first()?;
+	let first = *input.get(1)? as usize;
+	let (len, header) = if first < 0x80 {
+		(first, 2)
+	} else {
+		let octets = first & 0x7f;
+		if octets == 0 || octets > 4 {
+			return None;
+		}
+		let len = input.get(2..2 + octets)?.iter().fold(0, |len, b| len << 8 | *b as usize);
+		(len, 2 + octets)
+	};
+	let end = header.checked_add(len)?;
+	Some((tag, input.get(header..end)?, &input[end..]))
+}
+
+// The contents of a SEQUENCE.
+pub fn sequence(input: &[u8]) -> Option<&[u8]> {
+	match element(input)? {
+		(0x30, contents, _) => Some(contents),
+		_ => None,
+	}
+}
+
+// Every element of a SEQUENCE's contents, tag and all.
+pub fn elements(mut contents: &[u8]) -> Option<Vec<&[u8]>> {
+	let mut elements = vec![];
+	while !contents.is_empty() {
+		let (_, _, rest) = element(contents)?;
+		elements.push(&contents[..contents.len() - rest.len()]);
+		contents = rest;
+	}
+	Some(elements)
+}
+
+// One element, with the shortest length encoding.
+pub fn encode(tag: u8, contents: &[u8]) -> Vec<u8> {
+	let mut out = vec![tag];
+	let len = contents.len();
+	if len < 0x80 {
+		out.push(len as u8);
+	} else {
+		let bytes: Vec<u8> = len.to_be_bytes().into_iter().skip_while(|b| *b == 0).collect();
+		out.push(0x80 | bytes.len() as u8);
+		out.extend(bytes);
+	}
+	out.extend_from_slice(contents);
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn elements_round_trip_and_bad_lengths_are_refused() {
+		let long = vec![0x41; 300];
+		let mut encoded = encode(0x04, &long);
+		assert_eq!(&encoded[..4], [0x04, 0x82, 0x01, 0x2c]);
+		encoded.push(0xff);
+		assert_eq!(element(&encoded), Some((0x04, long.as_slice(), &[0xff][..])));
+
+		let seq = encode(0x30, &[encode(0x02, &[1]), encode(0x1b, b"CORP")].concat());
+		assert_eq!(elements(sequence(&seq).unwrap()).unwrap(), [&[0x02, 1, 1][..], &[0x1b, 4, b'C', b'O', b'R', b'P'][..]]);
+
+		// Truncated contents, truncated length, indefinite and oversized lengths
+		assert!(element(&[0x04, 0x05, 1, 2]).is_none());
+		assert!(element(&[0x04, 0x82, 0x01]).is_none());
+		assert!(element(&[0x30, 0x80, 0, 0]).is_none());
+		assert!(element(&[0x04, 0x85, 0, 0, 0, 0, 1, 0]).is_none());
+		assert!(element(&[0x04]).is_none());
+		assert!(sequence(&encode(0x31, &[])).is_none());
+	}
+}
@@ -0,0 +1,43 @@
+use serde::{Deserialize, Serialize};
+
+// Where a response sent to the actor came from.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseSource {
+	Llm,
+	Static,
+	Plugin,
+	Script,
+	// A canned answer to a honeypot-detection probe; see countermeasures.
+	Countermeasure,
+	// Part of the decoy git repository; see git.
+	Decoy,
+}
+
+// Structured record of what happened during a session. These are logged as
+// JSON so they can be parsed, and are what recorded-session fixtures assert on.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SessionEvent {
+	BannerSent { banner: String },
+	Received { data: String },
+	Responded { data: String, source: ResponseSource },
+	// The session hit its memory ceiling and was cut off.
+	MemoryLimitReached { retained_bytes: usize },
+	// An operator script labelled the session, e.g. "dropper".
+	Tagged { tag: String },
+	// A file the actor downloaded was fetched and kept; see payload_fetch.
+	PayloadFetched { url: String, sha256: String, bytes: usize },
+	Closed,
+}
+
+impl SessionEvent {
+	// Bytes of actor/response payload this event keeps alive.
+	pub fn payload_len(&self) -> usize {
+		match self {
+			SessionEvent::BannerSent { banner } => banner.len(),
+			SessionEvent::Received { data } | SessionEvent::Responded { data, .. } => data.len(),
+			SessionEvent::MemoryLimitReached { .. } | SessionEvent::Tagged { .. } | SessionEvent::PayloadFetched { .. } | SessionEvent::Closed => 0,
+		}
+	}
+}
@@ -0,0 +1,338 @@
+// Checks how the honeypot looks from the outside. Every interval the
+// instance's public IP is looked up on Shodan and Censys, and what they have
+// indexed is logged and reported in the metrics: which services and banners
+// they picked up, which of our listeners they have missed, and whether either
+// has tagged the host as a honeypot. A honeypot flag means scanners that
+// consult them will steer clear, and the deception needs work.
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use std::error::Error;
+use std::time::Duration;
+use tokio::time::interval;
+use crate::prelude::*;
+use crate::telemetry;
+
+// Longest banner excerpt written to the log.
+const BANNER_EXCERPT: usize = 80;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ExposureConfig {
+	#[serde(default)]
+	pub enabled: bool,
+	#[serde(default = "ExposureConfig::default_interval_secs")]
+	pub interval_secs: u64,
+	// Looked up from ip_lookup_url when empty.
+	#[serde(default)]
+	pub public_ip: String,
+	#[serde(default = "ExposureConfig::default_ip_lookup_url")]
+	pub ip_lookup_url: String,
+	// Either service is skipped without its credentials.
+	#[serde(default)]
+	pub shodan_api_key: String,
+	#[serde(default)]
+	pub censys_api_id: String,
+	#[serde(default)]
+	pub censys_api_secret: String,
+	#[serde(default = "ExposureConfig::default_shodan_url")]
+	pub shodan_url: String,
+	#[serde(default = "ExposureConfig::default_censys_url")]
+	pub censys_url: String,
+}
+
+impl ExposureConfig {
+	fn default_interval_secs() -> u64 {
+		24 * 60 * 60
+	}
+
+	fn default_ip_lookup_url() -> String {
+		"https://api.ipify.org".to_string()
+	}
+
+	fn default_shodan_url() -> String {
+		"https://api.shodan.io".to_string()
+	}
+
+	fn default_censys_url() -> String {
+		"https://search.censys.io/api".to_string()
+	}
+}
+
+impl Default for ExposureConfig {
+	fn default() -> Self {
+		ExposureConfig {
+			enabled: false,
+			interval_secs: Self::default_interval_secs(),
+			public_ip: String::new(),
+			ip_lookup_url: Self::default_ip_lookup_url(),
+			shodan_api_key: String::new(),
+			censys_api_id: String::new(),
+			censys_api_secret: String::new(),
+			shodan_url: Self::default_shodan_url(),
+			censys_url: Self::default_censys_url(),
+		}
+	}
+}
+
+// One service a search engine has on record for us.
+#[derive(Debug, PartialEq)]
+pub struct IndexedService {
+	pub port: u16,
+	pub service: String,
+	pub banner: String,
+}
+
+// What one search engine knows about the host.
+#[derive(Debug, Default, PartialEq)]
+pub struct Indexed {
+	pub services: Vec<IndexedService>,
+	pub tags: Vec<String>,
+	// Shodan's 0.0 (real system) to 1.0 (honeypot) estimate.
+	pub honeyscore: Option<f64>,
+}
+
+impl Indexed {
+	pub fn flagged_as_honeypot(&self) -> bool {
+		self.tags.iter().any(|tag| tag.to_ascii_lowercase().contains("honeypot")) || self.honeyscore.is_some_and(|score| score >= 0.5)
+	}
+}
+
+#[derive(Debug, Deserialize)]
+struct ShodanHost {
+	#[serde(default)]
+	tags: Vec<String>,
+	#[serde(default)]
+	data: Vec<ShodanService>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ShodanService {
+	port: u16,
+	#[serde(default)]
+	product: Option<String>,
+	#[serde(default)]
+	data: String,
+	#[serde(default, rename = "_shodan")]
+	meta: ShodanMeta,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ShodanMeta {
+	#[serde(default)]
+	module: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CensysResponse {
+	result: CensysHost,
+}
+
+#[derive(Debug, Deserialize)]
+struct CensysHost {
+	#[serde(default)]
+	labels: Vec<String>,
+	#[serde(default)]
+	services: Vec<CensysService>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CensysService {
+	port: u16,
+	#[serde(default)]
+	service_name: String,
+	#[serde(default)]
+	banner: String,
+}
+
+pub struct Exposure {
+	config: ExposureConfig,
+	client: Client,
+	// Every port a listener is on, to spot the ones nobody has indexed.
+	listening: Vec<u16>,
+}
+
+impl Exposure {
+	// Start checking in the background, if enabled.
+	pub fn start(config: &ExposureConfig, client: Client, mut listening: Vec<u16>) {
+		if !config.enabled {
+			return;
+		}
+		if config.shodan_api_key.is_empty() && config.censys_api_id.is_empty() {
+			error!("[exposure] is enabled without Shodan or Censys credentials, not checking");
+			return;
+		}
+		listening.sort_unstable();
+		listening.dedup();
+		let exposure = Exposure { config: config.clone(), client, listening };
+		task::spawn(exposure.run());
+	}
+
+	async fn run(self) {
+		let mut checks = interval(Duration::from_secs(self.config.interval_secs.max(60)));
+		loop {
+			checks.tick().await;
+			let ip = match self.public_ip().await {
+				Ok(ip) => ip,
+				Err(e) => {
+					warn!("Exposure check skipped, failed to look up the public IP: {}", e);
+					continue;
+				}
+			};
+			if !self.config.shodan_api_key.is_empty() {
+				let indexed = self.shodan(&ip).await;
+				self.report("shodan", &ip, indexed);
+			}
+			if !self.config.censys_api_id.is_empty() {
+				let indexed = self.censys(&ip).await;
+				self.report("censys", &ip, indexed);
+			}
+		}
+	}
+
+	async fn public_ip(&self) -> Result<String, Box<dyn Error + Send + Sync>> {
+		if !self.config.public_ip.is_empty() {
+			return Ok(self.config.public_ip.clone());
+		}
+		let ip = self.client.get(&self.config.ip_lookup_url).send().await?.error_for_status()?.text().await?;
+		Ok(ip.trim().to_string())
+	}
+
+	// None if Shodan has never indexed the host.
+	async fn shodan(&self, ip: &str) -> Result<Option<Indexed>, Box<dyn Error + Send + Sync>> {
+		let base = self.config.shodan_url.trim_end_matches('/');
+		let key = [("key", self.config.shodan_api_key.as_str())];
+		let response = self.client.get(format!("{}/shodan/host/{}", base, ip)).query(&key).send().await?;
+		if response.status() == StatusCode::NOT_FOUND {
+			return Ok(None);
+		}
+		let host: ShodanHost = response.error_for_status()?.json().await?;
+		// The score is a nice-to-have; the host record is what matters
+		let honeyscore = match self.client.get(format!("{}/labs/honeyscore/{}", base, ip)).query(&key).send().await.and_then(|r| r.error_for_status()) {
+			Ok(response) => response.json::<f64>().await.ok(),
+			Err(e) => {
+				warn!("Failed to fetch the Shodan honeyscore: {}", e);
+				None
+			}
+		};
+		let services = host
+			.data
+			.into_iter()
+			.map(|service| IndexedService {
+				port: service.port,
+				service: service.product.unwrap_or(service.meta.module),
+				banner: service.data,
+			})
+			.collect();
+		Ok(Some(Indexed { services, tags: host.tags, honeyscore }))
+	}
+
+	async fn censys(&self, ip: &str) -> Result<Option<Indexed>, Box<dyn Error + Send + Sync>> {
+		let response = self
+			.client
+			.get(format!("{}/v2/hosts/{}", self.config.censys_url.trim_end_matches('/'), ip))
+			.basic_auth(&self.config.censys_api_id, Some(&self.config.censys_api_secret))
+			.send()
+			.await?;
+		if response.status() == StatusCode::NOT_FOUND {
+			return Ok(None);
+		}
+		let host = response.error_for_status()?.json::<CensysResponse>().await?.result;
+		let services = host
+			.services
+			.into_iter()
+			.map(|service| IndexedService { port: service.port, service: service.service_name, banner: service.banner })
+			.collect();
+		Ok(Some(Indexed { services, tags: host.labels, honeyscore: None }))
+	}
+
+	fn report(&self, source: &str, ip: &str, indexed: Result<Option<Indexed>, Box<dyn Error + Send + Sync>>) {
+		let indexed = match indexed {
+			Ok(Some(indexed)) => indexed,
+			Ok(None) => {
+				info!("{} has not indexed {} yet", source, ip);
+				telemetry::record_exposure(source, 0, false);
+				return;
+			}
+			Err(e) => {
+				warn!("Failed to look up {} on {}: {}", ip, source, e);
+				return;
+			}
+		};
+		for service in &indexed.services {
+			info!("{} has {} port {} as {}: {:?}", source, ip, service.port, service.service, excerpt(&service.banner));
+		}
+		let missing = self.missing(&indexed);
+		if !missing.is_empty() {
+			info!("{} has not indexed ports {:?} on {} yet", source, missing, ip);
+		}
+		if let Some(score) = indexed.honeyscore {
+			info!("{} honeyscore for {} is {:.1}", source, ip, score);
+		}
+		if indexed.flagged_as_honeypot() {
+			warn!("{} flags {} as a honeypot (tags {:?}), scanners that check it will stay away", source, ip, indexed.tags);
+		}
+		telemetry::record_exposure(source, indexed.services.len(), indexed.flagged_as_honeypot());
+	}
+
+	// Listening ports the search engine has no record of.
+	fn missing(&self, indexed: &Indexed) -> Vec<u16> {
+		self.listening.iter().copied().filter(|port| !indexed.services.iter().any(|s| s.port == *port)).collect()
+	}
+}
+
+// The first line of a banner, cut short for the log.
+fn excerpt(banner: &str) -> String {
+	banner.lines().next().unwrap_or_default().chars().take(BANNER_EXCERPT).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::mock_http::{MockHttpServer, MockResponse};
+
+	#[tokio::test]
+	async fn shodan_and_censys_records_are_read_and_honeypot_tags_noticed() {
+		let server = MockHttpServer::start().await;
+		let config = ExposureConfig {
+			enabled: true,
+			public_ip: "203.0.113.7".to_string(),
+			shodan_api_key: "key".to_string(),
+			censys_api_id: "id".to_string(),
+			censys_api_secret: "secret".to_string(),
+			shodan_url: server.base_url(),
+			censys_url: server.base_url(),
+			..ExposureConfig::default()
+		};
+		let exposure = Exposure { config, client: Client::new(), listening: vec![21, 22, 80] };
+
+		let shodan_host = serde_json::json!({
+			"ip_str": "203.0.113.7",
+			"tags": ["honeypot"],
+			"data": [{ "port": 22, "data": "SSH-2.0-OpenSSH_8.2p1 Ubuntu-4ubuntu0.5\r\n", "_shodan": { "module": "ssh" } }],
+		});
+		server.respond_with(MockResponse::new(200, &shodan_host.to_string()));
+		server.respond_with(MockResponse::new(200, "0.8"));
+		let shodan = exposure.shodan("203.0.113.7").await.unwrap().unwrap();
+		assert_eq!(shodan.services, vec![IndexedService { port: 22, service: "ssh".to_string(), banner: "SSH-2.0-OpenSSH_8.2p1 Ubuntu-4ubuntu0.5\r\n".to_string() }]);
+		assert_eq!(shodan.honeyscore, Some(0.8));
+		assert!(shodan.flagged_as_honeypot());
+		assert_eq!(exposure.missing(&shodan), vec![21, 80]);
+		assert_eq!(excerpt(&shodan.services[0].banner), "SSH-2.0-OpenSSH_8.2p1 Ubuntu-4ubuntu0.5");
+
+		let censys_host = serde_json::json!({
+			"result": { "labels": ["remote-access"], "services": [{ "port": 80, "service_name": "HTTP", "banner": "HTTP/1.1 200 OK" }] },
+		});
+		server.respond_with(MockResponse::new(200, &censys_host.to_string()));
+		let censys = exposure.censys("203.0.113.7").await.unwrap().unwrap();
+		assert_eq!(censys.services[0].service, "HTTP");
+		assert!(!censys.flagged_as_honeypot());
+
+		// Never indexed
+		server.respond_with(MockResponse::new(404, "{}"));
+		assert!(exposure.censys("203.0.113.7").await.unwrap().is_none());
+
+		let requests = server.requests();
+		assert_eq!(requests[0].path, "/shodan/host/203.0.113.7?key=key");
+		assert_eq!(requests[1].path, "/labs/honeyscore/203.0.113.7?key=key");
+		assert_eq!(requests[2].path, "/v2/hosts/203.0.113.7");
+	}
+}
@@ -0,0 +1,173 @@
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use crate::config::{AppConfig, Persona};
+use crate::prelude::*;
+
+// Catalogs of real-world software strings we can pretend to be. Every
+// rustbucket advertising "vsFTPd 3.0.3" makes the fleet trivial to spot, so
+// each instance picks its own combination once and sticks with it.
+const HOST_PREFIXES: &[&str] = &["mail", "mx1", "srv01", "web", "files", "backup", "node3"];
+const DOMAINS: &[&str] = &["acme-corp.com", "northwind.net", "globex.io", "initech.local", "contoso.org"];
+
+const FTP_BANNERS: &[&str] = &[
+	"220 (vsFTPd 2.3.4)",
+	"220 (vsFTPd 3.0.2)",
+	"220 (vsFTPd 3.0.3)",
+	"220 (vsFTPd 3.0.5)",
+	"220 ProFTPD 1.3.5 Server ({hostname})",
+	"220 ProFTPD 1.3.5e Server ({hostname})",
+	"220 ProFTPD 1.3.6 Server ({hostname})",
+	"220---------- Welcome to Pure-FTPd [privsep] [TLS] ----------",
+];
+
+const SMTP_BANNERS: &[&str] = &[
+	"220 {hostname} ESMTP Postfix (Ubuntu)",
+	"220 {hostname} ESMTP Postfix (Debian/GNU)",
+	"220 {hostname} ESMTP Exim 4.92 Ubuntu",
+	"220 {hostname} ESMTP Exim 4.94.2",
+	"220 {hostname} ESMTP Exim 4.96",
+	"220 {hostname} ESMTP Sendmail 8.15.2/8.15.2",
+];
+
+// Server headers for the web services' static responses.
+const HTTP_SERVERS: &[&str] = &[
+	"Apache/2.4.6 (CentOS)",
+	"Apache/2.4.41 (Ubuntu)",
+	"Apache/2.4.52 (Ubuntu)",
+	"Apache/2.4.57 (Debian)",
+	"nginx/1.18.0 (Ubuntu)",
+	"nginx/1.22.1",
+	"nginx/1.24.0",
+	"Microsoft-IIS/10.0",
+];
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct FingerprintConfig {
+	// Pick random banners and a hostname for this instance at first boot.
+	#[serde(default)]
+	pub randomize: bool,
+	// Where the chosen fingerprint is kept so it survives restarts.
+	#[serde(default = "FingerprintConfig::default_state_file")]
+	pub state_file: String,
+}
+
+impl FingerprintConfig {
+	fn default_state_file() -> String {
+		"./fingerprint.toml".to_string()
+	}
+}
+
+impl Default for FingerprintConfig {
+	fn default() -> Self {
+		FingerprintConfig {
+			randomize: false,
+			state_file: Self::default_state_file(),
+		}
+	}
+}
+
+// The identity details one instance presents to the world.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Fingerprint {
+	// For personas without a hostname of their own, and so their certificates.
+	pub hostname: String,
+	pub ftp_banner: String,
+	pub smtp_banner: String,
+	// Missing from fingerprints saved by older versions.
+	#[serde(default)]
+	pub http_server: String,
+}
+
+impl Fingerprint {
+	// Load the fingerprint chosen on a previous boot, or roll a new one and persist it.
+	pub fn load_or_generate(state_file: &str) -> Result<Fingerprint, Box<dyn Error>> {
+		if Path::new(state_file).exists() {
+			let mut fingerprint: Fingerprint = toml::from_str(&fs::read_to_string(state_file)?)?;
+			if fingerprint.http_server.is_empty() {
+				fingerprint.http_server = HTTP_SERVERS.choose(&mut rand::thread_rng()).unwrap().to_string();
+				fs::write(state_file, toml::to_string(&fingerprint)?)?;
+			}
+			return Ok(fingerprint);
+		}
+
+		let fingerprint = Self::generate(&mut rand::thread_rng());
+		fs::write(state_file, toml::to_string(&fingerprint)?)?;
+		info!("Generated new instance fingerprint: {:?}", fingerprint);
+		Ok(fingerprint)
+	}
+
+	fn generate<R: Rng>(rng: &mut R) -> Fingerprint {
+		let hostname = format!(
+			"{}.{}",
+			HOST_PREFIXES.choose(rng).unwrap(),
+			DOMAINS.choose(rng).unwrap()
+		);
+		// {hostname} is left for each persona to fill in with its own
+		let pick = |catalog: &[&str], rng: &mut R| catalog.choose(rng).unwrap().to_string();
+
+		Fingerprint {
+			ftp_banner: pick(FTP_BANNERS, rng),
+			smtp_banner: pick(SMTP_BANNERS, rng),
+			http_server: pick(HTTP_SERVERS, rng),
+			hostname,
+		}
+	}
+
+	// Override the configured banners, Server headers and missing hostnames
+	// with this instance's randomized ones.
+	pub fn apply(&self, app_config: &mut AppConfig) {
+		let ports = &mut app_config.ports;
+		ports.ftp.banner = Some(self.ftp_banner.clone());
+		ports.smtp.banner = Some(self.smtp_banner.clone());
+		for web in [&mut ports.http, &mut ports.https] {
+			web.static_response = web.static_response.as_deref().map(|response| self.with_server_header(response));
+		}
+		if app_config.personas.is_empty() {
+			app_config.personas.push(Persona::default_persona());
+		}
+		for persona in &mut app_config.personas {
+			persona.hostname.get_or_insert_with(|| self.hostname.clone());
+		}
+	}
+
+	fn with_server_header(&self, response: &str) -> String {
+		let lines: Vec<String> = response
+			.split("\r\n")
+			.map(|line| match line.split_once(':') {
+				Some((name, _)) if name.eq_ignore_ascii_case("server") => format!("{}: {}", name, self.http_server),
+				_ => line.to_string(),
+			})
+			.collect();
+		lines.join("\r\n")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rand::rngs::StdRng;
+	use rand::SeedableRng;
+
+	#[test]
+	fn every_service_takes_the_instance_fingerprint() {
+		let fingerprint = Fingerprint::generate(&mut StdRng::seed_from_u64(1));
+		assert_eq!(fingerprint, Fingerprint::generate(&mut StdRng::seed_from_u64(1)));
+		assert!(HTTP_SERVERS.contains(&fingerprint.http_server.as_str()));
+
+		let mut app_config = AppConfig::from_config("Config.toml").unwrap();
+		app_config.ports.https.static_response = Some("HTTP/1.1 404 Not Found\r\nServer: Apache/2.4.41 (Ubuntu)\r\nContent-Length: 0\r\n".to_string());
+		fingerprint.apply(&mut app_config);
+		assert_eq!(app_config.ports.ftp.banner.as_deref(), Some(fingerprint.ftp_banner.as_str()));
+		assert_eq!(
+			app_config.ports.https.static_response.as_deref().unwrap(),
+			format!("HTTP/1.1 404 Not Found\r\nServer: {}\r\nContent-Length: 0\r\n", fingerprint.http_server)
+		);
+		let persona = &app_config.personas()[0];
+		assert_eq!(persona.hostname.as_deref(), Some(fingerprint.hostname.as_str()));
+		assert!(!persona.customize(app_config.ports.smtp.clone()).banner.unwrap().contains("{hostname}"));
+	}
+}
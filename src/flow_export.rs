@@ -0,0 +1,269 @@
+// Export every session as IPFIX (NetFlow v10) flow records to a collector,
+// for teams whose analytics are flow based. A session becomes two records,
+// actor -> honeypot and honeypot -> actor, tagged with the service name.
+// Templates go out with every message so a restarted collector picks them up.
+use serde::Deserialize;
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use time::OffsetDateTime;
+use crate::sinks::SessionSummary;
+
+const IPFIX_VERSION: u16 = 10;
+const TEMPLATE_SET_ID: u16 = 2;
+const IPV4_TEMPLATE_ID: u16 = 256;
+const IPV6_TEMPLATE_ID: u16 = 257;
+const PROTOCOL_TCP: u8 = 6;
+const VARIABLE_LENGTH: u16 = 0xffff;
+
+// (IANA information element id, length) for each record field, in order.
+const fn template(source: u16, destination: u16, address_len: u16) -> [(u16, u16); 9] {
+	[
+		(source, address_len),
+		(destination, address_len),
+		(7, 2),   // sourceTransportPort
+		(11, 2),  // destinationTransportPort
+		(4, 1),   // protocolIdentifier
+		(1, 8),   // octetDeltaCount
+		(152, 8), // flowStartMilliseconds
+		(153, 8), // flowEndMilliseconds
+		(96, VARIABLE_LENGTH), // applicationName
+	]
+}
+const IPV4_TEMPLATE: [(u16, u16); 9] = template(8, 12, 4);
+const IPV6_TEMPLATE: [(u16, u16); 9] = template(27, 28, 16);
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct FlowExportConfig {
+	#[serde(default)]
+	pub enabled: bool,
+	// IPFIX collector, e.g. nfcapd or pmacct listening on UDP 4739.
+	#[serde(default = "FlowExportConfig::default_collector")]
+	pub collector: String,
+	#[serde(default)]
+	pub observation_domain_id: u32,
+}
+
+impl FlowExportConfig {
+	fn default_collector() -> String {
+		"127.0.0.1:4739".to_string()
+	}
+}
+
+impl Default for FlowExportConfig {
+	fn default() -> Self {
+		FlowExportConfig {
+			enabled: false,
+			collector: Self::default_collector(),
+			observation_domain_id: 0,
+		}
+	}
+}
+
+// One direction of a session.
+#[derive(Debug, Clone, PartialEq)]
+struct FlowRecord {
+	source: SocketAddr,
+	destination: SocketAddr,
+	octets: u64,
+	start_ms: u64,
+	end_ms: u64,
+	application: String,
+}
+
+#[derive(Clone)]
+pub struct FlowExporter {
+	socket: Arc<UdpSocket>,
+	observation_domain_id: u32,
+	// Data records exported so far, as IPFIX sequence numbers count them.
+	sequence: Arc<AtomicU32>,
+}
+
+impl FlowExporter {
+	pub fn new(config: &FlowExportConfig) -> Result<Option<FlowExporter>, Box<dyn Error>> {
+		if !config.enabled {
+			return Ok(None);
+		}
+		let collector: SocketAddr = config.collector.parse()?;
+		let bind: SocketAddr = if collector.is_ipv4() { "0.0.0.0:0".parse()? } else { "[::]:0".parse()? };
+		let socket = UdpSocket::bind(bind)?;
+		socket.connect(collector)?;
+		Ok(Some(FlowExporter {
+			socket: Arc::new(socket),
+			observation_domain_id: config.observation_domain_id,
+			sequence: Arc::default(),
+		}))
+	}
+
+	pub fn export(&self, session: &SessionSummary) -> std::io::Result<()> {
+		let start_ms = unix_millis(session.started_at);
+		let end_ms = unix_millis(OffsetDateTime::now_utc());
+		let (actor, local) = same_family(session.actor, session.local);
+		let record = |source, destination, octets| FlowRecord {
+			source,
+			destination,
+			octets,
+			start_ms,
+			end_ms,
+			application: session.service.to_string(),
+		};
+		let records = [
+			record(actor, local, session.bytes_received()),
+			record(local, actor, session.bytes_sent()),
+		];
+		let sequence = self.sequence.fetch_add(records.len() as u32, Ordering::SeqCst);
+		let message = encode(&records, unix_millis(OffsetDateTime::now_utc()) / 1000, sequence, self.observation_domain_id);
+		self.socket.send(&message)?;
+		Ok(())
+	}
+}
+
+// A record uses one template, so both ends must share an address family.
+// Dual-stack listeners see IPv4 actors as IPv4-mapped IPv6; unwrap those, and
+// map IPv4 into IPv6 only when the other end is genuinely IPv6.
+fn same_family(a: SocketAddr, b: SocketAddr) -> (SocketAddr, SocketAddr) {
+	let canonical = |addr: SocketAddr| SocketAddr::new(addr.ip().to_canonical(), addr.port());
+	let (a, b) = (canonical(a), canonical(b));
+	let mapped = |addr: SocketAddr| match addr.ip() {
+		IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+		IpAddr::V6(_) => addr,
+	};
+	if a.is_ipv4() == b.is_ipv4() { (a, b) } else { (mapped(a), mapped(b)) }
+}
+
+fn unix_millis(at: OffsetDateTime) -> u64 {
+	(at.unix_timestamp_nanos() / 1_000_000).max(0) as u64
+}
+
+// Build one IPFIX message: the templates followed by a data set per address family.
+fn encode(records: &[FlowRecord], export_secs: u64, sequence: u32, observation_domain_id: u32) -> Vec<u8> {
+	let mut sets = vec![];
+
+	let mut templates = vec![];
+	for (id, fields) in [(IPV4_TEMPLATE_ID, &IPV4_TEMPLATE), (IPV6_TEMPLATE_ID, &IPV6_TEMPLATE)] {
+		templates.extend_from_slice(&id.to_be_bytes());
+		templates.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+		for (element, length) in fields {
+			templates.extend_from_slice(&element.to_be_bytes());
+			templates.extend_from_slice(&length.to_be_bytes());
+		}
+	}
+	push_set(&mut sets, TEMPLATE_SET_ID, &templates);
+
+	for (template_id, v4) in [(IPV4_TEMPLATE_ID, true), (IPV6_TEMPLATE_ID, false)] {
+		let mut data = vec![];
+		for record in records.iter().filter(|r| r.source.is_ipv4() == v4) {
+			push_address(&mut data, record.source.ip());
+			push_address(&mut data, record.destination.ip());
+			data.extend_from_slice(&record.source.port().to_be_bytes());
+			data.extend_from_slice(&record.destination.port().to_be_bytes());
+			data.push(PROTOCOL_TCP);
+			data.extend_from_slice(&record.octets.to_be_bytes());
+			data.extend_from_slice(&record.start_ms.to_be_bytes());
+			data.extend_from_slice(&record.end_ms.to_be_bytes());
+			let application = &record.application.as_bytes()[..record.application.len().min(254)];
+			data.push(application.len() as u8);
+			data.extend_from_slice(application);
+		}
+		if !data.is_empty() {
+			push_set(&mut sets, template_id, &data);
+		}
+	}
+
+	let mut message = Vec::with_capacity(16 + sets.len());
+	message.extend_from_slice(&IPFIX_VERSION.to_be_bytes());
+	message.extend_from_slice(&((16 + sets.len()) as u16).to_be_bytes());
+	message.extend_from_slice(&(export_secs as u32).to_be_bytes());
+	message.extend_from_slice(&sequence.to_be_bytes());
+	message.extend_from_slice(&observation_domain_id.to_be_bytes());
+	message.extend_from_slice(&sets);
+	message
+}
+
+fn push_set(out: &mut Vec<u8>, set_id: u16, body: &[u8]) {
+	out.extend_from_slice(&set_id.to_be_bytes());
+	out.extend_from_slice(&((4 + body.len()) as u16).to_be_bytes());
+	out.extend_from_slice(body);
+}
+
+fn push_address(out: &mut Vec<u8>, ip: IpAddr) {
+	match ip {
+		IpAddr::V4(ip) => out.extend_from_slice(&ip.octets()),
+		IpAddr::V6(ip) => out.extend_from_slice(&ip.octets()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn u16_at(bytes: &[u8], at: usize) -> u16 {
+		u16::from_be_bytes([bytes[at], bytes[at + 1]])
+	}
+
+	fn u64_at(bytes: &[u8], at: usize) -> u64 {
+		u64::from_be_bytes(bytes[at..at + 8].try_into().unwrap())
+	}
+
+	#[test]
+	fn encodes_templates_and_records_per_address_family() {
+		let records = [
+			FlowRecord {
+				source: "203.0.113.5:51000".parse().unwrap(),
+				destination: "192.0.2.10:21".parse().unwrap(),
+				octets: 42,
+				start_ms: 1_000,
+				end_ms: 2_500,
+				application: "ftp".to_string(),
+			},
+			FlowRecord {
+				source: "[2001:db8::5]:51000".parse().unwrap(),
+				destination: "[2001:db8::10]:80".parse().unwrap(),
+				octets: 7,
+				start_ms: 1_000,
+				end_ms: 2_500,
+				application: "http".to_string(),
+			},
+		];
+		let message = encode(&records, 1_700_000_000, 9, 3);
+
+		// Message header
+		assert_eq!(u16_at(&message, 0), 10);
+		assert_eq!(u16_at(&message, 2) as usize, message.len());
+		assert_eq!(&message[8..12], &9u32.to_be_bytes());
+		assert_eq!(&message[12..16], &3u32.to_be_bytes());
+
+		// Template set with both templates: 4 + 2 * (4 + 9 * 4) bytes
+		assert_eq!(u16_at(&message, 16), TEMPLATE_SET_ID);
+		let template_len = u16_at(&message, 18) as usize;
+		assert_eq!(template_len, 84);
+
+		// IPv4 data set: addresses, ports, protocol, octets, times, name
+		let v4 = 16 + template_len;
+		assert_eq!(u16_at(&message, v4), IPV4_TEMPLATE_ID);
+		let record = &message[v4 + 4..];
+		assert_eq!(&record[0..4], &[203, 0, 113, 5]);
+		assert_eq!(&record[4..8], &[192, 0, 2, 10]);
+		assert_eq!(u16_at(record, 10), 21);
+		assert_eq!(record[12], PROTOCOL_TCP);
+		assert_eq!(u64_at(record, 13), 42);
+		assert_eq!(u64_at(record, 29), 2_500);
+		assert_eq!(&record[37..41], b"\x03ftp");
+
+		// IPv6 data set follows
+		let v6 = v4 + u16_at(&message, v4 + 2) as usize;
+		assert_eq!(u16_at(&message, v6), IPV6_TEMPLATE_ID);
+		assert_eq!(v6 + u16_at(&message, v6 + 2) as usize, message.len());
+	}
+
+	#[test]
+	fn both_ends_of_a_record_share_an_address_family() {
+		let mapped_actor: SocketAddr = "[::ffff:203.0.113.5]:51000".parse().unwrap();
+		let (actor, local) = same_family(mapped_actor, "192.0.2.10:21".parse().unwrap());
+		assert_eq!((actor.to_string().as_str(), local.to_string().as_str()), ("203.0.113.5:51000", "192.0.2.10:21"));
+
+		let (actor, local) = same_family("203.0.113.5:51000".parse().unwrap(), "[2001:db8::10]:21".parse().unwrap());
+		assert_eq!((actor.to_string().as_str(), local.to_string().as_str()), ("[::ffff:203.0.113.5]:51000", "[2001:db8::10]:21"));
+	}
+}
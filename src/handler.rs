@@ -1,31 +1,181 @@
 use crate::prelude::*;
-use config::Config;
-use serde::Deserialize;
-use crate::chatgpt::ChatGPT;
-
-#[derive(Debug, Deserialize)]
-struct PortConfig {
-	enabled: bool,
-	port: u16,
+use crate::chat_service::ChatService;
+use crate::config::{Interaction, LimitsConfig, PortConfig};
+use crate::countermeasures::{self, Consistency};
+use crate::events::{ResponseSource, SessionEvent};
+use crate::payload_fetch;
+use crate::scripting;
+use crate::telemetry;
+use rand::Rng;
+use std::borrow::Cow;
+use std::time::Instant;
+use tokio::time::sleep;
+
+// How many earlier exchanges are replayed to ChatGPT when session memory is on.
+// Keeps token usage bounded for actors that never disconnect.
+const MAX_HISTORY: usize = 10;
+
+// Everything a session has emitted so far, with the payload bytes it is
+// holding on to so the session can be cut off at its memory ceiling.
+pub struct SessionLog {
+	pub events: Vec<SessionEvent>,
+	pub retained_bytes: usize,
+	limits: LimitsConfig,
 }
 
-#[derive(Debug, Deserialize)]
-struct AppConfig {
-	ports: Ports,
+impl SessionLog {
+	pub fn new(limits: LimitsConfig) -> SessionLog {
+		SessionLog {
+			events: Vec::new(),
+			retained_bytes: 0,
+			limits,
+		}
+	}
+	
+	// Record a session event and log it as JSON, truncated to keep log lines bounded.
+	pub fn emit(&mut self, event: SessionEvent) {
+		match serde_json::to_string(&event) {
+			Ok(json) => info!("Session event: {}", truncate(&json, self.limits.max_logged_bytes)),
+			Err(e) => error!("Failed to serialize session event: {}", e),
+		}
+		self.retained_bytes += event.payload_len();
+		self.events.push(event);
+	}
+	
+	pub fn over_budget(&self) -> bool {
+		self.retained_bytes > self.limits.max_session_bytes
+	}
+}
+
+// Answers a session's payloads: depending on the interaction level, either
+// ChatGPT (with the session's history when memory is on) or the service's
+// canned response. Operator scripts get the first look at every payload,
+// then known honeypot-detection probes get the answer a real host would give.
+pub struct Responder<'a, C> {
+	chatgpt: &'a C,
+	interaction: Interaction,
+	protocol: String,
+	static_response: Option<String>,
+	history: Vec<(String, String)>,
+	consistency: Consistency,
+	// Tagged as a honeypot-aware scanner already.
+	probed: bool,
+	// The last reply was one a real server hangs up after.
+	hang_up: bool,
+}
+
+impl<'a, C: ChatService> Responder<'a, C> {
+	pub fn new(service: Option<&PortConfig>, interaction: Interaction, chatgpt: &'a C) -> Responder<'a, C> {
+		Responder {
+			chatgpt,
+			interaction,
+			protocol: service.map(|s| s.name.clone()).unwrap_or_else(|| "unknown".to_string()),
+			static_response: service.and_then(|s| s.static_response.clone()),
+			history: Vec::new(),
+			consistency: Consistency::default(),
+			probed: false,
+			hang_up: false,
+		}
+	}
+	
+	// None when the payload goes to the canned response and there is none.
+	pub async fn respond(&mut self, payload: &str, log: &mut SessionLog) -> Option<(String, ResponseSource)> {
+		let hook = scripting::on_payload(&self.protocol, payload);
+		for tag in hook.tags {
+			log.emit(SessionEvent::Tagged { tag });
+		}
+		if let Some(reply) = hook.reply {
+			return Some((reply, ResponseSource::Script));
+		}
+		if let Some(answer) = countermeasures::check(&self.protocol, payload) {
+			self.probed(answer.probe, log);
+			sleep(answer.delay).await;
+			self.hang_up = answer.hang_up;
+			return Some((answer.reply, ResponseSource::Countermeasure));
+		}
+		if self.interaction.fetches_payloads() {
+			for fetched in payload_fetch::fetch_all(payload).await {
+				log.emit(SessionEvent::PayloadFetched { url: fetched.url, sha256: fetched.sha256, bytes: fetched.bytes });
+			}
+		}
+		let payload = hook.prompt.as_deref().unwrap_or(payload);
+		if let Some((reply, probe)) = self.consistency.recall(payload) {
+			if probe {
+				self.probed("repeat", log);
+			}
+			return Some((reply, ResponseSource::Countermeasure));
+		}
+		let use_llm = rand::thread_rng().gen_bool(self.interaction.llm_sample_rate());
+		if !use_llm {
+			return self.static_response.as_ref().map(|r| (format!("{}\r\n", r), ResponseSource::Static));
+		}
+		let llm_started = Instant::now();
+		let response = self.chatgpt.send_message(&self.history, payload).await.unwrap_or_else(|_| "Error processing request".to_string());
+		telemetry::record_llm_request(&self.protocol, llm_started.elapsed());
+		self.consistency.remember(payload, &response);
+		if self.interaction.session_memory() {
+			self.history.push((payload.to_string(), response.clone()));
+			if self.history.len() > MAX_HISTORY {
+				self.history.remove(0);
+			}
+		}
+		Some((response, ResponseSource::Llm))
+	}
+
+	// Whether the session should end now that the last reply has been sent.
+	pub fn hang_up(&self) -> bool {
+		self.hang_up
+	}
+
+	// Tag the session the first time it probes, and record each probe it runs.
+	fn probed(&mut self, probe: &str, log: &mut SessionLog) {
+		info!("Honeypot detection probe ({}) on {}", probe, self.protocol);
+		if !self.probed {
+			self.probed = true;
+			log.emit(SessionEvent::Tagged { tag: countermeasures::TAG.to_string() });
+		}
+		log.emit(SessionEvent::Tagged { tag: format!("probe:{}", probe) });
+	}
 }
 
-#[derive(Debug, Deserialize)]
-struct Ports {
-	ssh: PortConfig,
-	http: PortConfig,
-	ftp: PortConfig,
-	sftp: PortConfig,
-	smtp: PortConfig,
-	dns: PortConfig,
-	sms: PortConfig,
+// Cut `s` down to at most `max` bytes without splitting a UTF-8 character.
+fn truncate(s: &str, max: usize) -> Cow<'_, str> {
+	if s.len() <= max {
+		return Cow::Borrowed(s);
+	}
+	let mut end = max;
+	while !s.is_char_boundary(end) {
+		end -= 1;
+	}
+	Cow::Owned(format!("{}...({} bytes truncated)", &s[..end], s.len() - end))
 }
 
-pub async fn handle_client(mut stream: tokio::net::TcpStream, message: String, chatgpt: &ChatGPT) {
+// Talk to the actor until they disconnect, returning everything that happened.
+// The stream is plain TCP or TLS, depending on the service.
+pub async fn handle_client<S, C>(mut stream: S, service: Option<PortConfig>, interaction: Interaction, limits: LimitsConfig, chatgpt: &C) -> Vec<SessionEvent>
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+	C: ChatService,
+{
+	let mut log = SessionLog::new(limits);
+	let started = Instant::now();
+	let mut first_byte_sent = false;
+	let protocol = service.as_ref().map(|s| s.name.clone()).unwrap_or_else(|| "unknown".to_string());
+	let banner = service.as_ref().and_then(|s| s.banner.clone());
+	let mut responder = Responder::new(service.as_ref(), interaction, chatgpt);
+
+	// Greet the actor the way the advertised service would before they send anything.
+	if let Some(banner) = banner {
+		if let Err(e) = stream.write_all(format!("{}\r\n", banner).as_bytes()).await {
+			info!("Failed to send banner: {}", e);
+			log.emit(SessionEvent::Closed);
+			return log.events;
+		}
+		log.emit(SessionEvent::BannerSent { banner });
+		telemetry::record_time_to_first_byte(&protocol, started.elapsed());
+		first_byte_sent = true;
+	}
+
 	let mut buffer = [0; 1024];
 	loop {
 		match stream.read(&mut buffer).await {
@@ -34,18 +184,37 @@ pub async fn handle_client(mut stream: tokio::net::TcpStream, message: String, c
 				break;
 			}
 			Ok(n) => {
-				// Pass user input to ChatGPT, parse the GPT response and send it back to the user
+				let received_at = Instant::now();
+				// Borrowed from the read buffer, so the event's copy is the only
+				// allocation unless invalid UTF-8 had to be repaired, which makes a
+				// second, short-lived one. Pooling reads is out of scope: the
+				// session ceiling is what bounds memory.
 				let received_data = String::from_utf8_lossy(&buffer[0..n]);
-				let response = chatgpt.send_message(&received_data).await.unwrap_or_else(|_| "Error processing request".to_string());
-				let response_message = format!("{}", response);
-				info!("Received data: {}", received_data);
-				info!("Response message: {}", response_message);
-				
+				log.emit(SessionEvent::Received { data: received_data.to_string() });
+				if log.over_budget() {
+					warn!("Session exceeded its memory ceiling of {} bytes, disconnecting", limits.max_session_bytes);
+					log.emit(SessionEvent::MemoryLimitReached { retained_bytes: log.retained_bytes });
+					break;
+				}
+
+				let Some((response_message, source)) = responder.respond(&received_data, &mut log).await else {
+					continue;
+				};
+				log.emit(SessionEvent::Responded { data: response_message.clone(), source });
+
 				if let Err(e) = stream.write_all(response_message.as_bytes()).await {
 					println!("Failed to send data: {}", e);
 					info!("Failed to write data.");
 					break;
 				}
+				telemetry::record_exchange(&protocol, received_at.elapsed());
+				if !first_byte_sent {
+					telemetry::record_time_to_first_byte(&protocol, started.elapsed());
+					first_byte_sent = true;
+				}
+				if responder.hang_up() {
+					break;
+				}
 			}
 			Err(e) => {
 				tracing::info!("Failed to read from stream: {}", e);
@@ -53,4 +222,41 @@ pub async fn handle_client(mut stream: tokio::net::TcpStream, message: String, c
 			}
 		}
 	}
-}
\ No newline at end of file
+
+	log.emit(SessionEvent::Closed);
+	log.events
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::chat_service::MockChatService;
+	use crate::config::InteractionLevel;
+	use tokio::net::TcpStream;
+
+	#[test]
+	fn truncate_respects_char_boundaries() {
+		assert_eq!(truncate("short", 10), "short");
+		assert_eq!(truncate("héllo", 2), "h...(5 bytes truncated)");
+	}
+
+	#[tokio::test]
+	async fn sessions_are_cut_off_at_their_memory_ceiling() {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		let limits = LimitsConfig { max_session_bytes: 100, max_logged_bytes: 4096 };
+		let server = task::spawn(async move {
+			let (stream, _) = listener.accept().await.unwrap();
+			handle_client(stream, None, InteractionLevel::Low.into(), limits, &MockChatService::new("unused")).await
+		});
+
+		let mut client = TcpStream::connect(addr).await.unwrap();
+		client.write_all(&[b'A'; 200]).await.unwrap();
+		let mut buffer = [0; 16];
+		assert_eq!(client.read(&mut buffer).await.unwrap(), 0);
+
+		let events = server.await.unwrap();
+		assert_eq!(events[1], SessionEvent::MemoryLimitReached { retained_bytes: 200 });
+		assert_eq!(events[2], SessionEvent::Closed);
+	}
+}
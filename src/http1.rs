@@ -0,0 +1,333 @@
+// HTTP/1.x for the web listeners. Requests are framed the way a real server
+// frames them, by Content-Length or chunked transfer coding, so one connection
+// can carry many requests, pipelined or one after another, each logged and
+// answered on its own. Responses are sent with a Content-Length matching their
+// body, so a client reusing the connection doesn't read the next one as garbage.
+use httparse::Status;
+use std::io;
+use std::time::Instant;
+use crate::chat_service::ChatService;
+use crate::config::{Interaction, LimitsConfig, PortConfig};
+use crate::events::{ResponseSource, SessionEvent};
+use crate::git::{self, GitResponse};
+use crate::handler::{Responder, SessionLog};
+use crate::prelude::*;
+use crate::telemetry;
+
+const MAX_HEADERS: usize = 64;
+
+// Framing headers of the response as written, replaced by our own.
+const FRAMING_HEADERS: &[&str] = &["content-length", "transfer-encoding", "connection", "keep-alive"];
+
+// One request off the wire.
+#[derive(Debug, PartialEq)]
+struct HttpRequest {
+	// Exactly as received, framing included, so smuggling attempts are on record.
+	text: String,
+	head_only: bool,
+	// The client asked for, or its HTTP version implies, a close after the response.
+	close: bool,
+}
+
+// An HTTP/1.x response the way a static response or the LLM wrote it.
+pub struct ResponseText<'a> {
+	pub status: u16,
+	pub status_line: &'a str,
+	pub headers: Vec<(&'a str, &'a str)>,
+	pub body: &'a str,
+}
+
+// None when the text isn't an HTTP response at all.
+pub fn parse_response(text: &str) -> Option<ResponseText<'_>> {
+	let (head, body) = text
+		.split_once("\r\n\r\n")
+		.or_else(|| text.split_once("\n\n"))
+		.unwrap_or((text.trim_end(), ""));
+	let mut lines = head.lines();
+	let status_line = lines.next().filter(|line| line.starts_with("HTTP/"))?;
+	let status = status_line.split_whitespace().nth(1)?.parse().ok()?;
+	let headers = lines.filter_map(|line| line.split_once(':')).map(|(name, value)| (name.trim(), value.trim())).collect();
+	Some(ResponseText { status, status_line, headers, body })
+}
+
+// Serve HTTP/1.x requests until the actor goes away or asks to close,
+// returning everything that happened.
+pub async fn handle_client<S, C>(mut stream: S, service: Option<PortConfig>, interaction: Interaction, limits: LimitsConfig, chatgpt: &C) -> Vec<SessionEvent>
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+	C: ChatService,
+{
+	let mut log = SessionLog::new(limits);
+	let started = Instant::now();
+	let mut first_byte_sent = false;
+	let protocol = service.as_ref().map(|s| s.name.clone()).unwrap_or_else(|| "unknown".to_string());
+	let mut responder = Responder::new(service.as_ref(), interaction, chatgpt);
+
+	// Bytes read but not yet consumed by a request, e.g. the next pipelined one
+	let mut buffer = Vec::new();
+	loop {
+		let request = match read_request(&mut stream, &mut buffer, limits.max_session_bytes).await {
+			Ok(Some(request)) => request,
+			Ok(None) => {
+				info!("Connection closed");
+				break;
+			}
+			Err(e) => {
+				info!("Failed to read from stream: {}", e);
+				break;
+			}
+		};
+		let received_at = Instant::now();
+		// The event keeps its own copy; the request's, bounded by the read
+		// limit, is dropped once it has been answered
+		log.emit(SessionEvent::Received { data: request.text.clone() });
+		if log.over_budget() {
+			warn!("Session exceeded its memory ceiling of {} bytes, disconnecting", limits.max_session_bytes);
+			log.emit(SessionEvent::MemoryLimitReached { retained_bytes: log.retained_bytes });
+			break;
+		}
+
+		// The decoy git repository answers for itself, with binary bodies
+		let (logged, response, source) = if let Some(git) = git::answer_http(&request.text, &mut log) {
+			(git.summary.clone(), frame_git_response(&git, request.head_only, request.close), ResponseSource::Decoy)
+		} else {
+			let Some((text, source)) = responder.respond(&request.text, &mut log).await else {
+				if request.close {
+					break;
+				}
+				continue;
+			};
+			let response = frame_response(&text, request.head_only, request.close);
+			(response.clone(), response.into_bytes(), source)
+		};
+		log.emit(SessionEvent::Responded { data: logged, source });
+		if let Err(e) = stream.write_all(&response).await {
+			info!("Failed to send data: {}", e);
+			break;
+		}
+		telemetry::record_exchange(&protocol, received_at.elapsed());
+		if !first_byte_sent {
+			telemetry::record_time_to_first_byte(&protocol, started.elapsed());
+			first_byte_sent = true;
+		}
+		if request.close {
+			break;
+		}
+	}
+
+	log.emit(SessionEvent::Closed);
+	log.events
+}
+
+// Read until `buffer` holds a whole request and take it off the front.
+// None once the actor has closed the connection between requests.
+async fn read_request<S: AsyncRead + Unpin>(stream: &mut S, buffer: &mut Vec<u8>, max_bytes: usize) -> io::Result<Option<HttpRequest>> {
+	let mut chunk = [0; 4096];
+	loop {
+		if let Some(request) = next_request(buffer, max_bytes) {
+			return Ok(Some(request));
+		}
+		let n = stream.read(&mut chunk).await?;
+		if n == 0 {
+			// Whatever is left was cut off mid-request
+			return Ok((!buffer.is_empty()).then(|| take(buffer, buffer.len(), false, true)));
+		}
+		buffer.extend_from_slice(&chunk[..n]);
+	}
+}
+
+// The first complete request in `buffer`, or None if more bytes are needed.
+// Anything that can't be framed as HTTP, or grows past `max_bytes` without
+// completing, is taken whole and answered once before hanging up, like a
+// real server's 400.
+fn next_request(buffer: &mut Vec<u8>, max_bytes: usize) -> Option<HttpRequest> {
+	if buffer.is_empty() {
+		return None;
+	}
+	let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+	let mut request = httparse::Request::new(&mut headers);
+	let incomplete = buffer.len() <= max_bytes;
+	let head_len = match request.parse(buffer) {
+		Ok(Status::Complete(len)) => len,
+		Ok(Status::Partial) if incomplete => return None,
+		_ => return Some(take(buffer, buffer.len(), false, true)),
+	};
+
+	let header = |name: &str| {
+		request
+			.headers
+			.iter()
+			.find(|h| h.name.eq_ignore_ascii_case(name))
+			.map(|h| String::from_utf8_lossy(h.value).to_ascii_lowercase())
+	};
+	let chunked = header("transfer-encoding").is_some_and(|v| v.contains("chunked"));
+	let content_length = header("content-length").and_then(|v| v.trim().parse::<usize>().ok()).unwrap_or(0);
+	let connection = header("connection").unwrap_or_default();
+	let close = match request.version {
+		Some(0) => !connection.contains("keep-alive"),
+		_ => connection.contains("close"),
+	};
+	let head_only = request.method == Some("HEAD");
+
+	let body_len = if chunked {
+		match chunked_body_len(&buffer[head_len..]) {
+			Ok(Some(len)) => len,
+			Ok(None) if incomplete => return None,
+			_ => return Some(take(buffer, buffer.len(), head_only, true)),
+		}
+	} else if buffer.len() - head_len >= content_length {
+		content_length
+	} else if incomplete {
+		return None;
+	} else {
+		return Some(take(buffer, buffer.len(), head_only, true));
+	};
+	Some(take(buffer, head_len + body_len, head_only, close))
+}
+
+// Length of a chunked body at the start of `data`, trailers included, or
+// None if it hasn't all arrived yet.
+fn chunked_body_len(data: &[u8]) -> Result<Option<usize>, httparse::InvalidChunkSize> {
+	let mut at = 0;
+	loop {
+		let (line_len, size) = match httparse::parse_chunk_size(&data[at..])? {
+			Status::Complete(chunk) => chunk,
+			Status::Partial => return Ok(None),
+		};
+		at += line_len;
+		if size == 0 {
+			break;
+		}
+		// The chunk's data and its trailing CRLF
+		at = at.saturating_add(usize::try_from(size).unwrap_or(usize::MAX)).saturating_add(2);
+		if at > data.len() {
+			return Ok(None);
+		}
+	}
+	// Trailer fields, up to an empty line
+	loop {
+		let Some(line_end) = data[at..].windows(2).position(|w| w == b"\r\n") else {
+			return Ok(None);
+		};
+		at += line_end + 2;
+		if line_end == 0 {
+			return Ok(Some(at));
+		}
+	}
+}
+
+fn take(buffer: &mut Vec<u8>, len: usize, head_only: bool, close: bool) -> HttpRequest {
+	let bytes: Vec<u8> = buffer.drain(..len).collect();
+	HttpRequest {
+		text: String::from_utf8_lossy(&bytes).to_string(),
+		head_only,
+		close,
+	}
+}
+
+// Frame a response for a persistent connection. Its status line and headers
+// are kept as written, with Content-Length set to the body actually sent.
+// Anything that isn't an HTTP response becomes the body of a 200.
+fn frame_response(text: &str, head_only: bool, close: bool) -> String {
+	let (status_line, headers, body) = match parse_response(text) {
+		Some(response) => (response.status_line, response.headers, response.body),
+		None => ("HTTP/1.1 200 OK", vec![("Content-Type", "text/html")], text),
+	};
+	let mut response = format!("{}\r\n", status_line);
+	for (name, value) in headers {
+		if !FRAMING_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+			response.push_str(&format!("{}: {}\r\n", name, value));
+		}
+	}
+	response.push_str(&format!("Content-Length: {}\r\n", body.len()));
+	if close {
+		response.push_str("Connection: close\r\n");
+	}
+	response.push_str("\r\n");
+	if !head_only {
+		response.push_str(body);
+	}
+	response
+}
+
+fn frame_git_response(git: &GitResponse, head_only: bool, close: bool) -> Vec<u8> {
+	let reason = http::StatusCode::from_u16(git.status).ok().and_then(|s| s.canonical_reason()).unwrap_or_default();
+	let mut response = format!("HTTP/1.1 {} {}\r\nContent-Type: {}\r\nCache-Control: no-cache\r\nContent-Length: {}\r\n", git.status, reason, git.content_type, git.body.len());
+	if close {
+		response.push_str("Connection: close\r\n");
+	}
+	response.push_str("\r\n");
+	let mut response = response.into_bytes();
+	if !head_only {
+		response.extend_from_slice(&git.body);
+	}
+	response
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::chat_service::MockChatService;
+	use crate::config::InteractionLevel;
+	use tokio::io::duplex;
+
+	#[test]
+	fn responses_are_framed_for_persistent_connections() {
+		assert_eq!(
+			frame_response("HTTP/1.1 404 Not Found\r\nServer: Apache/2.4.41 (Ubuntu)\r\nContent-Length: 0\r\n\r\n", false, false),
+			"HTTP/1.1 404 Not Found\r\nServer: Apache/2.4.41 (Ubuntu)\r\nContent-Length: 0\r\n\r\n"
+		);
+		assert_eq!(
+			frame_response("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n<html></html>", false, true),
+			"HTTP/1.1 200 OK\r\nContent-Length: 13\r\nConnection: close\r\n\r\n<html></html>"
+		);
+		assert_eq!(frame_response("Invalid Command", true, false), "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 15\r\n\r\n");
+	}
+
+	#[tokio::test]
+	async fn pipelined_and_chunked_requests_are_answered_one_by_one() {
+		let (mut client, server) = duplex(64 * 1024);
+		let service = PortConfig {
+			name: "http".to_string(),
+			enabled: true,
+			port: 80,
+			tls: false,
+			banner: None,
+			static_response: Some("HTTP/1.1 404 Not Found\r\nServer: Apache/2.4.41 (Ubuntu)\r\nContent-Length: 0\r\n".to_string()),
+		};
+		let limits = LimitsConfig { max_session_bytes: 4096, max_logged_bytes: 4096 };
+		let server = task::spawn(async move {
+			handle_client(server, Some(service), InteractionLevel::Low.into(), limits, &MockChatService::new("unused")).await
+		});
+
+		let requests = [
+			"GET /.env HTTP/1.1\r\nHost: x\r\n\r\n",
+			"POST /login HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nuser\r\n6;ext=1\r\n=admin\r\n0\r\nX-Trailer: 1\r\n\r\n",
+			"POST /upload HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello",
+			"HEAD / HTTP/1.0\r\n\r\n",
+		];
+		// All pipelined in one write, the body of the third split across two
+		let everything = requests.concat();
+		let split = everything.find("hel").unwrap() + 3;
+		client.write_all(&everything.as_bytes()[..split]).await.unwrap();
+		tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+		client.write_all(&everything.as_bytes()[split..]).await.unwrap();
+
+		// HTTP/1.0 without keep-alive closes after its response
+		let mut responses = String::new();
+		client.read_to_string(&mut responses).await.unwrap();
+		assert_eq!(responses.matches("HTTP/1.1 404 Not Found\r\n").count(), 4);
+		assert!(responses.ends_with("Content-Length: 0\r\nConnection: close\r\n\r\n"));
+
+		let events = server.await.unwrap();
+		let received: Vec<_> = events
+			.iter()
+			.filter_map(|e| match e {
+				SessionEvent::Received { data } => Some(data.as_str()),
+				_ => None,
+			})
+			.collect();
+		assert_eq!(received, requests);
+		assert_eq!(events.last(), Some(&SessionEvent::Closed));
+	}
+}
@@ -0,0 +1,240 @@
+// HTTP/2 for TLS web listeners that negotiate `h2` by ALPN. Modern scanners
+// and browsers prefer it, and a server that offers only HTTP/1.1 on 443 stands
+// out. Each request is written down as the HTTP/1.x text the rest of the
+// honeypot understands, answered by the same static response or LLM, and the
+// HTTP/1.x answer is mapped back onto an HTTP/2 response.
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::{Reason, RecvStream};
+use http::header::{HeaderName, HeaderValue, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE};
+use http::{Method, Request, Response, StatusCode};
+use std::future::poll_fn;
+use std::time::Instant;
+use crate::chat_service::ChatService;
+use crate::config::{Interaction, LimitsConfig, PortConfig};
+use crate::events::{ResponseSource, SessionEvent};
+use crate::git;
+use crate::handler::{Responder, SessionLog};
+use crate::http1::parse_response;
+use crate::prelude::*;
+use crate::telemetry;
+
+pub const ALPN_H2: &[u8] = b"h2";
+
+// Hop-by-hop HTTP/1.x headers that are illegal in HTTP/2.
+const CONNECTION_HEADERS: &[&str] = &["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+// Serve an HTTP/2 connection until the actor goes away, returning everything that happened.
+pub async fn handle_client<S, C>(stream: S, service: Option<PortConfig>, interaction: Interaction, limits: LimitsConfig, chatgpt: &C) -> Vec<SessionEvent>
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+	C: ChatService,
+{
+	let mut log = SessionLog::new(limits);
+	let started = Instant::now();
+	let mut first_byte_sent = false;
+	let protocol = service.as_ref().map(|s| s.name.clone()).unwrap_or_else(|| "unknown".to_string());
+	let mut responder = Responder::new(service.as_ref(), interaction, chatgpt);
+
+	let mut connection = match h2::server::handshake(stream).await {
+		Ok(connection) => connection,
+		Err(e) => {
+			info!("HTTP/2 handshake failed: {}", e);
+			log.emit(SessionEvent::Closed);
+			return log.events;
+		}
+	};
+
+	// Requests are answered one at a time, in order, like the HTTP/1.x handler.
+	while let Some(request) = connection.accept().await {
+		let (request, respond) = match request {
+			Ok(request) => request,
+			Err(e) => {
+				info!("HTTP/2 connection error: {}", e);
+				break;
+			}
+		};
+		let received_at = Instant::now();
+
+		// The connection has to keep being polled for the request body to arrive
+		// and for the response to be flushed.
+		let served = serve_request(request, respond, &mut responder, &mut log, limits.max_session_bytes);
+		let over_budget = tokio::select! {
+			over_budget = served => over_budget,
+			_ = poll_fn(|cx| connection.poll_closed(cx)) => break,
+		};
+		if over_budget {
+			warn!("Session exceeded its memory ceiling of {} bytes, disconnecting", limits.max_session_bytes);
+			log.emit(SessionEvent::MemoryLimitReached { retained_bytes: log.retained_bytes });
+			connection.abrupt_shutdown(Reason::ENHANCE_YOUR_CALM);
+			let _ = poll_fn(|cx| connection.poll_closed(cx)).await;
+			break;
+		}
+		telemetry::record_exchange(&protocol, received_at.elapsed());
+		if !first_byte_sent {
+			telemetry::record_time_to_first_byte(&protocol, started.elapsed());
+			first_byte_sent = true;
+		}
+	}
+
+	log.emit(SessionEvent::Closed);
+	log.events
+}
+
+// Answer one request, returning whether the session went over its memory ceiling.
+async fn serve_request<C: ChatService>(request: Request<RecvStream>, respond: SendResponse<Bytes>, responder: &mut Responder<'_, C>, log: &mut SessionLog, max_bytes: usize) -> bool {
+	let head_only = request.method() == Method::HEAD;
+	let payload = request_text(request, max_bytes).await;
+	// As in http1, the event keeps its own copy and this one is dropped once answered
+	log.emit(SessionEvent::Received { data: payload.clone() });
+	if log.over_budget() {
+		return true;
+	}
+
+	if let Some(git) = git::answer_http(&payload, log) {
+		log.emit(SessionEvent::Responded { data: git.summary, source: ResponseSource::Decoy });
+		let response = Response::builder()
+			.status(git.status)
+			.header(CONTENT_TYPE, git.content_type)
+			.header(CACHE_CONTROL, "no-cache")
+			.header(CONTENT_LENGTH, git.body.len())
+			.body(())
+			.unwrap();
+		send(respond, response, Bytes::from(git.body), head_only);
+		return false;
+	}
+
+	let (response, body) = match responder.respond(&payload, log).await {
+		Some((text, source)) => {
+			log.emit(SessionEvent::Responded { data: text.clone(), source });
+			response_from_text(&text)
+		}
+		// Nothing configured to answer with: what an unconfigured vhost returns
+		None => (Response::builder().status(StatusCode::NOT_FOUND).body(()).unwrap(), Bytes::new()),
+	};
+	send(respond, response, body, head_only);
+	false
+}
+
+fn send(mut respond: SendResponse<Bytes>, response: Response<()>, body: Bytes, head_only: bool) {
+	let end_of_stream = head_only || body.is_empty();
+	match respond.send_response(response, end_of_stream) {
+		Ok(mut stream) if !end_of_stream => {
+			if let Err(e) = stream.send_data(body, true) {
+				info!("Failed to send HTTP/2 response body: {}", e);
+			}
+		}
+		Ok(_) => {}
+		Err(e) => info!("Failed to send HTTP/2 response: {}", e),
+	}
+}
+
+// Write an HTTP/2 request down the way it would look on an HTTP/1.1
+// connection, with the :authority pseudo-header as Host. The body is read no
+// further than `max_bytes`, past which the session is over its ceiling anyway.
+async fn request_text(request: Request<RecvStream>, max_bytes: usize) -> String {
+	let (parts, mut body) = request.into_parts();
+	let path = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+	let mut text = format!("{} {} HTTP/2\r\n", parts.method, path);
+	if let (Some(authority), false) = (parts.uri.authority(), parts.headers.contains_key("host")) {
+		text.push_str(&format!("host: {}\r\n", authority));
+	}
+	for (name, value) in &parts.headers {
+		text.push_str(&format!("{}: {}\r\n", name, String::from_utf8_lossy(value.as_bytes())));
+	}
+	text.push_str("\r\n");
+
+	let mut content = Vec::new();
+	while let Some(Ok(chunk)) = body.data().await {
+		let _ = body.flow_control().release_capacity(chunk.len());
+		content.extend_from_slice(&chunk);
+		if content.len() > max_bytes {
+			break;
+		}
+	}
+	text.push_str(&String::from_utf8_lossy(&content));
+	text
+}
+
+// Map an HTTP/1.x response, as a static response or the LLM would write it,
+// onto an HTTP/2 response and its body. Anything that isn't an HTTP response
+// is sent as the body of a 200.
+fn response_from_text(text: &str) -> (Response<()>, Bytes) {
+	let Some((status, parsed)) = parse_response(text).and_then(|r| Some((StatusCode::from_u16(r.status).ok()?, r))) else {
+		let response = Response::builder().status(StatusCode::OK).header(CONTENT_LENGTH, text.len()).body(()).unwrap();
+		return (response, Bytes::copy_from_slice(text.as_bytes()));
+	};
+
+	let mut response = Response::builder().status(status);
+	for (name, value) in parsed.headers {
+		let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) else {
+			continue;
+		};
+		if name != CONTENT_LENGTH && !CONNECTION_HEADERS.contains(&name.as_str()) {
+			response = response.header(name, value);
+		}
+	}
+	let response = response.header(CONTENT_LENGTH, parsed.body.len()).body(()).unwrap();
+	(response, Bytes::copy_from_slice(parsed.body.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::chat_service::MockChatService;
+	use crate::config::InteractionLevel;
+	use tokio::io::duplex;
+
+	#[test]
+	fn http1_responses_become_http2_responses() {
+		let (response, body) = response_from_text("HTTP/1.1 301 Moved Permanently\r\nServer: nginx\r\nLocation: /login\r\nConnection: keep-alive\r\nContent-Length: 99\r\n\r\nmoved");
+		assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+		assert_eq!(response.headers()["server"], "nginx");
+		assert_eq!(response.headers()["location"], "/login");
+		assert_eq!(response.headers()["content-length"], "5");
+		assert!(response.headers().get("connection").is_none());
+		assert_eq!(body, "moved");
+
+		let (response, body) = response_from_text("Invalid Command");
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(body, "Invalid Command");
+	}
+
+	#[tokio::test]
+	async fn requests_are_answered_over_http2() {
+		let (client, server) = duplex(64 * 1024);
+		let service = PortConfig {
+			name: "https".to_string(),
+			enabled: true,
+			port: 443,
+			tls: true,
+			banner: None,
+			static_response: Some("HTTP/1.1 404 Not Found\r\nServer: Apache/2.4.41 (Ubuntu)\r\nContent-Length: 0\r\n".to_string()),
+		};
+		let limits = LimitsConfig { max_session_bytes: 4096, max_logged_bytes: 4096 };
+		let server = task::spawn(async move {
+			handle_client(server, Some(service), InteractionLevel::Low.into(), limits, &MockChatService::new("unused")).await
+		});
+
+		let (mut h2, connection) = h2::client::handshake(client).await.unwrap();
+		task::spawn(connection);
+		let request = Request::post("https://vpn.corp.example/remote/login?lang=en").header("user-agent", "zgrab/0.x").body(()).unwrap();
+		let (response, mut send) = h2.send_request(request, false).unwrap();
+		send.send_data(Bytes::from_static(b"username=admin"), true).unwrap();
+		let response = response.await.unwrap();
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+		assert_eq!(response.headers()["server"], "Apache/2.4.41 (Ubuntu)");
+		// The connection closes once every handle to it is gone
+		drop((h2, send, response));
+
+		let events = server.await.unwrap();
+		assert_eq!(
+			events[0],
+			SessionEvent::Received {
+				data: "POST /remote/login?lang=en HTTP/2\r\nhost: vpn.corp.example\r\nuser-agent: zgrab/0.x\r\n\r\nusername=admin".to_string()
+			}
+		);
+		assert!(matches!(events[1], SessionEvent::Responded { .. }));
+		assert_eq!(events.last(), Some(&SessionEvent::Closed));
+	}
+}
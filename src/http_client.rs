@@ -0,0 +1,87 @@
+use reqwest::{Client, ClientBuilder, Proxy, Response};
+use serde::Deserialize;
+use std::error::Error;
+use std::time::Duration;
+
+// Egress settings shared by every module that talks HTTP (ChatGPT today,
+// enrichment lookups later), so outbound behavior is controlled in one place.
+#[derive(Debug, Deserialize, Clone)]
+pub struct HttpConfig {
+	// Whole-request timeout, including reading the response body.
+	#[serde(default = "HttpConfig::default_timeout_secs")]
+	pub timeout_secs: u64,
+	#[serde(default = "HttpConfig::default_connect_timeout_secs")]
+	pub connect_timeout_secs: u64,
+	// How long idle pooled connections are kept open for reuse.
+	#[serde(default = "HttpConfig::default_pool_idle_timeout_secs")]
+	pub pool_idle_timeout_secs: u64,
+	#[serde(default = "HttpConfig::default_pool_max_idle_per_host")]
+	pub pool_max_idle_per_host: usize,
+	// Route all outbound requests through this proxy, e.g. "http://10.0.0.5:3128".
+	#[serde(default)]
+	pub proxy: Option<String>,
+}
+
+impl HttpConfig {
+	fn default_timeout_secs() -> u64 {
+		30
+	}
+	
+	fn default_connect_timeout_secs() -> u64 {
+		5
+	}
+	
+	fn default_pool_idle_timeout_secs() -> u64 {
+		90
+	}
+	
+	fn default_pool_max_idle_per_host() -> usize {
+		32
+	}
+}
+
+impl Default for HttpConfig {
+	fn default() -> Self {
+		HttpConfig {
+			timeout_secs: Self::default_timeout_secs(),
+			connect_timeout_secs: Self::default_connect_timeout_secs(),
+			pool_idle_timeout_secs: Self::default_pool_idle_timeout_secs(),
+			pool_max_idle_per_host: Self::default_pool_max_idle_per_host(),
+			proxy: None,
+		}
+	}
+}
+
+// Build the client shared by the whole process. Clones share one connection pool.
+pub fn build_client(config: &HttpConfig) -> Result<Client, reqwest::Error> {
+	builder(config)?.build()
+}
+
+// The shared settings, for a client that needs a few of its own on top.
+pub fn builder(config: &HttpConfig) -> Result<ClientBuilder, reqwest::Error> {
+	let mut builder = Client::builder()
+		.timeout(Duration::from_secs(config.timeout_secs))
+		.connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+		.pool_idle_timeout(Duration::from_secs(config.pool_idle_timeout_secs))
+		.pool_max_idle_per_host(config.pool_max_idle_per_host);
+	if let Some(proxy) = &config.proxy {
+		builder = builder.proxy(Proxy::all(proxy)?);
+	}
+	Ok(builder)
+}
+
+// The body of `response`, refusing any larger than `max` bytes.
+pub async fn read_body(mut response: Response, max: usize) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+	let too_large = |url: &reqwest::Url| format!("{} is larger than {} bytes", url, max);
+	if response.content_length().is_some_and(|len| len > max as u64) {
+		return Err(too_large(response.url()).into());
+	}
+	let mut body = Vec::new();
+	while let Some(chunk) = response.chunk().await? {
+		if body.len() + chunk.len() > max {
+			return Err(too_large(response.url()).into());
+		}
+		body.extend_from_slice(&chunk);
+	}
+	Ok(body)
+}
@@ -0,0 +1,386 @@
+// A Kerberos KDC on port 88 (TCP) that never issues a ticket. AS-REQs are
+// parsed so the requested principal, realm and encryption types are logged,
+// which is what gives away user enumeration (kerbrute) and AS-REP roasting
+// (GetNPUsers, Rubeus asreproast), and each is answered with the KRB-ERROR an
+// Active Directory domain controller would send:
+//
+//     wrong realm                    KDC_ERR_WRONG_REALM
+//     user not in `users`            KDC_ERR_C_PRINCIPAL_UNKNOWN
+//     no pre-authentication          KDC_ERR_PREAUTH_REQUIRED, with salt hints
+//     encrypted timestamp            KDC_ERR_PREAUTH_FAILED
+//
+// So listed users look real and pre-authenticated, roasting never yields a
+// hash, and every password guess fails.
+use serde::Deserialize;
+use std::collections::HashMap;
+use time::macros::format_description;
+use time::OffsetDateTime;
+use crate::config::LimitsConfig;
+use crate::der;
+use crate::events::{ResponseSource, SessionEvent};
+use crate::handler::SessionLog;
+use crate::prelude::*;
+
+const KRB_AS_REQ: u8 = 0x6a;
+const KRB_TGS_REQ: u8 = 0x6c;
+const KRB_ERROR: u8 = 0x7e;
+
+const PA_ENC_TIMESTAMP: i64 = 2;
+const PA_ETYPE_INFO2: i64 = 19;
+const PA_PK_AS_REQ: i64 = 16;
+const PA_PK_AS_REP_OLD: i64 = 15;
+
+const RC4_HMAC: i64 = 23;
+const AES256_CTS_HMAC_SHA1: i64 = 18;
+const NT_PRINCIPAL: i64 = 1;
+const NT_SRV_INST: i64 = 2;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct KerberosConfig {
+	#[serde(default = "KerberosConfig::default_realm")]
+	pub realm: String,
+	// Accounts that exist in the fake domain. Everyone else is unknown, so
+	// user enumeration finds exactly these.
+	#[serde(default = "KerberosConfig::default_users")]
+	pub users: Vec<String>,
+}
+
+impl KerberosConfig {
+	fn default_realm() -> String {
+		"CORP.LOCAL".to_string()
+	}
+
+	fn default_users() -> Vec<String> {
+		["administrator", "krbtgt", "svc_backup", "svc_sql", "jsmith"].map(String::from).to_vec()
+	}
+}
+
+impl Default for KerberosConfig {
+	fn default() -> Self {
+		KerberosConfig {
+			realm: Self::default_realm(),
+			users: Self::default_users(),
+		}
+	}
+}
+
+// The interesting parts of an AS-REQ.
+#[derive(Debug, Default, PartialEq)]
+struct AsReq {
+	cname: Option<String>,
+	realm: String,
+	sname: Option<String>,
+	etypes: Vec<i64>,
+	padata: Vec<i64>,
+}
+
+impl AsReq {
+	fn parse(message: &[u8]) -> Option<AsReq> {
+		let (tag, body, _) = der::element(message)?;
+		if tag != KRB_AS_REQ {
+			return None;
+		}
+		let request = fields(der::sequence(body)?)?;
+		let padata = match request.get(&3) {
+			Some(padata) => der::elements(der::sequence(padata)?)?
+				.into_iter()
+				.filter_map(|pa| integer(fields(der::sequence(pa)?)?.get(&1)?))
+				.collect(),
+			None => vec![],
+		};
+		let req_body = fields(der::sequence(request.get(&4)?)?)?;
+		Some(AsReq {
+			cname: req_body.get(&1).and_then(|name| principal(name)),
+			realm: general_string(req_body.get(&2)?)?,
+			sname: req_body.get(&3).and_then(|name| principal(name)),
+			etypes: der::elements(der::sequence(req_body.get(&8)?)?)?.into_iter().filter_map(integer).collect(),
+			padata,
+		})
+	}
+
+	// The account name without any instance, as AD looks it up.
+	fn user(&self) -> Option<&str> {
+		self.cname.as_deref().map(|name| name.split('/').next().unwrap_or(name))
+	}
+
+	// No pre-authentication and only weak encryption types is how AS-REP
+	// roasting tools ask, to get a hash that cracks quickly.
+	fn roasting(&self) -> bool {
+		!self.padata.contains(&PA_ENC_TIMESTAMP) && !self.etypes.is_empty() && self.etypes.iter().all(|e| *e == RC4_HMAC || *e <= 3)
+	}
+
+	fn describe(&self) -> String {
+		let etypes: Vec<_> = self.etypes.iter().map(|e| etype_name(*e)).collect();
+		let padata: Vec<_> = self.padata.iter().map(|p| p.to_string()).collect();
+		format!(
+			"AS-REQ cname={} realm={} sname={} etypes={} padata=[{}]",
+			self.cname.as_deref().unwrap_or("-"),
+			self.realm,
+			self.sname.as_deref().unwrap_or("-"),
+			etypes.join(","),
+			padata.join(",")
+		)
+	}
+}
+
+fn etype_name(etype: i64) -> String {
+	match etype {
+		1 => "des-cbc-crc".to_string(),
+		3 => "des-cbc-md5".to_string(),
+		17 => "aes128-cts-hmac-sha1-96".to_string(),
+		18 => "aes256-cts-hmac-sha1-96".to_string(),
+		19 => "aes128-cts-hmac-sha256-128".to_string(),
+		20 => "aes256-cts-hmac-sha384-192".to_string(),
+		23 => "rc4-hmac".to_string(),
+		24 => "rc4-hmac-exp".to_string(),
+		other => other.to_string(),
+	}
+}
+
+// The KRB-ERROR a domain controller would answer with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum KrbError {
+	PrincipalUnknown = 6,
+	PreauthFailed = 24,
+	PreauthRequired = 25,
+	BadIntegrity = 31,
+	WrongRealm = 68,
+}
+
+impl KrbError {
+	fn name(self) -> &'static str {
+		match self {
+			KrbError::PrincipalUnknown => "KDC_ERR_C_PRINCIPAL_UNKNOWN",
+			KrbError::PreauthFailed => "KDC_ERR_PREAUTH_FAILED",
+			KrbError::PreauthRequired => "KDC_ERR_PREAUTH_REQUIRED",
+			KrbError::BadIntegrity => "KRB_AP_ERR_BAD_INTEGRITY",
+			KrbError::WrongRealm => "KDC_ERR_WRONG_REALM",
+		}
+	}
+}
+
+fn decide(config: &KerberosConfig, request: &AsReq) -> KrbError {
+	if !request.realm.eq_ignore_ascii_case(&config.realm) {
+		return KrbError::WrongRealm;
+	}
+	let known = request.user().is_some_and(|user| config.users.iter().any(|u| u.eq_ignore_ascii_case(user)));
+	if !known {
+		KrbError::PrincipalUnknown
+	} else if request.padata.contains(&PA_ENC_TIMESTAMP) {
+		KrbError::PreauthFailed
+	} else {
+		KrbError::PreauthRequired
+	}
+}
+
+// Answer one framed message, returning what to log, any tags and the reply.
+fn answer(config: &KerberosConfig, message: &[u8]) -> (String, Vec<String>, KrbError, Vec<u8>) {
+	let realm = config.realm.to_ascii_uppercase();
+	match AsReq::parse(message) {
+		Some(request) => {
+			let error = decide(config, &request);
+			info!("Kerberos {} -> {}", request.describe(), error.name());
+			let tags = if request.roasting() { vec!["kerberos:as-rep-roasting".to_string()] } else { vec![] };
+			let user = request.user().unwrap_or_default().to_string();
+			let e_data = (error == KrbError::PreauthRequired).then(|| method_data(&realm, &user));
+			let reply = krb_error(error, &realm, request.cname.as_deref(), e_data);
+			(request.describe(), tags, error, reply)
+		}
+		None => {
+			// A TGS-REQ can't carry a ticket we issued; anything else is garbage
+			let description = match der::element(message) {
+				Some((KRB_TGS_REQ, ..)) => "TGS-REQ".to_string(),
+				_ => format!("unparsed message of {} bytes", message.len()),
+			};
+			(description, vec![], KrbError::BadIntegrity, krb_error(KrbError::BadIntegrity, &realm, None, None))
+		}
+	}
+}
+
+// What the client can pre-authenticate with, and the AES salt for the user.
+fn method_data(realm: &str, user: &str) -> Vec<u8> {
+	let etype_info2 = seq(&[
+		seq(&[ctx(0, &int(AES256_CTS_HMAC_SHA1)), ctx(1, &general(&format!("{}{}", realm, user)))]),
+		seq(&[ctx(0, &int(RC4_HMAC))]),
+	]);
+	let pa = |kind: i64, value: &[u8]| seq(&[ctx(1, &int(kind)), ctx(2, &der::encode(0x04, value))]);
+	seq(&[pa(PA_ETYPE_INFO2, &etype_info2), pa(PA_ENC_TIMESTAMP, &[]), pa(PA_PK_AS_REQ, &[]), pa(PA_PK_AS_REP_OLD, &[])])
+}
+
+fn krb_error(error: KrbError, realm: &str, cname: Option<&str>, e_data: Option<Vec<u8>>) -> Vec<u8> {
+	let now = OffsetDateTime::now_utc();
+	let stime = now.format(format_description!("[year][month][day][hour][minute][second]Z")).unwrap_or_default();
+	let mut fields = vec![
+		ctx(0, &int(5)),
+		ctx(1, &int(30)),
+		ctx(4, &der::encode(0x18, stime.as_bytes())),
+		ctx(5, &int(i64::from(now.microsecond()))),
+		ctx(6, &int(error as i64)),
+	];
+	if let Some(cname) = cname {
+		fields.push(ctx(7, &general(realm)));
+		fields.push(ctx(8, &principal_name(NT_PRINCIPAL, &cname.split('/').collect::<Vec<_>>())));
+	}
+	fields.push(ctx(9, &general(realm)));
+	fields.push(ctx(10, &principal_name(NT_SRV_INST, &["krbtgt", realm])));
+	if let Some(e_data) = e_data {
+		fields.push(ctx(12, &der::encode(0x04, &e_data)));
+	}
+	der::encode(KRB_ERROR, &seq(&fields))
+}
+
+// Kerberos structures on top of der.
+
+// A SEQUENCE's [n] explicitly tagged fields, by n.
+fn fields(contents: &[u8]) -> Option<HashMap<u8, &[u8]>> {
+	der::elements(contents)?
+		.into_iter()
+		.map(|element| {
+			let (tag, inner, _) = der::element(element)?;
+			(tag & 0xe0 == 0xa0).then_some((tag & 0x1f, inner))
+		})
+		.collect()
+}
+
+fn integer(data: &[u8]) -> Option<i64> {
+	match der::element(data)? {
+		(0x02, bytes, _) if !bytes.is_empty() && bytes.len() <= 8 => {
+			let sign = if bytes[0] & 0x80 != 0 { -1i64 } else { 0 };
+			Some(bytes.iter().fold(sign, |n, b| n << 8 | *b as i64))
+		}
+		_ => None,
+	}
+}
+
+fn general_string(data: &[u8]) -> Option<String> {
+	match der::element(data)? {
+		(0x1b, bytes, _) => Some(String::from_utf8_lossy(bytes).to_string()),
+		_ => None,
+	}
+}
+
+// A PrincipalName's components joined with '/', e.g. "krbtgt/CORP.LOCAL".
+fn principal(data: &[u8]) -> Option<String> {
+	let name = fields(der::sequence(data)?)?;
+	let parts = der::elements(der::sequence(name.get(&1)?)?)?
+		.into_iter()
+		.map(general_string)
+		.collect::<Option<Vec<_>>>()?;
+	Some(parts.join("/"))
+}
+
+// Kerberos structures, written with der::encode.
+fn seq(parts: &[Vec<u8>]) -> Vec<u8> {
+	der::encode(0x30, &parts.concat())
+}
+
+fn ctx(n: u8, inner: &[u8]) -> Vec<u8> {
+	der::encode(0xa0 | n, inner)
+}
+
+fn int(n: i64) -> Vec<u8> {
+	let bytes = n.to_be_bytes();
+	// Shortest two's complement form
+	let mut start = 0;
+	while start < 7 && ((bytes[start] == 0 && bytes[start + 1] & 0x80 == 0) || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0)) {
+		start += 1;
+	}
+	der::encode(0x02, &bytes[start..])
+}
+
+fn general(s: &str) -> Vec<u8> {
+	der::encode(0x1b, s.as_bytes())
+}
+
+fn principal_name(name_type: i64, parts: &[&str]) -> Vec<u8> {
+	let strings: Vec<_> = parts.iter().map(|p| general(p)).collect();
+	seq(&[ctx(0, &int(name_type)), ctx(1, &seq(&strings))])
+}
+
+// Answer framed messages until the actor disconnects. Over TCP every message
+// is preceded by its length as a 4-byte big-endian integer.
+pub async fn handle_client<S>(mut stream: S, config: &KerberosConfig, limits: LimitsConfig) -> Vec<SessionEvent>
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+{
+	let mut log = SessionLog::new(limits);
+	loop {
+		let mut length = [0; 4];
+		if stream.read_exact(&mut length).await.is_err() {
+			break;
+		}
+		let length = u32::from_be_bytes(length) as usize;
+		// The top bit is reserved for extensions no client uses
+		if length > limits.max_session_bytes || length & 0x8000_0000 != 0 {
+			warn!("Kerberos message of {} bytes exceeds the session limit, disconnecting", length);
+			log.emit(SessionEvent::MemoryLimitReached { retained_bytes: length });
+			break;
+		}
+		let mut message = vec![0; length];
+		if stream.read_exact(&mut message).await.is_err() {
+			break;
+		}
+		let (description, tags, error, reply) = answer(config, &message);
+		log.emit(SessionEvent::Received { data: description });
+		for tag in tags {
+			log.emit(SessionEvent::Tagged { tag });
+		}
+		log.emit(SessionEvent::Responded { data: format!("KRB-ERROR {} ({})", error.name(), error as i32), source: ResponseSource::Static });
+		let mut framed = (reply.len() as u32).to_be_bytes().to_vec();
+		framed.extend(reply);
+		if let Err(e) = stream.write_all(&framed).await {
+			info!("Failed to send data: {}", e);
+			break;
+		}
+		if log.over_budget() {
+			break;
+		}
+	}
+	log.emit(SessionEvent::Closed);
+	log.events
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn as_req(user: &str, realm: &str, etypes: &[i64], padata: &[i64]) -> Vec<u8> {
+		let body = seq(&[
+			ctx(0, &der::encode(0x03, &[0, 0x40, 0x81, 0, 0x10])),
+			ctx(1, &principal_name(NT_PRINCIPAL, &[user])),
+			ctx(2, &general(realm)),
+			ctx(3, &principal_name(NT_SRV_INST, &["krbtgt", realm])),
+			ctx(7, &int(0x1234_5678)),
+			ctx(8, &seq(&etypes.iter().map(|e| int(*e)).collect::<Vec<_>>())),
+		]);
+		let padata: Vec<_> = padata.iter().map(|kind| seq(&[ctx(1, &int(*kind)), ctx(2, &der::encode(0x04, b"x"))])).collect();
+		der::encode(KRB_AS_REQ, &seq(&[ctx(1, &int(5)), ctx(2, &int(10)), ctx(3, &seq(&padata)), ctx(4, &body)]))
+	}
+
+	// The error code of a KRB-ERROR.
+	fn error_code(reply: &[u8]) -> i64 {
+		let (tag, contents, _) = der::element(reply).unwrap();
+		assert_eq!(tag, KRB_ERROR);
+		integer(fields(der::sequence(contents).unwrap()).unwrap()[&6]).unwrap()
+	}
+
+	#[test]
+	fn as_reqs_are_logged_and_refused_like_a_domain_controller_would() {
+		let config = KerberosConfig::default();
+
+		let roast = as_req("svc_backup", "corp.local", &[RC4_HMAC], &[]);
+		let (description, tags, error, reply) = answer(&config, &roast);
+		assert_eq!(description, "AS-REQ cname=svc_backup realm=corp.local sname=krbtgt/corp.local etypes=rc4-hmac padata=[]");
+		assert_eq!(tags, vec!["kerberos:as-rep-roasting"]);
+		assert_eq!((error, error_code(&reply)), (KrbError::PreauthRequired, 25));
+
+		let (_, tags, error, _) = answer(&config, &as_req("Administrator", "CORP.LOCAL", &[18, 17, 23], &[PA_ENC_TIMESTAMP]));
+		assert!(tags.is_empty());
+		assert_eq!(error, KrbError::PreauthFailed);
+		assert_eq!(answer(&config, &as_req("nobody", "CORP.LOCAL", &[18], &[])).2, KrbError::PrincipalUnknown);
+		assert_eq!(answer(&config, &as_req("administrator", "OTHER.ORG", &[18], &[])).2, KrbError::WrongRealm);
+		assert_eq!(answer(&config, b"\x30\x03garbage").2, KrbError::BadIntegrity);
+		assert_eq!(int(128), vec![0x02, 0x02, 0x00, 0x80]);
+		assert_eq!(integer(&int(-129)), Some(-129));
+	}
+}
@@ -1,34 +1,652 @@
+// Ships finished sessions to S3 in gzipped batches. Sessions are appended to
+// the batch file as JSON lines; every interval the batch is moved into the
+// pending directory, compressed and uploaded. A stage that fails leaves its
+// files where they are for the next round, so sessions keep being collected
+// through an S3 outage or a full disk and nothing already batched is lost.
+//
+// In session mode each session is instead its own object, keyed
+// <app_id>/sessions/ip=<actor>/dt=<day>/<time>-<port>.json, which Athena can
+// partition on and which can be handed to another team on its own. Objects
+// wait in <pending_dir>/sessions under the same layout until uploaded.
+use aws_config::BehaviorVersion;
+use aws_sdk_s3::Client;
+use ring::digest::{digest, SHA256};
+use serde::Deserialize;
+use serde_json::Value;
+use std::error::Error;
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::time::sleep;
-use crate::{log_collector, log_compressor, log_uploader};
-use config::{Config, File};
-
-pub async fn start_batching_process() {
-	let settings = Config::builder().add_source(File::with_name("Config")).build().unwrap();
-	let interval_secs: u64 = settings.get("general.upload_interval_secs").unwrap();
-	let interval = Duration::from_secs(interval_secs);
-	let app_id = settings.get("aws.app_id").unwrap();
-	let s3_bucket = settings.get("aws.s3_bucket").unwrap();
-	
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use time::{Date, OffsetDateTime};
+use tokio::time::{interval, sleep};
+#[cfg(all(test, feature = "chaos"))]
+use crate::chaos::Chaos;
+use crate::log_collector::collect_log;
+use crate::log_compressor::compress_logs;
+use crate::log_uploader::upload_to_s3;
+use crate::prelude::*;
+use crate::events::SessionEvent;
+use crate::sinks::SessionSummary;
+use crate::telemetry;
+
+// Wait before the first retry of an upload, doubled for each one after.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LogUploadConfig {
+	#[serde(default)]
+	pub enabled: bool,
+	#[serde(default)]
+	pub mode: UploadMode,
+	#[serde(default = "LogUploadConfig::default_interval_secs")]
+	pub interval_secs: u64,
+	// Objects are keyed <app_id>/<batch>.log.gz, so several honeypots can share a bucket.
+	#[serde(default = "LogUploadConfig::default_app_id")]
+	pub app_id: String,
+	#[serde(default)]
+	pub s3_bucket: String,
+	#[serde(default = "LogUploadConfig::default_batch_file")]
+	pub batch_file: String,
+	// Batches waiting to be compressed or uploaded. Grows while S3 is unreachable.
+	#[serde(default = "LogUploadConfig::default_pending_dir")]
+	pub pending_dir: String,
+	#[serde(default = "LogUploadConfig::default_upload_attempts")]
+	pub upload_attempts: u32,
+}
+
+impl LogUploadConfig {
+	fn default_interval_secs() -> u64 {
+		300
+	}
+
+	fn default_app_id() -> String {
+		"rustbucket".to_string()
+	}
+
+	fn default_batch_file() -> String {
+		"./logs/batch.log".to_string()
+	}
+
+	fn default_pending_dir() -> String {
+		"./logs/pending".to_string()
+	}
+
+	fn default_upload_attempts() -> u32 {
+		3
+	}
+}
+
+impl Default for LogUploadConfig {
+	fn default() -> Self {
+		LogUploadConfig {
+			enabled: false,
+			mode: UploadMode::default(),
+			interval_secs: Self::default_interval_secs(),
+			app_id: Self::default_app_id(),
+			s3_bucket: String::new(),
+			batch_file: Self::default_batch_file(),
+			pending_dir: Self::default_pending_dir(),
+			upload_attempts: Self::default_upload_attempts(),
+		}
+	}
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadMode {
+	// Gzipped batches of JSON lines.
+	#[default]
+	Batch,
+	// One object per session.
+	Session,
+	Both,
+}
+
+impl UploadMode {
+	fn batches(self) -> bool {
+		matches!(self, UploadMode::Batch | UploadMode::Both)
+	}
+
+	fn sessions(self) -> bool {
+		matches!(self, UploadMode::Session | UploadMode::Both)
+	}
+}
+
+// What went wrong, and in which stage of the pipeline.
+#[derive(Debug)]
+pub enum PipelineError {
+	Collect(io::Error),
+	Rotate(io::Error),
+	Compress(PathBuf, io::Error),
+	Upload(String),
+}
+
+impl PipelineError {
+	// Label for the error metric.
+	pub fn stage(&self) -> &'static str {
+		match self {
+			PipelineError::Collect(_) => "collect",
+			PipelineError::Rotate(_) => "rotate",
+			PipelineError::Compress(..) => "compress",
+			PipelineError::Upload(_) => "upload",
+		}
+	}
+}
+
+impl fmt::Display for PipelineError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			PipelineError::Collect(e) => write!(f, "failed to append to the batch file: {}", e),
+			PipelineError::Rotate(e) => write!(f, "failed to move the batch file aside: {}", e),
+			PipelineError::Compress(path, e) => write!(f, "failed to compress {}: {}", path.display(), e),
+			PipelineError::Upload(e) => write!(f, "failed to upload: {}", e),
+		}
+	}
+}
+
+impl Error for PipelineError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			PipelineError::Collect(e) | PipelineError::Rotate(e) | PipelineError::Compress(_, e) => Some(e),
+			PipelineError::Upload(_) => None,
+		}
+	}
+}
+
+fn report(error: &PipelineError) {
+	error!("Log pipeline: {}", error);
+	telemetry::record_log_pipeline_error(error.stage());
+}
+
+#[derive(Clone)]
+pub struct LogBatcher {
+	config: LogUploadConfig,
+	// Held while appending to or rotating the batch file, so a session is
+	// never written into a batch that has already been moved aside.
+	batch: Arc<Mutex<()>>,
+	// Held for a round of shipping, so the final one at shutdown doesn't
+	// upload the same files as a round already under way.
+	shipping: Arc<tokio::sync::Mutex<()>>,
+	#[cfg(all(test, feature = "chaos"))]
+	chaos: Chaos,
+}
+
+impl LogBatcher {
+	// Start collecting sessions and shipping them in the background, if enabled.
+	pub fn start(config: &LogUploadConfig) -> Option<LogBatcher> {
+		if !config.enabled {
+			return None;
+		}
+		if config.s3_bucket.is_empty() {
+			error!("[log_upload] is enabled without an s3_bucket, not collecting sessions");
+			return None;
+		}
+		let batcher = LogBatcher::new(config);
+		task::spawn(batcher.clone().run());
+		Some(batcher)
+	}
+
+	fn new(config: &LogUploadConfig) -> LogBatcher {
+		LogBatcher {
+			config: config.clone(),
+			batch: Arc::default(),
+			shipping: Arc::default(),
+			#[cfg(all(test, feature = "chaos"))]
+			chaos: Chaos::new(),
+		}
+	}
+
+	// Inject scripted faults into every upload this batcher makes.
+	#[cfg(all(test, feature = "chaos"))]
+	pub fn with_chaos(mut self, chaos: Chaos) -> LogBatcher {
+		self.chaos = chaos;
+		self
+	}
+
+	pub fn collect(&self, session: &SessionSummary) {
+		let record = record(session);
+		if self.config.mode.sessions() {
+			if let Err(e) = queue_session(session, &record, &self.sessions_dir()) {
+				report(&PipelineError::Collect(e));
+			}
+		}
+		if self.config.mode.batches() {
+			let _batch = self.batch.lock().unwrap_or_else(|e| e.into_inner());
+			if let Err(e) = collect_log(&record.to_string(), &self.config.batch_file) {
+				report(&PipelineError::Collect(e));
+			}
+		}
+	}
+
+	fn sessions_dir(&self) -> PathBuf {
+		Path::new(&self.config.pending_dir).join("sessions")
+	}
+
+	async fn run(self) {
+		let client = Client::new(&aws_config::load_defaults(BehaviorVersion::latest()).await);
+		info!("Uploading session batches to s3://{}/{} every {}s", self.config.s3_bucket, self.config.app_id, self.config.interval_secs);
+		let mut ticks = interval(Duration::from_secs(self.config.interval_secs.max(1)));
+		let mut degraded = false;
+		loop {
+			ticks.tick().await;
+			let healthy = self.ship(&client).await;
+			// Alert once on the way down and once on the way back up, not every round
+			if !healthy && !degraded {
+				error!("Log pipeline degraded, keeping batches in {} until they can be shipped", self.config.pending_dir);
+			} else if healthy && degraded {
+				info!("Log pipeline recovered");
+			}
+			degraded = !healthy;
+		}
+	}
+
+	// A last round at shutdown, so nothing collected is left behind until the
+	// next start.
+	pub async fn flush(&self) {
+		let client = Client::new(&aws_config::load_defaults(BehaviorVersion::latest()).await);
+		if self.ship(&client).await {
+			info!("Shipped the remaining session logs");
+		} else {
+			warn!("Some session logs could not be shipped, they are kept in {} for the next start", self.config.pending_dir);
+		}
+	}
+
+	// One round: move the batch aside, compress and upload everything pending.
+	// Returns whether every stage went through.
+	async fn ship(&self, client: &Client) -> bool {
+		let _shipping = self.shipping.lock().await;
+		let pending_dir = Path::new(&self.config.pending_dir);
+		let rotated = {
+			let _batch = self.batch.lock().unwrap_or_else(|e| e.into_inner());
+			rotate(Path::new(&self.config.batch_file), pending_dir)
+		};
+		let mut errors = compress_pending(pending_dir);
+		if let Err(e) = rotated {
+			errors.push(PipelineError::Rotate(e));
+		}
+
+		// (file, key) for every batch and session object waiting for S3
+		let mut uploads = vec![];
+		match pending(pending_dir, "gz") {
+			Ok(archives) => uploads.extend(archives.into_iter().map(|archive| {
+				let key = format!("{}/{}", self.config.app_id, archive.file_name().unwrap_or_default().to_string_lossy());
+				(archive, key)
+			})),
+			Err(e) => errors.push(PipelineError::Rotate(e)),
+		}
+		let sessions_dir = self.sessions_dir();
+		match pending_sessions(&sessions_dir) {
+			Ok(sessions) => uploads.extend(sessions.into_iter().map(|(object, key)| (object, format!("{}/sessions/{}", self.config.app_id, key)))),
+			Err(e) => errors.push(PipelineError::Rotate(e)),
+		}
+		let mut remaining = uploads.len();
+		for (file, key) in uploads {
+			// S3 is most likely still down for the rest, so leave them for next round
+			if let Err(e) = self.upload(client, &file, &key).await {
+				errors.push(e);
+				break;
+			}
+			if let Err(e) = fs::remove_file(&file) {
+				warn!("Uploaded {} but failed to remove it, it will be uploaded again: {}", file.display(), e);
+			}
+			remaining -= 1;
+		}
+		telemetry::record_pending_log_batches(remaining);
+		remove_empty_dirs(&sessions_dir, OffsetDateTime::now_utc().date());
+
+		errors.iter().for_each(report);
+		errors.is_empty()
+	}
+
+	async fn upload(&self, client: &Client, file: &Path, key: &str) -> Result<(), PipelineError> {
+		let mut attempt = 1;
+		loop {
+			#[cfg(all(test, feature = "chaos"))]
+			let uploaded = match self.chaos.inject().await.map_err(|e| e.to_string()) {
+				Ok(()) => upload_to_s3(client, file, &self.config.s3_bucket, key).await,
+				Err(e) => Err(PipelineError::Upload(e)),
+			};
+			#[cfg(not(all(test, feature = "chaos")))]
+			let uploaded = upload_to_s3(client, file, &self.config.s3_bucket, key).await;
+			match uploaded {
+				Ok(()) => return Ok(()),
+				Err(e) if attempt < self.config.upload_attempts => {
+					warn!("Upload of {} failed (attempt {} of {}): {}", key, attempt, self.config.upload_attempts, e);
+					sleep(RETRY_DELAY * 2u32.pow(attempt - 1)).await;
+					attempt += 1;
+				}
+				Err(e) => return Err(e),
+			}
+		}
+	}
+}
+
+// What is kept of a session: one batch line, or the core of a session object.
+fn record(session: &SessionSummary) -> Value {
+	serde_json::json!({
+		"started_at": session.started_at.format(&Rfc3339).unwrap_or_default(),
+		"service": session.service,
+		"hostname": session.hostname,
+		"actor": session.actor.to_string(),
+		"local": session.local.to_string(),
+		"events": session.events,
+	})
+}
+
+// Where a session's object goes under <app_id>/sessions/. IPv6 addresses keep
+// their colons here; see local_path for the copy on disk.
+fn session_key(session: &SessionSummary) -> String {
+	let day = session.started_at.format(format_description!("[year]-[month]-[day]")).unwrap_or_default();
+	let time = session.started_at.format(format_description!("[hour][minute][second]")).unwrap_or_default();
+	format!("ip={}/dt={}/{}-{:09}-{}.json", session.actor.ip(), day, time, session.started_at.nanosecond(), session.actor.port())
+}
+
+// Where a key is queued under `sessions_dir`. ':' isn't allowed in Windows
+// file names, so an IPv6 address's colons become '-', which no address contains.
+fn local_path(sessions_dir: &Path, key: &str) -> PathBuf {
+	sessions_dir.join(key.replace(':', "-"))
+}
+
+// The session as the actor saw it: `>` what they sent, `<` what came back.
+fn transcript(session: &SessionSummary) -> String {
+	let mut transcript = String::new();
+	for event in session.events {
+		let (prefix, text) = match event {
+			SessionEvent::BannerSent { banner } => ("<", banner.as_str()),
+			SessionEvent::Received { data } => (">", data.as_str()),
+			SessionEvent::Responded { data, .. } => ("<", data.as_str()),
+			_ => continue,
+		};
+		for line in text.trim_end().lines() {
+			let _ = writeln!(transcript, "{} {}", prefix, line.trim_end_matches('\r'));
+		}
+	}
+	transcript
+}
+
+// One entry per payload the actor sent, so payloads can be matched across sessions by hash.
+fn captures(session: &SessionSummary) -> Vec<Value> {
+	session
+		.events
+		.iter()
+		.filter_map(|event| match event {
+			SessionEvent::Received { data } => Some(data),
+			_ => None,
+		})
+		.enumerate()
+		.map(|(index, data)| {
+			let sha256 = digest(&SHA256, data.as_bytes()).as_ref().iter().fold(String::new(), |mut hex, b| {
+				let _ = write!(hex, "{:02x}", b);
+				hex
+			});
+			serde_json::json!({ "payload": index, "bytes": data.len(), "sha256": sha256 })
+		})
+		.collect()
+}
+
+// Write the session's object under `sessions_dir`, laid out as it will be in S3.
+fn queue_session(session: &SessionSummary, record: &Value, sessions_dir: &Path) -> io::Result<PathBuf> {
+	let mut object = record.clone();
+	object["transcript"] = transcript(session).into();
+	object["captures"] = captures(session).into();
+	let path = local_path(sessions_dir, &session_key(session));
+	let dir = path.parent().unwrap_or(sessions_dir);
+	// Written aside first so a half-written object is never uploaded
+	let partial = path.with_extension("json.partial");
+	let contents = object.to_string() + "\n";
+	// A round of shipping may prune the directory between the two steps
+	let mut attempts = 1;
 	loop {
-		let log_file = "logs/batch.log";
-		let compressed_file = "logs/batch.gz";
-		
-		// Collect some sample logs (replace with actual log collection in production)
-		log_collector::collect_log("This is a sample log", log_file);
-		
-		// Compress logs
-		compressor::compress_logs(log_file, compressed_file).unwrap();
-		
-		// Generate a unique filename
-		let s3_key = format!("{}/{}", app_id, "batch.gz");
-		
-		// Upload compressed file
-		if let Err(e) = uploader::upload_to_s3(compressed_file, s3_bucket, &s3_key).await {
-			eprintln!("Failed to upload batch: {}", e);
-		}
-		
-		// Wait until the next interval
-		sleep(interval).await;
-	}
-}
\ No newline at end of file
+		match fs::create_dir_all(dir).and_then(|_| fs::write(&partial, &contents)) {
+			Err(e) if e.kind() == io::ErrorKind::NotFound && attempts < 3 => attempts += 1,
+			written => break written?,
+		}
+	}
+	fs::rename(&partial, &path)?;
+	Ok(path)
+}
+
+// Session objects waiting under `sessions_dir`, with their S3 keys.
+fn pending_sessions(sessions_dir: &Path) -> io::Result<Vec<(PathBuf, String)>> {
+	let name = |path: &Path| path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+	let mut objects = vec![];
+	for ip in pending_dirs(sessions_dir)? {
+		for day in pending_dirs(&ip)? {
+			for object in pending(&day, "json")? {
+				let key = format!("{}/{}/{}", name(&ip).replace('-', ":"), name(&day), name(&object));
+				objects.push((object, key));
+			}
+		}
+	}
+	Ok(objects)
+}
+
+fn pending_dirs(dir: &Path) -> io::Result<Vec<PathBuf>> {
+	let entries = match fs::read_dir(dir) {
+		Ok(entries) => entries,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+		Err(e) => return Err(e),
+	};
+	let mut dirs = entries
+		.map(|entry| entry.map(|e| e.path()))
+		.filter(|path| path.as_ref().map_or(true, |p| p.is_dir()))
+		.collect::<io::Result<Vec<_>>>()?;
+	dirs.sort();
+	Ok(dirs)
+}
+
+// Clear out the per-IP directories and the per-day ones before `today` that
+// uploads have emptied. Today's are still being written to.
+fn remove_empty_dirs(sessions_dir: &Path, today: Date) {
+	let today = format!("dt={}", today.format(format_description!("[year]-[month]-[day]")).unwrap_or_default());
+	for ip in pending_dirs(sessions_dir).unwrap_or_default() {
+		for day in pending_dirs(&ip).unwrap_or_default() {
+			if day.file_name().is_some_and(|name| *name.to_string_lossy() < *today) {
+				// Fails while anything is left in it
+				let _ = fs::remove_dir(day);
+			}
+		}
+		let _ = fs::remove_dir(ip);
+	}
+}
+
+// Move the batch file into `pending_dir` under a timestamped name, unless there is nothing in it.
+fn rotate(batch_file: &Path, pending_dir: &Path) -> io::Result<Option<PathBuf>> {
+	match fs::metadata(batch_file) {
+		Ok(metadata) if metadata.len() > 0 => {}
+		Ok(_) => return Ok(None),
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+		Err(e) => return Err(e),
+	}
+	fs::create_dir_all(pending_dir)?;
+	let now = OffsetDateTime::now_utc();
+	let stamp = now
+		.format(format_description!("[year][month][day]T[hour][minute][second]Z"))
+		.map_err(io::Error::other)?;
+	let rotated = pending_dir.join(format!("batch-{}-{:09}.log", stamp, now.nanosecond()));
+	fs::rename(batch_file, &rotated)?;
+	Ok(Some(rotated))
+}
+
+// Compress every pending batch, removing the original once its archive is in place.
+fn compress_pending(pending_dir: &Path) -> Vec<PipelineError> {
+	let batches = match pending(pending_dir, "log") {
+		Ok(batches) => batches,
+		Err(e) => return vec![PipelineError::Rotate(e)],
+	};
+	batches
+		.into_iter()
+		.filter_map(|batch| {
+			let archive = batch.with_extension("log.gz");
+			compress_logs(&batch, &archive)
+				.and_then(|()| fs::remove_file(&batch))
+				.err()
+				.map(|e| PipelineError::Compress(batch, e))
+		})
+		.collect()
+}
+
+// Files in `dir` with the given extension, oldest first.
+fn pending(dir: &Path, extension: &str) -> io::Result<Vec<PathBuf>> {
+	let entries = match fs::read_dir(dir) {
+		Ok(entries) => entries,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+		Err(e) => return Err(e),
+	};
+	let mut files = entries
+		.map(|entry| entry.map(|e| e.path()))
+		.filter(|path| path.as_ref().map_or(true, |p| p.extension().is_some_and(|e| e == extension)))
+		.collect::<io::Result<Vec<_>>>()?;
+	files.sort();
+	Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use flate2::read::GzDecoder;
+	use std::io::Read;
+
+	#[test]
+	fn batches_are_rotated_and_compressed_while_collection_continues() {
+		let dir = std::env::temp_dir().join(format!("rustbucket-batches-{}", std::process::id()));
+		let batch_file = dir.join("batch.log");
+		let pending_dir = dir.join("pending");
+		fs::create_dir_all(&dir).unwrap();
+		let batch = batch_file.to_str().unwrap();
+
+		collect_log("first", batch).unwrap();
+		collect_log("second", batch).unwrap();
+		let rotated = rotate(&batch_file, &pending_dir).unwrap().unwrap();
+		// Nothing new to rotate yet
+		assert_eq!(rotate(&batch_file, &pending_dir).unwrap(), None);
+		collect_log("third", batch).unwrap();
+
+		assert!(compress_pending(&pending_dir).is_empty());
+		assert!(!rotated.exists());
+		let archives = pending(&pending_dir, "gz").unwrap();
+		assert_eq!(archives, vec![rotated.with_extension("log.gz")]);
+		let mut contents = String::new();
+		GzDecoder::new(fs::File::open(&archives[0]).unwrap()).read_to_string(&mut contents).unwrap();
+		assert_eq!(contents, "first\nsecond\n");
+		assert_eq!(fs::read_to_string(&batch_file).unwrap(), "third\n");
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn sessions_are_queued_as_objects_under_ip_and_day() {
+		let dir = std::env::temp_dir().join(format!("rustbucket-sessions-{}", std::process::id()));
+		let config = LogUploadConfig {
+			mode: UploadMode::Session,
+			batch_file: dir.join("batch.log").to_string_lossy().to_string(),
+			pending_dir: dir.join("pending").to_string_lossy().to_string(),
+			..LogUploadConfig::default()
+		};
+		let batcher = LogBatcher::new(&config);
+		let events = [
+			SessionEvent::BannerSent { banner: "220 mail ESMTP".to_string() },
+			SessionEvent::Received { data: "EHLO x\r\n".to_string() },
+			SessionEvent::Responded { data: "250 mail\r\n".to_string(), source: crate::events::ResponseSource::Static },
+			SessionEvent::Closed,
+		];
+		let session = SessionSummary {
+			service: "smtp",
+			hostname: "mail",
+			actor: "203.0.113.7:40112".parse().unwrap(),
+			local: "192.0.2.1:25".parse().unwrap(),
+			started_at: time::macros::datetime!(2024-05-06 07:08:09.5 UTC),
+			events: &events,
+		};
+		batcher.collect(&session);
+
+		// Batches are left alone in session mode
+		assert!(!dir.join("batch.log").exists());
+		let objects = pending_sessions(&batcher.sessions_dir()).unwrap();
+		assert_eq!(objects.len(), 1);
+		assert_eq!(objects[0].1, "ip=203.0.113.7/dt=2024-05-06/070809-500000000-40112.json");
+		let object: Value = serde_json::from_str(&fs::read_to_string(&objects[0].0).unwrap()).unwrap();
+		assert_eq!(object["service"], "smtp");
+		assert_eq!(object["transcript"], "< 220 mail ESMTP\n> EHLO x\n< 250 mail\n");
+		assert_eq!(object["captures"][0]["bytes"], 8);
+		assert_eq!(object["events"].as_array().unwrap().len(), 4);
+
+		// IPv6 keeps its colons in the key but not on disk
+		batcher.collect(&SessionSummary { actor: "[2001:db8::1]:40113".parse().unwrap(), ..session });
+		let objects = pending_sessions(&batcher.sessions_dir()).unwrap();
+		assert_eq!(objects.len(), 2);
+		assert_eq!(objects[0].1, "ip=2001:db8::1/dt=2024-05-06/070809-500000000-40113.json");
+		assert!(objects[0].0.starts_with(batcher.sessions_dir().join("ip=2001-db8--1")));
+		assert!(!objects[0].0.to_string_lossy().contains(':'));
+
+		// The day's directory is kept while it is still today there
+		for (path, _) in &objects {
+			fs::remove_file(path).unwrap();
+		}
+		remove_empty_dirs(&batcher.sessions_dir(), time::macros::date!(2024 - 05 - 06));
+		assert!(objects[0].0.parent().unwrap().exists());
+		remove_empty_dirs(&batcher.sessions_dir(), time::macros::date!(2024 - 05 - 07));
+		assert_eq!(fs::read_dir(batcher.sessions_dir()).unwrap().count(), 0);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	// An S3 outage leaves the batch pending, and the next healthy round ships it.
+	#[cfg(feature = "chaos")]
+	#[tokio::test]
+	async fn batches_survive_upload_faults() {
+		use crate::chaos::Fault;
+		use crate::mock_http::{MockHttpServer, MockResponse};
+		use aws_sdk_s3::config::{Credentials, Region};
+
+		let dir = std::env::temp_dir().join(format!("rustbucket-chaos-{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let config = LogUploadConfig {
+			s3_bucket: "honeypot-logs".to_string(),
+			batch_file: dir.join("batch.log").to_string_lossy().to_string(),
+			pending_dir: dir.join("pending").to_string_lossy().to_string(),
+			upload_attempts: 1,
+			..LogUploadConfig::default()
+		};
+		let chaos = Chaos::new();
+		chaos.schedule([Fault::RateLimited, Fault::ConnectionReset]);
+		let batcher = LogBatcher::new(&config).with_chaos(chaos);
+		let server = MockHttpServer::start().await;
+		let s3 = aws_sdk_s3::Config::builder()
+			.behavior_version(BehaviorVersion::latest())
+			.region(Region::new("us-east-1"))
+			.credentials_provider(Credentials::new("test", "test", None, None, "test"))
+			.endpoint_url(server.base_url())
+			.force_path_style(true)
+			.build();
+		let client = Client::from_conf(s3);
+
+		let events = [SessionEvent::Received { data: "USER root\r\n".to_string() }, SessionEvent::Closed];
+		batcher.collect(&SessionSummary {
+			service: "ftp",
+			hostname: "ftp",
+			actor: "203.0.113.7:40112".parse().unwrap(),
+			local: "192.0.2.1:21".parse().unwrap(),
+			started_at: OffsetDateTime::now_utc(),
+			events: &events,
+		});
+
+		for _ in 0..2 {
+			assert!(!batcher.ship(&client).await);
+			assert_eq!(pending(Path::new(&config.pending_dir), "gz").unwrap().len(), 1);
+		}
+		assert!(server.requests().is_empty());
+
+		server.respond_with(MockResponse::new(200, ""));
+		assert!(batcher.ship(&client).await);
+		assert!(pending(Path::new(&config.pending_dir), "gz").unwrap().is_empty());
+		let requests = server.requests();
+		assert_eq!(requests.len(), 1);
+		assert_eq!(requests[0].method, "PUT");
+		assert!(requests[0].path.starts_with("/honeypot-logs/rustbucket/batch-"), "{}", requests[0].path);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -1,12 +1,11 @@
 use std::fs::OpenOptions;
-use std::io::Write;
+use std::io::{self, Write};
 
-pub fn collect_log(log_message: &str, log_file: &str) {
+pub fn collect_log(log_message: &str, log_file: &str) -> io::Result<()> {
 	let mut file = OpenOptions::new()
 		.create(true)
 		.append(true)
-		.open(log_file)
-		.unwrap();
+		.open(log_file)?;
 	
-	writeln!(file, "{}", log_message).unwrap();
+	writeln!(file, "{}", log_message)
 }
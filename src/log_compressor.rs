@@ -1,12 +1,16 @@
 use flate2::write::GzEncoder;
 use flate2::Compression;
-use std::fs::File;
-use std::io::{self, Read};
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
 
-pub fn compress_logs(input_file: &str, output_file: &str) -> io::Result<()> {
-	let input = File::open(input_file)?;
-	let mut encoder = GzEncoder::new(File::create(output_file)?, Compression::default());
-	io::copy(&mut input.take(10_000_000), &mut encoder)?;
-	encoder.finish()?;
-	Ok(())
+// The archive is written beside its final name and renamed into place, so a
+// crash part way through never leaves a truncated one behind to be uploaded.
+pub fn compress_logs(input_file: &Path, output_file: &Path) -> io::Result<()> {
+	let partial = output_file.with_extension("partial");
+	let mut input = File::open(input_file)?;
+	let mut encoder = GzEncoder::new(File::create(&partial)?, Compression::default());
+	io::copy(&mut input, &mut encoder)?;
+	encoder.finish()?.sync_all()?;
+	fs::rename(partial, output_file)
 }
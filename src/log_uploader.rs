@@ -1,29 +1,23 @@
-use aws_sdk_s3::{Client, Error};
+use aws_sdk_s3::error::DisplayErrorContext;
 use aws_sdk_s3::primitives::ByteStream;
-use aws_config;
+use aws_sdk_s3::Client;
 use std::path::Path;
-use std::convert::Infallible;
-use aws_smithy_types::error::Error as SmithyError;
-use tracing::error;
+use crate::log_batcher::PipelineError;
+use crate::prelude::*;
 
-pub async fn upload_to_s3(file_path: &str, bucket: &str, key: &str) -> Result<(), Error> {
-	let shared_config = aws_config::load_from_env().await;
-	let client = Client::new(&shared_config);
-	
-	let body = ByteStream::from_path(Path::new(file_path))
+pub async fn upload_to_s3(client: &Client, file_path: &Path, bucket: &str, key: &str) -> Result<(), PipelineError> {
+	let body = ByteStream::from_path(file_path)
 		.await
-		.map_err(|e| {
-			error!("Failed to create ByteStream from file path: {}", file_path);
-			Error::Unhandled(SmithyError::new(e.to_string()))
-		})?;
+		.map_err(|e| PipelineError::Upload(format!("failed to read {}: {}", file_path.display(), e)))?;
 	
 	client.put_object()
 		.bucket(bucket)
 		.key(key)
 		.body(body)
 		.send()
-		.await?;
+		.await
+		.map_err(|e| PipelineError::Upload(DisplayErrorContext(e).to_string()))?;
 	
-	println!("File uploaded to S3: {}", key);
+	info!("Uploaded {} to s3://{}/{}", file_path.display(), bucket, key);
 	Ok(())
 }
@@ -1,66 +1,178 @@
+mod acme;
+mod admin;
+mod auth_log;
+mod blocklist;
+mod crowdsec;
 mod handler;
+mod bench;
 mod prelude;
 mod chatgpt;
+mod chat_service;
+#[cfg(all(test, feature = "chaos"))]
+mod chaos;
+mod config;
+mod countermeasures;
+mod der;
+mod events;
+mod exposure;
+mod fingerprint;
+mod flow_export;
+mod git;
+mod http_client;
+mod http1;
+mod http2;
+mod kerberos;
 mod log_collector;
 mod log_compressor;
 mod log_uploader;
 mod log_batcher;
+#[cfg(test)]
+mod mock_http;
+mod payload_fetch;
+mod plugins;
+mod protocol;
+mod recording;
+mod rsync;
+mod scripting;
+mod shutdown;
+mod simulate;
+mod sinks;
+mod telemetry;
+mod tls;
+mod updater;
+#[cfg(windows)]
+mod windows_service;
+mod zeek;
 
 use crate::prelude::*;
+use std::future::Future;
+use std::time::Duration;
 use tracing::{info, error};
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::{fmt, reload, EnvFilter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 use tracing_appender::rolling;
-use handler::handle_client;
 use chatgpt::ChatGPT;
+use chat_service::ChatService;
+use config::{AppConfig, Persona};
+use protocol::{Registry, Session};
+use recording::SessionFixture;
+use shutdown::Shutdown;
+use sinks::{SessionSinks, SessionSummary};
+use time::OffsetDateTime;
+use std::sync::Arc;
+use tls::{SessionStream, TlsListener};
+use tokio::time::timeout;
 
 
 
-async fn start_listener(addr: &str) -> tokio::io::Result<()> {
-    let listener = TcpListener::bind(addr).await?;    
+async fn start_listener<C: ChatService>(addr: String, persona: Persona, app_config: AppConfig, chatgpt: C, shutdown: Shutdown, sinks: SessionSinks, tls: Option<TlsListener<C>>) -> tokio::io::Result<()> {
+    let listener = TcpListener::bind(&addr).await?;    
     // Retrieve the actual address and port the listener is bound to
     let listener_addr = listener.local_addr()?;
-    println!("Listening on {}", listener_addr);
-    // Instantiate ChatGPT
-    let chatgpt = ChatGPT::new().unwrap();
+    println!("Listening on {} as persona {}", listener_addr, persona.name);
+    let handler = Registry::from_config(&app_config).resolve(&app_config, listener_addr.port());
     
     loop {
-        match listener.accept().await {
+        // Stop accepting as soon as shutdown is requested; sessions already
+        // running are left to finish on their own.
+        let accepted = tokio::select! {
+            _ = shutdown.requested() => {
+                info!("Listener on {} stopped accepting connections", listener_addr);
+                return Ok(());
+            }
+            accepted = listener.accept() => accepted,
+        };
+        match accepted {
             Ok((stream, client_addr)) => {
                 let port = client_addr.port();
                 println!("New connection on {}: {}", client_addr, client_addr);
+                let Some(handler) = handler.clone() else {
+                    // We know our Security Groups are misconfigured if we hit this message.
+                    // Open Security Groups should map 1:1 with the services we can emulate.
+                    error!("Actor connected to an unexpected port.");
+                    println!("Unexpected port: {}", port);
+                    continue;
+                };
                 // Spawn a new task to handle the connection asynchronously
-                let chatgpt = chatgpt.clone();
+                let mut chatgpt = chatgpt.clone();
+                info!("Persona {} accepted connection from {}", persona.name, client_addr);
+                let port_config = app_config.ports.find(listener_addr.port()).cloned();
+                let mut service = port_config.clone().map(|s| persona.customize(s));
+                let mut interaction = persona.interaction(&app_config.general);
+                let limits = app_config.limits;
+                let record_sessions_dir = app_config.general.record_sessions_dir.clone();
+                let general = app_config.general.clone();
+                let sinks = sinks.clone();
+                let mut hostname = persona.hostname.clone().unwrap_or_else(|| persona.name.clone());
+                let local_addr = stream.local_addr().unwrap_or(listener_addr);
+                let tls = tls.clone();
+                let mut negotiated_h2 = false;
+                let session = shutdown.track_session();
+                let stream = shutdown.closing(stream);
                 task::spawn(async move {
-                    match listener_addr.port() {                        
-                        25 => {
-                            // Handle connection for port 25
-                            info!("Actor attempted to connect to port 25 - SMTP");
-                            //@todo: Implement a more realistic SMTP response and don't send this message to ChatGPT
-                            let message = "220 mail.example.com ESMTP Postfix (Ubuntu)".to_string();
-                            info!("Actor input message: {}", message);
-                            handle_client(stream, message, &chatgpt).await;
-                        }
-                        80 => {
-                            // Handle connection for port 80
-                            info!("Actor attempted to connect to port 80 - HTTP");
-                            //@todo: Implement a more realistic HTTP response and don't send this message to ChatGPT
-                            let message = "GET / HTTP/1.1\nHost: example.com".to_string();
-                            info!("Actor input message: {}", message);
-                            handle_client(stream, message, &chatgpt).await;
-                        }
-                        21 => {
-                            // Handle connection for port 21
-                            info!("Actor attempted to connect to port 21 - FTP");
-                            //@todo: Implement a more realistic FTP response and don't send this message to ChatGPT
-                            let message = "220 (vsFTPd 3.0.3)".to_string();
-                            info!("Actor input message: {}", message);
-                            handle_client(stream, message, &chatgpt).await;
-                        }
-                        _ => {
-                            // We know our Security Groups are misconfigured if we hit this message.
-                            // Open Security Groups should map 1:1 with the ports in this match statement.
-                            error!("Actor connected to an unexpected port.");
-                            println!("Unexpected port: {}", port);
+                    let _session = session;
+                    let started_at = OffsetDateTime::now_utc();
+                    
+                    // TLS services finish the handshake before the actor sees anything
+                    let stream: Box<dyn SessionStream> = match tls {
+                        Some(tls) => match timeout(tls::HANDSHAKE_TIMEOUT, tls.acceptor.accept(stream)).await {
+                            Ok(Ok(stream)) => {
+                                // The SNI hostname shows which targets actors are hunting for
+                                let server_name = stream.get_ref().1.server_name();
+                                info!("TLS session from {} asked for {}", client_addr, server_name.unwrap_or("no server name"));
+                                // Another persona's server name is served as that persona
+                                if let Some((virtual_host, virtual_chat)) = tls.virtual_host(server_name) {
+                                    info!("Serving {} as persona {} by SNI", client_addr, virtual_host.name);
+                                    service = port_config.map(|s| virtual_host.customize(s));
+                                    interaction = virtual_host.interaction(&general);
+                                    hostname = virtual_host.hostname.clone().unwrap_or_else(|| virtual_host.name.clone());
+                                    chatgpt = virtual_chat.clone();
+                                }
+                                negotiated_h2 = stream.get_ref().1.alpn_protocol() == Some(http2::ALPN_H2);
+                                Box::new(stream)
+                            }
+                            Ok(Err(e)) => {
+                                info!("TLS handshake with {} failed: {}", client_addr, e);
+                                return;
+                            }
+                            Err(_) => {
+                                info!("TLS handshake with {} timed out", client_addr);
+                                return;
+                            }
+                        },
+                        None => Box::new(stream),
+                    };
+                    info!("Actor attempted to connect to port {} - {}", listener_addr.port(), handler.name());
+                    let events = handler.handle(Session {
+                        stream,
+                        service: service.clone(),
+                        interaction,
+                        limits,
+                        chatgpt: &chatgpt,
+                        negotiated_h2,
+                    }).await;
+                    
+                    sinks.session_finished(SessionSummary {
+                        service: service.as_ref().map_or(handler.name(), |s| s.name.as_str()),
+                        hostname: &hostname,
+                        actor: client_addr,
+                        local: local_addr,
+                        started_at,
+                        events: &events,
+                    }).await;
+                    
+                    // Keep the session around as a regression fixture if asked to
+                    if let Some(dir) = record_sessions_dir {
+                        let fixture = SessionFixture {
+                            port: listener_addr.port(),
+                            service,
+                            interaction,
+                            events,
+                        };
+                        match fixture.save(&dir, client_addr) {
+                            Ok(path) => info!("Recorded session to {}", path.display()),
+                            Err(e) => error!("Failed to record session: {}", e),
                         }
                     }
                 });
@@ -72,38 +184,150 @@ async fn start_listener(addr: &str) -> tokio::io::Result<()> {
     }
 }
 
-#[tokio::main]
-async fn main() -> tokio::io::Result<()> {
+// Create tasks for each persona's listeners on different addresses and ports
+fn spawn_listeners<C, F>(app_config: &AppConfig, shutdown: &Shutdown, sinks: &SessionSinks, certificates: &tls::Certificates, make_chat: F) -> Vec<task::JoinHandle<()>>
+where
+    C: ChatService,
+    F: Fn(&Persona, u16) -> C,
+{
+    let mut handles = vec![];
+    
+    let personas = app_config.personas();
+    for persona in &personas {
+        for port in persona.ports.clone() {
+            let service = app_config.ports.find(port);
+            let wants_tls = service.is_some_and(|s| s.tls);
+            // One certificate per persona, shared by all of its TLS services
+            let acceptor = certificates.acceptor(&persona.name, service.is_some_and(|s| s.is_web()));
+            let tls = match (acceptor, wants_tls) {
+                (Some(acceptor), true) => {
+                    // Other personas reachable on this port by SNI, each with its own prompts and model
+                    let virtual_hosts = personas
+                        .iter()
+                        .filter(|v| v.name != persona.name)
+                        .flat_map(|v| v.server_names.iter().map(move |name| (name.to_ascii_lowercase(), v)))
+                        .map(|(name, v)| (name, (v.clone(), make_chat(v, port))))
+                        .collect();
+                    Some(TlsListener { acceptor, virtual_hosts: Arc::new(virtual_hosts) })
+                }
+                (None, true) => {
+                    error!("Not starting TLS listener on port {} for persona {} without a certificate", port, persona.name);
+                    continue;
+                }
+                (_, false) => None,
+            };
+            let addr = persona.address(port);
+            let chatgpt = make_chat(persona, port);
+            let persona = persona.clone();
+            let app_config = app_config.clone();
+            let shutdown = shutdown.clone();
+            let sinks = sinks.clone();
+            let handle = tokio::spawn(async move {
+                start_listener(addr, persona, app_config, chatgpt, shutdown, sinks, tls).await.unwrap();
+            });
+            handles.push(handle);
+        }
+    }
+    
+    handles
+}
+
+fn main() -> tokio::io::Result<()> {
+    // `rustbucket service install|uninstall|run` manages the Windows service
+    #[cfg(windows)]
+    if std::env::args().nth(1).as_deref() == Some("service") {
+        return windows_service::main(&std::env::args().skip(2).collect::<Vec<_>>());
+    }
+    tokio::runtime::Runtime::new()?.block_on(run(shutdown::wait_for_signal()))
+}
+
+// Run the honeypot until `stop` resolves, then shut down gracefully.
+async fn run(stop: impl Future<Output = ()>) -> tokio::io::Result<()> {
     // Set up rolling logs
     let file_appender = rolling::daily("logs", "rustbucket.log");
     let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
     
-    // Initialize tracing subscriber
-    let subscriber = tracing_subscriber::fmt::Subscriber::builder()
-        .with_env_filter(EnvFilter::new("info"))
-        .with_writer(non_blocking)
-        .with_ansi(false)
-        .init();
+    // Initialize tracing subscriber behind a reload layer so the filter can be
+    // changed at runtime from the admin socket
+    let (log_filter_layer, log_filter) = reload::Layer::new(EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::registry()
+        .with(log_filter_layer)
+        .with(fmt::layer().with_writer(non_blocking).with_ansi(false));
+    #[cfg(windows)]
+    let subscriber = subscriber.with(windows_service::EventLog::open());
+    subscriber.init();
     info!("Tracing initialized");
     
-    // Create tasks for each listener on different ports
-    let ports = vec!["0.0.0.0:25", "0.0.0.0:23", "0.0.0.0:21", "0.0.0.0:80"];
+    // Load configuration once so every listener shares the same instance fingerprint
+    let app_config = AppConfig::new().unwrap();
+    telemetry::install(&app_config.metrics).unwrap();
+    scripting::install(&app_config.scripting).unwrap();
+    countermeasures::install(&app_config.countermeasures);
+    payload_fetch::install(&app_config.payload_fetch, &app_config.http);
+    git::install(&app_config.git);
+    updater::log_updated_from();
+    if let Err(e) = admin::set_log_filter(&log_filter, &app_config.general.log_level) {
+        error!("Invalid log_level {:?}, keeping \"info\": {}", app_config.general.log_level, e);
+    }
+    admin::start(&app_config.admin, log_filter).await.unwrap();
     
-    let mut handles = vec![];
+    // `rustbucket simulate <scenario file>` runs scripted attacks against the
+    // real listeners instead of waiting for the internet to show up.
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("simulate") {
+        let scenario_file = args.get(2).map(String::as_str).unwrap_or("scenarios.toml");
+        simulate::run(scenario_file, app_config).await;
+        drop(_guard);
+        return Ok(());
+    }
     
-    for port in ports {
-        let handle = tokio::spawn(async move {
-            start_listener(port).await.unwrap();
-        });
-        handles.push(handle);
+    // `rustbucket bench` load tests the listeners with many concurrent connections
+    if args.get(1).map(String::as_str) == Some("bench") {
+        bench::run(&args[2..], app_config).await;
+        drop(_guard);
+        return Ok(());
     }
     
-    // Wait for all listeners to finish (this will run indefinitely)
+    // One HTTP client for the whole process so every listener shares its connection pool
+    let http_client = http_client::build_client(&app_config.http).unwrap();
+    
+    // Instantiate ChatGPT per listener, using the persona's own prompts and the port's model
+    let shutdown = Shutdown::new();
+    let sinks = SessionSinks::open(&app_config, &http_client);
+    let certificates = tls::Certificates::load(&app_config, Some(&http_client));
+    let listening = app_config.personas().iter().flat_map(|persona| persona.ports.clone()).collect();
+    exposure::Exposure::start(&app_config.exposure, http_client.clone(), listening);
+    let handles = spawn_listeners(&app_config, &shutdown, &sinks, &certificates, |persona, port| {
+        let mut chatgpt = ChatGPT::new().unwrap().with_client(http_client.clone());
+        if let Some(static_messages) = persona.static_messages.clone() {
+            chatgpt = chatgpt.with_static_messages(static_messages);
+        }
+        chatgpt.with_model(app_config.llm.model_for(port))
+    });
+    
+    // Run until asked to stop or a new release is installed, then drain: stop
+    // accepting, give in-flight sessions the grace period to finish (and
+    // record their fixtures).
+    let installed = tokio::select! {
+        _ = stop => None,
+        installed = updater::installed(&app_config.update, &http_client) => Some(installed),
+    };
+    info!("Shutdown requested, draining {} active sessions", shutdown.active_sessions());
+    shutdown.trigger();
     for handle in handles {
-        handle.await.unwrap();
+        let _ = handle.await;
     }
+    let grace = Duration::from_secs(app_config.general.shutdown_grace_secs);
+    let remaining = shutdown.drain(grace).await;
+    if remaining > 0 {
+        warn!("Grace period of {:?} expired with {} sessions still active", grace, remaining);
+    }
+    sinks.flush().await;
     
     // Flush logs before shutdown
     drop(_guard);
-    Ok(())
+    match installed {
+        Some(installed) => Err(updater::restart(&installed)),
+        None => Ok(()),
+    }
 }
@@ -0,0 +1,166 @@
+// A tiny HTTP server on loopback standing in for the APIs rustbucket talks
+// to (OpenAI chat completions, S3, ACME, CrowdSec, release manifests), so
+// tests can exercise the real clients end to end. Responses are programmed
+// up front and every request is recorded for assertions.
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpStream;
+use crate::prelude::*;
+
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+	pub status: u16,
+	pub body: String,
+	pub headers: Vec<(String, String)>,
+}
+
+impl MockResponse {
+	pub fn new(status: u16, body: &str) -> MockResponse {
+		MockResponse { status, body: body.to_string(), headers: vec![] }
+	}
+
+	pub fn with_header(mut self, name: &str, value: &str) -> MockResponse {
+		self.headers.push((name.to_string(), value.to_string()));
+		self
+	}
+
+	// A successful completion whose first choice says `content`.
+	pub fn completion(content: &str) -> MockResponse {
+		let body = json!({
+			"id": "chatcmpl-mock",
+			"object": "chat.completion",
+			"choices": [{
+				"index": 0,
+				"message": { "role": "assistant", "content": content },
+				"finish_reason": "stop"
+			}],
+			"usage": { "prompt_tokens": 0, "completion_tokens": 0, "total_tokens": 0 }
+		});
+		MockResponse::new(200, &body.to_string())
+	}
+
+	// An API error such as 401 (bad key) or 429 (rate limited).
+	pub fn error(status: u16, message: &str) -> MockResponse {
+		let body = json!({ "error": { "message": message, "type": "mock_error" } });
+		MockResponse::new(status, &body.to_string())
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+	pub method: String,
+	pub path: String,
+	pub headers: HashMap<String, String>,
+	pub body: Value,
+}
+
+pub struct MockHttpServer {
+	base_url: String,
+	responses: Arc<Mutex<VecDeque<MockResponse>>>,
+	requests: Arc<Mutex<Vec<RecordedRequest>>>,
+	server: task::JoinHandle<()>,
+}
+
+impl MockHttpServer {
+	pub async fn start() -> MockHttpServer {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let base_url = format!("http://{}", listener.local_addr().unwrap());
+		let responses: Arc<Mutex<VecDeque<MockResponse>>> = Arc::default();
+		let requests: Arc<Mutex<Vec<RecordedRequest>>> = Arc::default();
+
+		let server = {
+			let responses = responses.clone();
+			let requests = requests.clone();
+			task::spawn(async move {
+				while let Ok((stream, _)) = listener.accept().await {
+					let responses = responses.clone();
+					let requests = requests.clone();
+					task::spawn(async move {
+						serve(stream, responses, requests).await;
+					});
+				}
+			})
+		};
+
+		MockHttpServer { base_url, responses, requests, server }
+	}
+
+	pub fn base_url(&self) -> String {
+		self.base_url.clone()
+	}
+
+	// Where the ChatGPT client posts, with `MockResponse::completion` replies.
+	pub fn completions_url(&self) -> String {
+		format!("{}/v1/chat/completions", self.base_url)
+	}
+
+	// Queue a response. Requests beyond the queue get a 500.
+	pub fn respond_with(&self, response: MockResponse) {
+		self.responses.lock().unwrap().push_back(response);
+	}
+
+	pub fn requests(&self) -> Vec<RecordedRequest> {
+		self.requests.lock().unwrap().clone()
+	}
+}
+
+impl Drop for MockHttpServer {
+	fn drop(&mut self) {
+		self.server.abort();
+	}
+}
+
+// Handle one HTTP/1.1 request and close the connection.
+async fn serve(mut stream: TcpStream, responses: Arc<Mutex<VecDeque<MockResponse>>>, requests: Arc<Mutex<Vec<RecordedRequest>>>) {
+	let mut data = Vec::new();
+	let mut buffer = [0; 4096];
+	let (head_len, content_length) = loop {
+		match stream.read(&mut buffer).await {
+			Ok(0) | Err(_) => return,
+			Ok(n) => data.extend_from_slice(&buffer[..n]),
+		}
+		if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+			let head = String::from_utf8_lossy(&data[..end]).to_lowercase();
+			let content_length = head
+				.lines()
+				.find_map(|line| line.strip_prefix("content-length:"))
+				.and_then(|v| v.trim().parse::<usize>().ok())
+				.unwrap_or(0);
+			break (end + 4, content_length);
+		}
+	};
+	while data.len() < head_len + content_length {
+		match stream.read(&mut buffer).await {
+			Ok(0) | Err(_) => return,
+			Ok(n) => data.extend_from_slice(&buffer[..n]),
+		}
+	}
+
+	let head = String::from_utf8_lossy(&data[..head_len]).to_string();
+	let mut lines = head.lines();
+	let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+	let method = request_line.next().unwrap_or_default().to_string();
+	let path = request_line.next().unwrap_or_default().to_string();
+	let headers = lines
+		.filter_map(|line| line.split_once(':'))
+		.map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+		.collect();
+	let body = serde_json::from_slice(&data[head_len..head_len + content_length]).unwrap_or(Value::Null);
+	requests.lock().unwrap().push(RecordedRequest { method, path, headers, body });
+
+	let response = responses
+		.lock()
+		.unwrap()
+		.pop_front()
+		.unwrap_or_else(|| MockResponse::error(500, "no mock response queued"));
+	let headers: String = response.headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect();
+	let reply = format!(
+		"HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+		response.status,
+		headers,
+		response.body.len(),
+		response.body
+	);
+	let _ = stream.write_all(reply.as_bytes()).await;
+}
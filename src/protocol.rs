@@ -0,0 +1,190 @@
+// What speaks to an actor once a listener has accepted them. Each service in
+// [ports] maps to a ProtocolHandler by its name, so moving a service to
+// another port keeps its emulation, and a plugin configured for a port takes
+// over from whatever is registered for its service.
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use crate::chat_service::ChatService;
+use crate::config::{AppConfig, Interaction, LimitsConfig, PortConfig};
+use crate::events::SessionEvent;
+use crate::kerberos::{self, KerberosConfig};
+use crate::plugins::{self, Plugin};
+use crate::rsync::{self, RsyncConfig};
+use crate::tls::SessionStream;
+use crate::{git, handler, http1, http2};
+
+pub type SessionFuture<'a> = Pin<Box<dyn Future<Output = Vec<SessionEvent>> + Send + 'a>>;
+
+// One accepted connection, past its TLS handshake if the service has one.
+pub struct Session<'a, C> {
+	pub stream: Box<dyn SessionStream>,
+	pub service: Option<PortConfig>,
+	pub interaction: Interaction,
+	pub limits: LimitsConfig,
+	pub chatgpt: &'a C,
+	// ALPN settled on HTTP/2 during the handshake.
+	pub negotiated_h2: bool,
+}
+
+pub trait ProtocolHandler<C: ChatService>: Send + Sync {
+	// What the actor is talking to, for the logs.
+	fn name(&self) -> &str;
+
+	// Talk to the actor until they disconnect, returning everything that happened.
+	fn handle<'a>(&'a self, session: Session<'a, C>) -> SessionFuture<'a>;
+}
+
+// Banner, then one answer per read. Good enough for line-based protocols
+// until they get an emulation of their own.
+struct LineBased(&'static str);
+
+impl<C: ChatService> ProtocolHandler<C> for LineBased {
+	fn name(&self) -> &str {
+		self.0
+	}
+
+	fn handle<'a>(&'a self, session: Session<'a, C>) -> SessionFuture<'a> {
+		Box::pin(handler::handle_client(session.stream, session.service, session.interaction, session.limits, session.chatgpt))
+	}
+}
+
+// HTTP/1.x, or HTTP/2 when the client negotiated it.
+struct Web(&'static str);
+
+impl<C: ChatService> ProtocolHandler<C> for Web {
+	fn name(&self) -> &str {
+		self.0
+	}
+
+	fn handle<'a>(&'a self, session: Session<'a, C>) -> SessionFuture<'a> {
+		if session.negotiated_h2 {
+			Box::pin(http2::handle_client(session.stream, session.service, session.interaction, session.limits, session.chatgpt))
+		} else {
+			Box::pin(http1::handle_client(session.stream, session.service, session.interaction, session.limits, session.chatgpt))
+		}
+	}
+}
+
+struct Kerberos(KerberosConfig);
+
+impl<C: ChatService> ProtocolHandler<C> for Kerberos {
+	fn name(&self) -> &str {
+		"Kerberos"
+	}
+
+	fn handle<'a>(&'a self, session: Session<'a, C>) -> SessionFuture<'a> {
+		Box::pin(kerberos::handle_client(session.stream, &self.0, session.limits))
+	}
+}
+
+struct Rsync(RsyncConfig);
+
+impl<C: ChatService> ProtocolHandler<C> for Rsync {
+	fn name(&self) -> &str {
+		"rsync"
+	}
+
+	fn handle<'a>(&'a self, session: Session<'a, C>) -> SessionFuture<'a> {
+		Box::pin(rsync::handle_client(session.stream, &self.0, session.limits))
+	}
+}
+
+// The git daemon, serving the decoy repository; see git.
+struct GitDaemon;
+
+impl<C: ChatService> ProtocolHandler<C> for GitDaemon {
+	fn name(&self) -> &str {
+		"git"
+	}
+
+	fn handle<'a>(&'a self, session: Session<'a, C>) -> SessionFuture<'a> {
+		Box::pin(git::handle_client(session.stream, session.limits))
+	}
+}
+
+struct PluginHandler(Arc<Plugin>);
+
+impl<C: ChatService> ProtocolHandler<C> for PluginHandler {
+	fn name(&self) -> &str {
+		self.0.name()
+	}
+
+	fn handle<'a>(&'a self, session: Session<'a, C>) -> SessionFuture<'a> {
+		Box::pin(plugins::handle_client(session.stream, &self.0, session.limits))
+	}
+}
+
+// Service name -> the handler that emulates it.
+pub struct Registry<C> {
+	handlers: HashMap<&'static str, Arc<dyn ProtocolHandler<C>>>,
+}
+
+impl<C: ChatService> Registry<C> {
+	// Every built-in emulation.
+	pub fn builtin() -> Registry<C> {
+		let mut registry = Registry { handlers: HashMap::new() };
+		registry.register("http", Web("HTTP"));
+		registry.register("https", Web("HTTPS"));
+		//@todo: Implement more realistic SMTP and FTP responses
+		registry.register("smtp", LineBased("SMTP"));
+		registry.register("ftp", LineBased("FTP"));
+		registry.register("git", GitDaemon);
+		registry
+	}
+
+	// The built-in emulations plus those that take their settings from the config.
+	pub fn from_config(app_config: &AppConfig) -> Registry<C> {
+		let mut registry = Registry::builtin();
+		registry.register("kerberos", Kerberos(app_config.kerberos.clone()));
+		registry.register("rsync", Rsync(app_config.rsync.clone()));
+		registry
+	}
+
+	pub fn register(&mut self, service: &'static str, handler: impl ProtocolHandler<C> + 'static) {
+		self.handlers.insert(service, Arc::new(handler));
+	}
+
+	// The handler for a listener's port, or None if nothing there can talk to actors.
+	pub fn resolve(&self, app_config: &AppConfig, port: u16) -> Option<Arc<dyn ProtocolHandler<C>>> {
+		if let Some(plugin) = plugins::for_port(&app_config.plugins, port) {
+			return Some(Arc::new(PluginHandler(plugin)));
+		}
+		self.for_service(app_config.ports.find(port)?)
+	}
+
+	fn for_service(&self, service: &PortConfig) -> Option<Arc<dyn ProtocolHandler<C>>> {
+		self.handlers.get(service.name.as_str()).cloned()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::chat_service::MockChatService;
+
+	fn service(name: &str, port: u16) -> PortConfig {
+		PortConfig {
+			name: name.to_string(),
+			enabled: true,
+			port,
+			tls: false,
+			banner: None,
+			static_response: None,
+		}
+	}
+
+	#[test]
+	fn services_resolve_by_name_wherever_they_listen() {
+		let mut registry = Registry::<MockChatService>::builtin();
+		let name = |handler: Option<Arc<dyn ProtocolHandler<MockChatService>>>| handler.map(|h| h.name().to_string());
+
+		assert_eq!(name(registry.for_service(&service("http", 8080))).as_deref(), Some("HTTP"));
+		assert_eq!(name(registry.for_service(&service("smtp", 2525))).as_deref(), Some("SMTP"));
+		// Configured, but nothing emulates it yet
+		assert!(registry.for_service(&service("ssh", 22)).is_none());
+		registry.register("ssh", LineBased("SSH"));
+		assert_eq!(name(registry.for_service(&service("ssh", 22))).as_deref(), Some("SSH"));
+	}
+}
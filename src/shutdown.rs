@@ -0,0 +1,181 @@
+// Coordinates a graceful shutdown: listeners stop accepting as soon as it is
+// requested, and in-flight sessions get a grace period to finish before exit.
+// Sessions waiting on an idle actor are closed straight away, as if the actor
+// had hung up, so they don't hold up the exit for the whole grace period.
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{watch, Notify};
+use tokio::time::timeout;
+
+#[derive(Clone)]
+pub struct Shutdown {
+	requested: watch::Sender<bool>,
+	active: Arc<AtomicUsize>,
+	idle: Arc<Notify>,
+}
+
+// Held by a session for as long as it runs; dropping it lets a drain finish.
+pub struct SessionGuard {
+	active: Arc<AtomicUsize>,
+	idle: Arc<Notify>,
+}
+
+impl Drop for SessionGuard {
+	fn drop(&mut self) {
+		if self.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+			self.idle.notify_waiters();
+		}
+	}
+}
+
+impl Shutdown {
+	pub fn new() -> Shutdown {
+		Shutdown {
+			requested: watch::Sender::new(false),
+			active: Arc::default(),
+			idle: Arc::default(),
+		}
+	}
+
+	pub fn trigger(&self) {
+		self.requested.send_replace(true);
+	}
+
+	// Resolves once shutdown has been triggered.
+	pub async fn requested(&self) {
+		let mut requested = self.requested.subscribe();
+		let _ = requested.wait_for(|requested| *requested).await;
+	}
+
+	// `stream`, reading as closed by the actor once shutdown is requested
+	// while nothing is waiting to be read.
+	pub fn closing<S>(&self, stream: S) -> ClosingStream<S> {
+		let shutdown = self.clone();
+		ClosingStream {
+			inner: stream,
+			requested: Box::pin(async move { shutdown.requested().await }),
+		}
+	}
+
+	pub fn track_session(&self) -> SessionGuard {
+		self.active.fetch_add(1, Ordering::SeqCst);
+		SessionGuard {
+			active: self.active.clone(),
+			idle: self.idle.clone(),
+		}
+	}
+
+	pub fn active_sessions(&self) -> usize {
+		self.active.load(Ordering::SeqCst)
+	}
+
+	// Wait up to `grace` for every tracked session to finish, returning how many
+	// were still running when it ran out.
+	pub async fn drain(&self, grace: Duration) -> usize {
+		let _ = timeout(grace, async {
+			loop {
+				let idle = self.idle.notified();
+				if self.active_sessions() == 0 {
+					return;
+				}
+				idle.await;
+			}
+		})
+		.await;
+		self.active_sessions()
+	}
+}
+
+pub struct ClosingStream<S> {
+	inner: S,
+	requested: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ClosingStream<S> {
+	fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+		match Pin::new(&mut self.inner).poll_read(cx, buf) {
+			// Nothing from the actor: end of stream if we're shutting down
+			Poll::Pending => self.requested.as_mut().poll(cx).map(Ok),
+			ready => ready,
+		}
+	}
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ClosingStream<S> {
+	fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+		Pin::new(&mut self.inner).poll_write(cx, buf)
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_shutdown(cx)
+	}
+}
+
+// Wait for Ctrl-C, or SIGTERM from the service manager on Unix.
+pub async fn wait_for_signal() {
+	#[cfg(unix)]
+	{
+		use tokio::signal::unix::{signal, SignalKind};
+		match signal(SignalKind::terminate()) {
+			Ok(mut terminate) => {
+				tokio::select! {
+					_ = tokio::signal::ctrl_c() => {}
+					_ = terminate.recv() => {}
+				}
+				return;
+			}
+			Err(e) => tracing::warn!("Failed to install SIGTERM handler: {}", e),
+		}
+	}
+	let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn drain_waits_for_sessions_to_finish() {
+		let shutdown = Shutdown::new();
+		let session = shutdown.track_session();
+		let finisher = tokio::spawn(async move {
+			tokio::time::sleep(Duration::from_millis(50)).await;
+			drop(session);
+		});
+		assert_eq!(shutdown.drain(Duration::from_secs(5)).await, 0);
+		finisher.await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn idle_sessions_read_as_closed_on_shutdown() {
+		use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+		let shutdown = Shutdown::new();
+		let (mut actor, stream) = duplex(64);
+		let mut stream = shutdown.closing(stream);
+		actor.write_all(b"ls\n").await.unwrap();
+		let mut buffer = [0; 64];
+		assert_eq!(stream.read(&mut buffer).await.unwrap(), 3);
+
+		let reader = tokio::spawn(async move { stream.read(&mut buffer).await.unwrap() });
+		tokio::time::sleep(Duration::from_millis(20)).await;
+		shutdown.trigger();
+		assert_eq!(reader.await.unwrap(), 0);
+	}
+
+	#[tokio::test]
+	async fn drain_gives_up_after_the_grace_period() {
+		let shutdown = Shutdown::new();
+		let _session = shutdown.track_session();
+		assert_eq!(shutdown.drain(Duration::from_millis(20)).await, 1);
+	}
+}
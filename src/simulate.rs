@@ -0,0 +1,291 @@
+use serde::Deserialize;
+use std::error::Error;
+use std::fs;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+use crate::chat_service::MockChatService;
+use crate::config::AppConfig;
+use crate::prelude::*;
+use crate::shutdown::Shutdown;
+use crate::sinks::SessionSinks;
+use crate::tls::Certificates;
+
+// How long we wait for the honeypot to answer each step before moving on.
+pub const READ_TIMEOUT: Duration = Duration::from_millis(500);
+// Listeners are spawned right before the scenarios run, so give them a moment to bind.
+pub const CONNECT_ATTEMPTS: u32 = 10;
+
+#[derive(Debug, Deserialize)]
+struct ScenarioFile {
+	#[serde(rename = "scenario")]
+	scenarios: Vec<Scenario>,
+}
+
+// A scripted attacker: connect to a port and send each step in turn.
+#[derive(Debug, Deserialize)]
+pub struct Scenario {
+	pub name: String,
+	pub port: u16,
+	// Address to attack. Defaults to the persona that exposes `port`.
+	#[serde(default)]
+	pub target: Option<String>,
+	pub steps: Vec<String>,
+	#[serde(default)]
+	pub delay_ms: u64,
+	// Number of back-to-back connections, e.g. for brute force attempts.
+	#[serde(default = "Scenario::default_connections")]
+	pub connections: u32,
+}
+
+impl Scenario {
+	fn default_connections() -> u32 {
+		1
+	}
+}
+
+#[derive(Debug, Default)]
+struct Outcome {
+	connections: u32,
+	steps_sent: u32,
+	responses: u32,
+	bytes_received: usize,
+	errors: Vec<String>,
+}
+
+// Drive the real listeners with the scenarios in `scenario_file`, answering
+// with a mock ChatService, and print what happened to each one.
+pub async fn run(scenario_file: &str, mut app_config: AppConfig) {
+	let scenarios = match load_scenarios(scenario_file) {
+		Ok(scenarios) => scenarios,
+		Err(e) => {
+			error!("Failed to load scenario file {}: {}", scenario_file, e);
+			println!("Failed to load scenario file {}: {}", scenario_file, e);
+			return;
+		}
+	};
+	info!("Simulating {} scenarios from {}", scenarios.len(), scenario_file);
+
+	// Scripted traffic comes from loopback, so keep it away from firewall and
+	// intel sinks that would ban or report the host itself. Every other sink
+	// records the simulated sessions as it would real ones.
+	app_config.crowdsec.enabled = false;
+	app_config.blocklist.enabled = false;
+	let http_client = match crate::http_client::build_client(&app_config.http) {
+		Ok(client) => client,
+		Err(e) => {
+			error!("Failed to build the HTTP client: {}", e);
+			return;
+		}
+	};
+	let sinks = SessionSinks::open(&app_config, &http_client);
+	let chat = MockChatService::new("simulated response");
+	let shutdown = Shutdown::new();
+	let handles = crate::spawn_listeners(&app_config, &shutdown, &sinks, &Certificates::load(&app_config, None), |_, _| chat.clone());
+
+	for scenario in &scenarios {
+		let target = match resolve_target(scenario, &app_config) {
+			Some(target) => target,
+			None => {
+				println!("[{}] SKIPPED: no persona listens on port {}", scenario.name, scenario.port);
+				continue;
+			}
+		};
+
+		let started = Instant::now();
+		let outcome = run_scenario(scenario, &target).await;
+		let status = if outcome.errors.is_empty() { "OK" } else { "FAILED" };
+		println!(
+			"[{}] {} against {}: {} connections, {} steps sent, {} responses ({} bytes) in {:?}",
+			scenario.name,
+			status,
+			target,
+			outcome.connections,
+			outcome.steps_sent,
+			outcome.responses,
+			outcome.bytes_received,
+			started.elapsed()
+		);
+		for e in &outcome.errors {
+			println!("    {}", e);
+		}
+	}
+
+	shutdown.trigger();
+	for handle in handles {
+		let _ = handle.await;
+	}
+	sinks.flush().await;
+}
+
+pub fn load_scenarios(scenario_file: &str) -> Result<Vec<Scenario>, Box<dyn Error>> {
+	let contents = fs::read_to_string(scenario_file)?;
+	let file: ScenarioFile = toml::from_str(&contents)?;
+	Ok(file.scenarios)
+}
+
+pub fn resolve_target(scenario: &Scenario, app_config: &AppConfig) -> Option<String> {
+	if let Some(target) = &scenario.target {
+		return Some(target.clone());
+	}
+
+	let persona = app_config
+		.personas()
+		.into_iter()
+		.find(|p| p.ports.contains(&scenario.port))?;
+	// Wildcard binds are reached over loopback
+	let host = match persona.bind_address.as_str() {
+		"0.0.0.0" => "127.0.0.1".to_string(),
+		"::" => "[::1]".to_string(),
+		addr if addr.contains(':') => format!("[{}]", addr),
+		addr => addr.to_string(),
+	};
+	Some(format!("{}:{}", host, scenario.port))
+}
+
+async fn run_scenario(scenario: &Scenario, target: &str) -> Outcome {
+	let mut outcome = Outcome::default();
+
+	for _ in 0..scenario.connections {
+		let mut stream = match connect(target).await {
+			Ok(stream) => stream,
+			Err(e) => {
+				outcome.errors.push(format!("connect to {} failed: {}", target, e));
+				return outcome;
+			}
+		};
+		outcome.connections += 1;
+
+		// Swallow the banner, if the service sends one
+		let mut buffer = [0; 4096];
+		if let Ok(Ok(n)) = timeout(READ_TIMEOUT, stream.read(&mut buffer)).await {
+			outcome.bytes_received += n;
+		}
+
+		for step in &scenario.steps {
+			if let Err(e) = stream.write_all(step.as_bytes()).await {
+				outcome.errors.push(format!("send failed after {} steps: {}", outcome.steps_sent, e));
+				break;
+			}
+			outcome.steps_sent += 1;
+
+			match timeout(READ_TIMEOUT, stream.read(&mut buffer)).await {
+				Ok(Ok(0)) => {
+					outcome.errors.push(format!("connection closed after {} steps", outcome.steps_sent));
+					break;
+				}
+				Ok(Ok(n)) => {
+					outcome.responses += 1;
+					outcome.bytes_received += n;
+				}
+				Ok(Err(e)) => {
+					outcome.errors.push(format!("read failed: {}", e));
+					break;
+				}
+				// No answer is a legitimate outcome for some services
+				Err(_) => {}
+			}
+
+			sleep(Duration::from_millis(scenario.delay_ms)).await;
+		}
+	}
+
+	outcome
+}
+
+pub async fn connect(target: &str) -> std::io::Result<TcpStream> {
+	let mut attempt = 1;
+	loop {
+		match TcpStream::connect(target).await {
+			Ok(stream) => return Ok(stream),
+			Err(e) if attempt >= CONNECT_ATTEMPTS => return Err(e),
+			Err(_) => {
+				attempt += 1;
+				sleep(Duration::from_millis(100)).await;
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::path::Path;
+	use crate::config::Persona;
+
+	fn scenario(port: u16, target: Option<&str>) -> Scenario {
+		Scenario {
+			name: "test".to_string(),
+			port,
+			target: target.map(str::to_string),
+			steps: vec!["USER admin\r\n".to_string(), "PASS admin\r\n".to_string()],
+			delay_ms: 0,
+			connections: 2,
+		}
+	}
+
+	#[test]
+	fn the_example_scenarios_load() {
+		let example = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenarios.toml.example");
+		let scenarios = load_scenarios(&example.to_string_lossy()).unwrap();
+		let ftp = scenarios.iter().find(|s| s.name == "ftp brute force").unwrap();
+		assert_eq!((ftp.port, ftp.connections, ftp.delay_ms, ftp.steps.len()), (21, 3, 100, 4));
+		// Unset fields take their defaults
+		assert!(scenarios.iter().all(|s| s.connections >= 1));
+		assert!(load_scenarios("/nonexistent/scenarios.toml").is_err());
+	}
+
+	#[test]
+	fn targets_resolve_to_the_persona_exposing_the_port() {
+		let config_file = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/Config.toml");
+		let mut app_config = AppConfig::from_config(&config_file.to_string_lossy()).unwrap();
+		let persona = |bind_address: &str, ports: Vec<u16>| Persona { name: bind_address.to_string(), bind_address: bind_address.to_string(), ports, ..Persona::default_persona() };
+		app_config.personas = vec![
+			persona("0.0.0.0", vec![21]),
+			persona("::", vec![25]),
+			persona("10.0.0.12", vec![80]),
+			persona("fd00::12", vec![443]),
+		];
+
+		// Wildcard binds are reached over loopback, IPv6 in brackets
+		assert_eq!(resolve_target(&scenario(21, None), &app_config).as_deref(), Some("127.0.0.1:21"));
+		assert_eq!(resolve_target(&scenario(25, None), &app_config).as_deref(), Some("[::1]:25"));
+		assert_eq!(resolve_target(&scenario(80, None), &app_config).as_deref(), Some("10.0.0.12:80"));
+		assert_eq!(resolve_target(&scenario(443, None), &app_config).as_deref(), Some("[fd00::12]:443"));
+		assert_eq!(resolve_target(&scenario(22, None), &app_config), None);
+		// An explicit target wins, even for a port nobody listens on
+		assert_eq!(resolve_target(&scenario(22, Some("192.0.2.1:22")), &app_config).as_deref(), Some("192.0.2.1:22"));
+	}
+
+	// A line service that greets, then answers each line, or hangs up on the first.
+	async fn service(answers: bool) -> String {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let target = listener.local_addr().unwrap().to_string();
+		task::spawn(async move {
+			while let Ok((mut stream, _)) = listener.accept().await {
+				task::spawn(async move {
+					let _ = stream.write_all(b"220 ready\r\n").await;
+					let mut buffer = [0; 1024];
+					while matches!(stream.read(&mut buffer).await, Ok(n) if n > 0) && answers {
+						let _ = stream.write_all(b"530 denied\r\n").await;
+					}
+				});
+			}
+		});
+		target
+	}
+
+	#[tokio::test]
+	async fn scenarios_count_connections_steps_and_responses() {
+		let target = service(true).await;
+		let outcome = run_scenario(&scenario(21, None), &target).await;
+		assert!(outcome.errors.is_empty(), "{:?}", outcome.errors);
+		assert_eq!((outcome.connections, outcome.steps_sent, outcome.responses), (2, 4, 4));
+		assert_eq!(outcome.bytes_received, 2 * ("220 ready\r\n".len() + 2 * "530 denied\r\n".len()));
+
+		let target = service(false).await;
+		let outcome = run_scenario(&scenario(21, None), &target).await;
+		assert_eq!(outcome.errors[0], "connection closed after 1 steps");
+		assert_eq!((outcome.steps_sent, outcome.responses), (2, 0));
+	}
+}
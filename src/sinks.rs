@@ -0,0 +1,179 @@
+// Outputs that act on a finished session beyond the main log: firewall feeds
+// and threat intel sharing. Opened once and shared by every listener.
+use reqwest::Client;
+use std::net::SocketAddr;
+use time::OffsetDateTime;
+use crate::auth_log::{self, AuthLog};
+use crate::blocklist::Blocklist;
+use crate::config::AppConfig;
+use crate::crowdsec::CrowdSec;
+use crate::flow_export::FlowExporter;
+use crate::log_batcher::LogBatcher;
+use crate::zeek::ConnLog;
+use crate::events::SessionEvent;
+use crate::prelude::*;
+
+// Why a session's source deserves reporting or banning.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Offense {
+	// The actor tried to log in this many times.
+	LoginAttempts(usize),
+	// The actor sent this many payloads without trying to log in.
+	Probe(usize),
+}
+
+impl Offense {
+	pub fn of(service: &str, events: &[SessionEvent]) -> Option<Offense> {
+		let login_attempts = auth_log::auth_failures(service, events).len();
+		let payloads = events.iter().filter(|e| matches!(e, SessionEvent::Received { .. })).count();
+		if login_attempts > 0 {
+			Some(Offense::LoginAttempts(login_attempts))
+		} else if payloads > 0 {
+			Some(Offense::Probe(payloads))
+		} else {
+			None
+		}
+	}
+}
+
+// What a sink gets to see about one session.
+pub struct SessionSummary<'a> {
+	pub service: &'a str,
+	pub hostname: &'a str,
+	pub actor: SocketAddr,
+	// The honeypot's end of the connection.
+	pub local: SocketAddr,
+	pub started_at: OffsetDateTime,
+	pub events: &'a [SessionEvent],
+}
+
+impl SessionSummary<'_> {
+	// Payload bytes from the actor, as far as the events record them.
+	pub fn bytes_received(&self) -> u64 {
+		self.events
+			.iter()
+			.map(|event| match event {
+				SessionEvent::Received { data } => data.len() as u64,
+				_ => 0,
+			})
+			.sum()
+	}
+
+	pub fn bytes_sent(&self) -> u64 {
+		self.events
+			.iter()
+			.map(|event| match event {
+				SessionEvent::BannerSent { banner } => banner.len() as u64 + 2,
+				SessionEvent::Responded { data, .. } => data.len() as u64,
+				_ => 0,
+			})
+			.sum()
+	}
+}
+
+#[derive(Clone, Default)]
+pub struct SessionSinks {
+	auth_log: Option<AuthLog>,
+	crowdsec: Option<CrowdSec>,
+	blocklist: Option<Blocklist>,
+	flow_exporter: Option<FlowExporter>,
+	conn_log: Option<ConnLog>,
+	log_batcher: Option<LogBatcher>,
+}
+
+impl SessionSinks {
+	// Open every enabled sink. One that fails to open is logged and skipped
+	// rather than keeping the honeypot down.
+	pub fn open(app_config: &AppConfig, http_client: &Client) -> SessionSinks {
+		let auth_log = AuthLog::open(&app_config.auth_log).unwrap_or_else(|e| {
+			error!("Failed to open auth log {}: {}", app_config.auth_log.path, e);
+			None
+		});
+		SessionSinks {
+			auth_log,
+			crowdsec: CrowdSec::new(&app_config.crowdsec, http_client.clone()),
+			blocklist: Blocklist::new(&app_config.blocklist),
+			flow_exporter: FlowExporter::new(&app_config.flow_export).unwrap_or_else(|e| {
+				error!("Failed to set up flow export to {}: {}", app_config.flow_export.collector, e);
+				None
+			}),
+			conn_log: ConnLog::open(&app_config.zeek).unwrap_or_else(|e| {
+				error!("Failed to open Zeek conn.log {}: {}", app_config.zeek.path, e);
+				None
+			}),
+			log_batcher: LogBatcher::start(&app_config.log_upload),
+		}
+	}
+
+	// Write out whatever the sinks still hold, at shutdown. Flow records and
+	// alerts go out as each session finishes, so there is nothing to flush
+	// for those.
+	pub async fn flush(&self) {
+		if let Some(auth_log) = &self.auth_log {
+			if let Err(e) = auth_log.flush() {
+				error!("Failed to flush auth log: {}", e);
+			}
+		}
+		if let Some(conn_log) = &self.conn_log {
+			if let Err(e) = conn_log.flush() {
+				error!("Failed to flush Zeek conn.log: {}", e);
+			}
+		}
+		if let Some(log_batcher) = &self.log_batcher {
+			log_batcher.flush().await;
+		}
+	}
+
+	pub async fn session_finished(&self, session: SessionSummary<'_>) {
+		if let Some(flow_exporter) = &self.flow_exporter {
+			if let Err(e) = flow_exporter.export(&session) {
+				error!("Failed to export flow records: {}", e);
+			}
+		}
+		if let Some(conn_log) = &self.conn_log {
+			if let Err(e) = conn_log.record(&session) {
+				error!("Failed to write Zeek conn.log: {}", e);
+			}
+		}
+
+		if let Some(log_batcher) = &self.log_batcher {
+			log_batcher.collect(&session);
+		}
+
+		// Log any login attempts where fail2ban can pick them up
+		if let Some(auth_log) = &self.auth_log {
+			let failures = auth_log::auth_failures(session.service, session.events);
+			if let Err(e) = auth_log.record(&failures, session.actor, session.hostname) {
+				error!("Failed to write auth log: {}", e);
+			}
+		}
+
+		let Some(offense) = Offense::of(session.service, session.events) else {
+			return;
+		};
+		if let Some(crowdsec) = &self.crowdsec {
+			if let Some(alert) = crowdsec.alert(session.service, session.actor, session.started_at, offense) {
+				match crowdsec.push(alert).await {
+					Ok(()) => info!("Reported {} to CrowdSec", session.actor.ip()),
+					Err(e) => error!("Failed to push CrowdSec alert: {}", e),
+				}
+			}
+		}
+
+		if let Some(blocklist) = &self.blocklist {
+			let tags: Vec<&str> = session
+				.events
+				.iter()
+				.filter_map(|event| match event {
+					SessionEvent::Tagged { tag } => Some(tag.as_str()),
+					_ => None,
+				})
+				.collect();
+			match blocklist.ban(session.actor.ip(), offense, &tags).await {
+				Ok(true) => info!("Added {} to the blocklist", session.actor.ip()),
+				Ok(false) => {}
+				Err(e) => error!("Failed to update blocklist: {}", e),
+			}
+		}
+	}
+}
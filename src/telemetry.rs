@@ -0,0 +1,81 @@
+use metrics_exporter_prometheus::PrometheusBuilder;
+use serde::Deserialize;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
+use crate::prelude::*;
+
+// Latency buckets in seconds, from real-service timing (~1ms) up to slow LLM round trips.
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MetricsConfig {
+	#[serde(default)]
+	pub enabled: bool,
+	// Where Prometheus can scrape /metrics. Keep this off the honeypot's public interfaces.
+	#[serde(default = "MetricsConfig::default_listen")]
+	pub listen: String,
+}
+
+impl MetricsConfig {
+	fn default_listen() -> String {
+		"127.0.0.1:9898".to_string()
+	}
+}
+
+impl Default for MetricsConfig {
+	fn default() -> Self {
+		MetricsConfig {
+			enabled: false,
+			listen: Self::default_listen(),
+		}
+	}
+}
+
+// Start the Prometheus exporter. Until this is called every record_* is a no-op.
+pub fn install(config: &MetricsConfig) -> Result<(), Box<dyn Error>> {
+	if !config.enabled {
+		return Ok(());
+	}
+	let listen: SocketAddr = config.listen.parse()?;
+	PrometheusBuilder::new()
+		.with_http_listener(listen)
+		.set_buckets(LATENCY_BUCKETS)?
+		.install()?;
+	info!("Serving metrics on http://{}/metrics", listen);
+	Ok(())
+}
+
+// Time from accepting a connection to the first byte we send back.
+pub fn record_time_to_first_byte(protocol: &str, latency: Duration) {
+	metrics::histogram!("rustbucket_time_to_first_byte_seconds", "protocol" => protocol.to_string())
+		.record(latency.as_secs_f64());
+}
+
+// Time from receiving an actor's payload to finishing our reply.
+pub fn record_exchange(protocol: &str, latency: Duration) {
+	metrics::histogram!("rustbucket_exchange_seconds", "protocol" => protocol.to_string())
+		.record(latency.as_secs_f64());
+}
+
+// Time spent waiting on the LLM for a single reply.
+pub fn record_llm_request(protocol: &str, latency: Duration) {
+	metrics::histogram!("rustbucket_llm_request_seconds", "protocol" => protocol.to_string())
+		.record(latency.as_secs_f64());
+}
+
+// A stage of the S3 log pipeline failed; see log_batcher.
+pub fn record_log_pipeline_error(stage: &'static str) {
+	metrics::counter!("rustbucket_log_pipeline_errors_total", "stage" => stage).increment(1);
+}
+
+// Batches and session objects waiting for S3. Climbs for as long as uploads fail.
+pub fn record_pending_log_batches(count: usize) {
+	metrics::gauge!("rustbucket_log_pipeline_pending_batches").set(count as f64);
+}
+
+// What a search engine has indexed about the host; see exposure.
+pub fn record_exposure(source: &str, services: usize, flagged_as_honeypot: bool) {
+	metrics::gauge!("rustbucket_exposure_indexed_services", "source" => source.to_string()).set(services as f64);
+	metrics::gauge!("rustbucket_exposure_flagged_honeypot", "source" => source.to_string()).set(if flagged_as_honeypot { 1.0 } else { 0.0 });
+}
@@ -0,0 +1,394 @@
+// TLS for listeners with `tls = true`. Each persona gets a self-signed
+// certificate that looks like the ones real servers ship with: its hostname
+// plus the usual service names under the same domain, or a router's default
+// name when it has no hostname, with a snakeoil-style validity window that
+// started a while ago. Certificates are saved so they survive restarts (a
+// fresh certificate on every boot is a tell), and any PEM pair placed in
+// `cert_dir` is served as-is instead. With [acme] a persona's self-signed
+// certificate is replaced by a browser-valid one once it has been issued.
+// A listener presents the certificate of whichever persona claims the SNI
+// hostname the client asked for, so one port can front many virtual hosts.
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rcgen::{CertificateParams, DnType, KeyPair, SerialNumber};
+use reqwest::Client;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration as StdDuration;
+use time::{Duration, OffsetDateTime};
+use tokio_rustls::rustls::crypto::ring::{self, sign::any_supported_type};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use crate::acme;
+use crate::config::{AppConfig, Persona, PortConfig};
+use crate::http2;
+use crate::prelude::*;
+
+// ALPN protocol of ACME TLS-ALPN-01 validation handshakes (RFC 8737).
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+// How long a client gets to complete the handshake before it is dropped.
+pub const HANDSHAKE_TIMEOUT: StdDuration = StdDuration::from_secs(10);
+
+// A session's connection, either plain TCP or TLS over it.
+pub trait SessionStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> SessionStream for T {}
+
+// Names consumer routers put in their factory certificates.
+const ROUTER_NAMES: &[&str] = &[
+	"router.asus.com",
+	"www.routerlogin.net",
+	"tplinkwifi.net",
+	"fritz.box",
+	"myrouter.local",
+	"linksyssmartwifi.com",
+];
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TlsConfig {
+	// Where each persona's `<name>.crt` and `<name>.key` are kept.
+	#[serde(default = "TlsConfig::default_cert_dir")]
+	pub cert_dir: String,
+}
+
+impl TlsConfig {
+	fn default_cert_dir() -> String {
+		"./certs".to_string()
+	}
+}
+
+impl Default for TlsConfig {
+	fn default() -> Self {
+		TlsConfig {
+			cert_dir: Self::default_cert_dir(),
+		}
+	}
+}
+
+// The certificate a persona's TLS listeners present. It can be swapped while
+// running, and during ACME validation `acme-tls/1` handshakes are answered
+// with the challenge certificate for the requested name instead.
+#[derive(Debug)]
+pub struct CertResolver {
+	current: RwLock<Arc<CertifiedKey>>,
+	challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+	pub fn new(key: CertifiedKey) -> CertResolver {
+		CertResolver {
+			current: RwLock::new(Arc::new(key)),
+			challenges: RwLock::default(),
+		}
+	}
+
+	pub fn set_certificate(&self, key: CertifiedKey) {
+		*self.current.write().unwrap() = Arc::new(key);
+	}
+
+	pub fn set_challenge(&self, domain: &str, key: Option<CertifiedKey>) {
+		let mut challenges = self.challenges.write().unwrap();
+		match key {
+			Some(key) => challenges.insert(domain.to_string(), Arc::new(key)),
+			None => challenges.remove(domain),
+		};
+	}
+}
+
+impl ResolvesServerCert for CertResolver {
+	fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+		let acme_validation = client_hello.alpn().is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN));
+		if acme_validation {
+			let domain = client_hello.server_name()?;
+			return self.challenges.read().unwrap().get(domain).cloned();
+		}
+		Some(self.current.read().unwrap().clone())
+	}
+}
+
+// Picks the certificate of the persona whose server_names include the SNI
+// hostname, falling back to the persona that owns the listener.
+#[derive(Debug)]
+struct SniResolver {
+	default: Arc<CertResolver>,
+	virtual_hosts: HashMap<String, Arc<CertResolver>>,
+}
+
+impl ResolvesServerCert for SniResolver {
+	fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+		let resolver = client_hello
+			.server_name()
+			.and_then(|name| self.virtual_hosts.get(&name.to_ascii_lowercase()))
+			.unwrap_or(&self.default)
+			.clone();
+		resolver.resolve(client_hello)
+	}
+}
+
+// Everything a persona's TLS listener needs: the acceptor, and the other
+// personas it serves by SNI along with their chat services.
+#[derive(Clone)]
+pub struct TlsListener<C> {
+	pub acceptor: TlsAcceptor,
+	pub virtual_hosts: Arc<HashMap<String, (Persona, C)>>,
+}
+
+impl<C> TlsListener<C> {
+	pub fn virtual_host(&self, server_name: Option<&str>) -> Option<&(Persona, C)> {
+		self.virtual_hosts.get(&server_name?.to_ascii_lowercase())
+	}
+}
+
+// Every persona's certificate, set up once at startup.
+#[derive(Clone, Default)]
+pub struct Certificates {
+	resolvers: HashMap<String, (Arc<CertResolver>, bool)>,
+	// SNI hostname, lowercased, to the persona serving it.
+	server_names: HashMap<String, String>,
+}
+
+impl Certificates {
+	// Load or mint a certificate for each persona that serves TLS, and start
+	// ACME issuance for the configured persona when an HTTP client is given.
+	pub fn load(app_config: &AppConfig, http_client: Option<&Client>) -> Certificates {
+		let mut resolvers = HashMap::new();
+		let mut server_names = HashMap::new();
+		for persona in app_config.personas() {
+			let services: Vec<_> = persona.ports.iter().filter_map(|p| app_config.ports.find(*p)).filter(|s| s.tls).collect();
+			if services.is_empty() && persona.server_names.is_empty() {
+				continue;
+			}
+			let key = match load_or_mint(&app_config.tls, &persona, &services) {
+				Ok(key) => key,
+				Err(e) => {
+					error!("Failed to set up TLS for persona {}: {}", persona.name, e);
+					continue;
+				}
+			};
+			let resolver = Arc::new(CertResolver::new(key));
+			let acme = app_config.acme.enabled && app_config.acme.persona == persona.name;
+			if let (true, Some(http_client)) = (acme, http_client) {
+				acme::spawn(app_config.acme.clone(), http_client.clone(), resolver.clone());
+			}
+			for name in &persona.server_names {
+				server_names.insert(name.to_ascii_lowercase(), persona.name.clone());
+			}
+			resolvers.insert(persona.name.clone(), (resolver, acme));
+		}
+		Certificates { resolvers, server_names }
+	}
+
+	// Web services offer HTTP/2 by ALPN, preferred over HTTP/1.1 like real servers do.
+	pub fn acceptor(&self, persona: &str, web: bool) -> Option<TlsAcceptor> {
+		let (default, mut acme) = self.resolvers.get(persona).cloned()?;
+		let mut virtual_hosts = HashMap::new();
+		for (name, owner) in self.server_names.iter().filter(|(_, owner)| *owner != persona) {
+			if let Some((resolver, owner_acme)) = self.resolvers.get(owner) {
+				virtual_hosts.insert(name.clone(), resolver.clone());
+				acme |= owner_acme;
+			}
+		}
+		let resolver = Arc::new(SniResolver { default, virtual_hosts });
+		// Named explicitly: the AWS SDK enables rustls's other provider, so there is no process-wide default
+		let mut server_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+			.with_safe_default_protocol_versions()
+			.ok()?
+			.with_no_client_auth()
+			.with_cert_resolver(resolver);
+		if web {
+			server_config.alpn_protocols = vec![http2::ALPN_H2.to_vec(), b"http/1.1".to_vec()];
+		}
+		if acme {
+			server_config.alpn_protocols.push(ACME_TLS_ALPN.to_vec());
+		}
+		Some(TlsAcceptor::from(Arc::new(server_config)))
+	}
+}
+
+// Turn a PEM certificate chain and private key into what rustls serves.
+pub fn certified_key(cert_pem: &[u8], key_pem: &[u8]) -> Result<CertifiedKey, Box<dyn Error + Send + Sync>> {
+	let certs = CertificateDer::pem_slice_iter(cert_pem).collect::<Result<Vec<_>, _>>()?;
+	let key = PrivateKeyDer::from_pem_slice(key_pem)?;
+	Ok(CertifiedKey::new(certs, any_supported_type(&key)?))
+}
+
+// A persona's saved certificate, minted on first use.
+fn load_or_mint(config: &TlsConfig, persona: &Persona, services: &[&PortConfig]) -> Result<CertifiedKey, Box<dyn Error + Send + Sync>> {
+	let (cert_path, key_path) = paths(config, persona);
+	if !cert_path.exists() || !key_path.exists() {
+		let (cert_pem, key_pem) = mint(persona, services, &mut rand::thread_rng())?;
+		fs::create_dir_all(&config.cert_dir)?;
+		fs::write(&cert_path, cert_pem)?;
+		fs::write(&key_path, key_pem)?;
+		info!("Generated TLS certificate {} for persona {}", cert_path.display(), persona.name);
+	}
+	certified_key(&fs::read(&cert_path)?, &fs::read(&key_path)?)
+}
+
+fn paths(config: &TlsConfig, persona: &Persona) -> (PathBuf, PathBuf) {
+	let dir = Path::new(&config.cert_dir);
+	(dir.join(format!("{}.crt", persona.name)), dir.join(format!("{}.key", persona.name)))
+}
+
+// Common name and subject alternative names for a persona's certificate.
+fn subject_names<R: Rng>(persona: &Persona, services: &[&PortConfig], rng: &mut R) -> (String, Vec<String>) {
+	let Some(hostname) = persona.hostname.clone().or_else(|| persona.server_names.first().cloned()) else {
+		let name = ROUTER_NAMES.choose(rng).unwrap().to_string();
+		return (name.clone(), vec![name]);
+	};
+
+	let mut names = vec![hostname.clone()];
+	// "mail.example.com" serves its siblings under "example.com"
+	if let Some((_, domain)) = hostname.split_once('.').filter(|(_, domain)| domain.contains('.')) {
+		for service in services {
+			let prefix = match service.name.as_str() {
+				"http" | "https" => "www",
+				"smtp" => "mail",
+				"ftp" => "ftp",
+				_ => continue,
+			};
+			let name = format!("{}.{}", prefix, domain);
+			if !names.contains(&name) {
+				names.push(name);
+			}
+		}
+	}
+	for name in &persona.server_names {
+		if !names.contains(name) {
+			names.push(name.clone());
+		}
+	}
+	(hostname, names)
+}
+
+// A self-signed certificate and its key, both PEM encoded.
+fn mint<R: Rng>(persona: &Persona, services: &[&PortConfig], rng: &mut R) -> Result<(String, String), Box<dyn Error + Send + Sync>> {
+	let (common_name, names) = subject_names(persona, services, rng);
+	let mut params = CertificateParams::new(names)?;
+	params.distinguished_name.push(DnType::CommonName, common_name);
+	// Like a distro's snakeoil certificate: made at install time, good for ten years
+	params.not_before = OffsetDateTime::now_utc() - Duration::days(rng.gen_range(30..700));
+	params.not_after = params.not_before + Duration::days(3650);
+	params.serial_number = Some(SerialNumber::from(rng.gen::<[u8; 16]>().to_vec()));
+
+	let key = KeyPair::generate()?;
+	let cert = params.self_signed(&key)?;
+	Ok((cert.pem(), key.serialize_pem()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rand::rngs::StdRng;
+	use rand::SeedableRng;
+	use tokio::io::duplex;
+	use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+	use tokio_rustls::rustls::crypto::ring::default_provider;
+	use tokio_rustls::rustls::pki_types::{ServerName, UnixTime};
+	use tokio_rustls::rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+	use tokio_rustls::TlsConnector;
+
+	// Scanners don't check certificates either.
+	#[derive(Debug)]
+	struct AcceptAnything;
+
+	impl ServerCertVerifier for AcceptAnything {
+		fn verify_server_cert(&self, _: &CertificateDer, _: &[CertificateDer], _: &ServerName, _: &[u8], _: UnixTime) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+			Ok(ServerCertVerified::assertion())
+		}
+
+		fn verify_tls12_signature(&self, _: &[u8], _: &CertificateDer, _: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+			Ok(HandshakeSignatureValid::assertion())
+		}
+
+		fn verify_tls13_signature(&self, _: &[u8], _: &CertificateDer, _: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+			Ok(HandshakeSignatureValid::assertion())
+		}
+
+		fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+			default_provider().signature_verification_algorithms.supported_schemes()
+		}
+	}
+
+	// The certificate a client asking for `server_name` is shown.
+	async fn presented(acceptor: TlsAcceptor, server_name: &str) -> CertificateDer<'static> {
+		let (client, server) = duplex(16 * 1024);
+		let config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+			.with_safe_default_protocol_versions()
+			.unwrap()
+			.dangerous().with_custom_certificate_verifier(Arc::new(AcceptAnything)).with_no_client_auth();
+		let server_name = ServerName::try_from(server_name.to_string()).unwrap();
+		let (client, _) = tokio::join!(TlsConnector::from(Arc::new(config)).connect(server_name, client), acceptor.accept(server));
+		client.unwrap().get_ref().1.peer_certificates().unwrap()[0].clone()
+	}
+
+	fn service(name: &str) -> PortConfig {
+		PortConfig {
+			name: name.to_string(),
+			enabled: true,
+			port: 0,
+			tls: true,
+			banner: None,
+			static_response: None,
+		}
+	}
+
+	#[test]
+	fn certificates_cover_the_persona_and_its_services() {
+		let mut rng = StdRng::seed_from_u64(1);
+		let mail = Persona { hostname: Some("mx1.acme-corp.com".to_string()), ..Persona::default_persona() };
+		let (common_name, names) = subject_names(&mail, &[&service("smtp"), &service("https")], &mut rng);
+		assert_eq!(common_name, "mx1.acme-corp.com");
+		assert_eq!(names, ["mx1.acme-corp.com", "mail.acme-corp.com", "www.acme-corp.com"]);
+
+		let (common_name, names) = subject_names(&Persona::default_persona(), &[&service("https")], &mut rng);
+		assert!(ROUTER_NAMES.contains(&common_name.as_str()));
+		assert_eq!(names, [common_name]);
+
+		let portal = Persona { server_names: vec!["vpn.corp.example".to_string(), "sso.corp.example".to_string()], ..Persona::default_persona() };
+		let (common_name, names) = subject_names(&portal, &[], &mut rng);
+		assert_eq!(common_name, "vpn.corp.example");
+		assert_eq!(names, ["vpn.corp.example", "sso.corp.example"]);
+	}
+
+	#[tokio::test]
+	async fn listeners_present_the_certificate_of_the_persona_named_by_sni() {
+		let mut rng = StdRng::seed_from_u64(2);
+		let front = Persona { hostname: Some("www.globex.io".to_string()), ..Persona::default_persona() };
+		let portal = Persona { name: "portal".to_string(), server_names: vec!["vpn.corp.example".to_string()], ..Persona::default_persona() };
+		let mut certificates = Certificates::default();
+		for persona in [&front, &portal] {
+			let (cert_pem, key_pem) = mint(persona, &[&service("https")], &mut rng).unwrap();
+			let resolver = Arc::new(CertResolver::new(certified_key(cert_pem.as_bytes(), key_pem.as_bytes()).unwrap()));
+			certificates.resolvers.insert(persona.name.clone(), (resolver, false));
+		}
+		certificates.server_names.insert("vpn.corp.example".to_string(), "portal".to_string());
+		let cert_of = |persona: &str| certificates.resolvers[persona].0.current.read().unwrap().cert[0].clone();
+
+		let acceptor = certificates.acceptor("default", true).unwrap();
+		assert_eq!(presented(acceptor.clone(), "VPN.corp.example").await, cert_of("portal"));
+		assert_eq!(presented(acceptor.clone(), "www.globex.io").await, cert_of("default"));
+		assert_eq!(presented(acceptor, "10.0.0.13").await, cert_of("default"));
+	}
+
+	#[test]
+	fn minted_certificates_are_loaded_back_and_kept() {
+		let dir = std::env::temp_dir().join(format!("rustbucket-tls-{}", std::process::id()));
+		let config = TlsConfig { cert_dir: dir.to_string_lossy().to_string() };
+		let persona = Persona { hostname: Some("www.example.org".to_string()), ..Persona::default_persona() };
+
+		let first = load_or_mint(&config, &persona, &[&service("https")]).unwrap();
+		let second = load_or_mint(&config, &persona, &[&service("https")]).unwrap();
+		assert_eq!(first.cert, second.cert);
+
+		fs::remove_dir_all(dir).unwrap();
+	}
+}
@@ -0,0 +1,359 @@
+// Self-update for fleets. Every check interval the release manifest at
+// manifest_url (any HTTP(S) location, such as an S3 bucket or a release
+// server) is fetched:
+//
+//     { "version": "1.1.0",
+//       "artifacts": { "x86_64-linux": { "url": "https://.../rustbucket", "sha256": "<hex>", "signature": "<base64>" } } }
+//
+// Each artifact's signature is an Ed25519 signature, checked against
+// public_key, over the line
+//
+//     rustbucket <version> <platform> <sha256 of the binary, hex>
+//
+// so neither the version nor the platform can be changed without the key, and
+// an old build can't be passed off as a new one. Only a verified release that
+// is newer, and not a pre-release unless allow_prerelease is set, is
+// downloaded, and only a binary with the signed digest replaces the running
+// executable, which is kept beside it as .old. Once in-flight
+// sessions have drained, rustbucket execs the new binary with the same
+// arguments, so everything kept on disk (certificates, fingerprint, pending
+// log batches) carries over. On Windows the new binary is used from the next
+// start.
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use reqwest::Client;
+use ring::digest::{digest, SHA256};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::future::pending;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::interval;
+use crate::http_client::read_body;
+use crate::prelude::*;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+// Set for the new process, so it can say what it was updated from.
+const UPDATED_FROM_ENV: &str = "RUSTBUCKET_UPDATED_FROM";
+// Largest manifest and release binary that will be downloaded.
+const MAX_MANIFEST_BYTES: usize = 1024 * 1024;
+const MAX_BINARY_BYTES: usize = 256 * 1024 * 1024;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct UpdateConfig {
+	#[serde(default)]
+	pub enabled: bool,
+	#[serde(default)]
+	pub manifest_url: String,
+	// Base64 Ed25519 public key that release binaries must be signed with.
+	#[serde(default)]
+	pub public_key: String,
+	#[serde(default = "UpdateConfig::default_check_interval_secs")]
+	pub check_interval_secs: u64,
+	// Also install release candidates and other x.y.z-suffix versions.
+	#[serde(default)]
+	pub allow_prerelease: bool,
+}
+
+impl UpdateConfig {
+	fn default_check_interval_secs() -> u64 {
+		6 * 60 * 60
+	}
+}
+
+impl Default for UpdateConfig {
+	fn default() -> Self {
+		UpdateConfig {
+			enabled: false,
+			manifest_url: String::new(),
+			public_key: String::new(),
+			check_interval_secs: Self::default_check_interval_secs(),
+			allow_prerelease: false,
+		}
+	}
+}
+
+#[derive(Debug, Deserialize)]
+struct Release {
+	version: String,
+	// Keyed by platform, e.g. "x86_64-linux" or "aarch64-macos".
+	artifacts: HashMap<String, Artifact>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Artifact {
+	url: String,
+	sha256: String,
+	signature: String,
+}
+
+// A release that has replaced the executable on disk.
+pub struct Installed {
+	pub version: String,
+	pub exe: PathBuf,
+}
+
+fn platform() -> String {
+	format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS)
+}
+
+// A release version, x.y.z with an optional pre-release (1.1.0-rc.1).
+// Missing parts count as 0, so 1.0 is 1.0.0, and build metadata is ignored.
+#[derive(Debug, PartialEq, Eq)]
+struct Version {
+	core: Vec<u64>,
+	pre_release: Vec<String>,
+}
+
+impl Version {
+	fn parse(version: &str) -> Version {
+		let version = version.trim().trim_start_matches('v');
+		let version = version.split('+').next().unwrap_or_default();
+		let (core, pre_release) = version.split_once('-').unwrap_or((version, ""));
+		let mut core: Vec<u64> = core.split('.').map(|part| part.parse().unwrap_or(0)).collect();
+		while core.last() == Some(&0) {
+			core.pop();
+		}
+		let pre_release = pre_release.split('.').filter(|id| !id.is_empty()).map(str::to_string).collect();
+		Version { core, pre_release }
+	}
+
+	fn is_pre_release(&self) -> bool {
+		!self.pre_release.is_empty()
+	}
+}
+
+// As semver orders them: a pre-release sorts before its release, and its
+// numeric identifiers compare as numbers (rc.10 is after rc.9).
+impl Ord for Version {
+	fn cmp(&self, other: &Version) -> Ordering {
+		self.core.cmp(&other.core).then_with(|| match (self.is_pre_release(), other.is_pre_release()) {
+			(false, false) => Ordering::Equal,
+			(false, true) => Ordering::Greater,
+			(true, false) => Ordering::Less,
+			(true, true) => {
+				let key = |v: &Version| -> Vec<(bool, u64, String)> {
+					v.pre_release
+						.iter()
+						.map(|id| {
+							let number = id.parse::<u64>().ok();
+							// Numeric identifiers sort before alphanumeric ones
+							(number.is_none(), number.unwrap_or(0), id.clone())
+						})
+						.collect()
+				};
+				key(self).cmp(&key(other))
+			}
+		})
+	}
+}
+
+impl PartialOrd for Version {
+	fn partial_cmp(&self, other: &Version) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+// Whether `candidate` should replace `current`: a later version, and not a
+// pre-release unless those were asked for.
+fn is_newer(candidate: &str, current: &str, allow_prerelease: bool) -> bool {
+	let candidate = Version::parse(candidate);
+	(allow_prerelease || !candidate.is_pre_release()) && candidate > Version::parse(current)
+}
+
+// Log the update that started this process, if one did.
+pub fn log_updated_from() {
+	if let Ok(from) = std::env::var(UPDATED_FROM_ENV) {
+		info!("Updated from {} to {}", from, VERSION);
+	}
+}
+
+// Resolves once a newer release has been installed over the running
+// executable, and never if updates are off or can't be verified.
+pub async fn installed(config: &UpdateConfig, client: &Client) -> Installed {
+	if !config.enabled {
+		return pending().await;
+	}
+	let public_key = match STANDARD.decode(config.public_key.trim()) {
+		Ok(key) if !config.manifest_url.is_empty() => key,
+		Ok(_) => {
+			error!("[update] is enabled without a manifest_url, not checking for updates");
+			return pending().await;
+		}
+		Err(e) => {
+			error!("Invalid [update] public_key, not checking for updates: {}", e);
+			return pending().await;
+		}
+	};
+
+	let mut checks = interval(Duration::from_secs(config.check_interval_secs.max(60)));
+	// Staged for the next start where the running binary can't be replaced in place
+	let mut staged = None;
+	loop {
+		checks.tick().await;
+		let current = staged.as_deref().unwrap_or(VERSION);
+		let (version, binary) = match check(&config.manifest_url, &public_key, current, config.allow_prerelease, client).await {
+			Ok(Some(release)) => release,
+			Ok(None) => continue,
+			Err(e) => {
+				warn!("Update check against {} failed: {}", config.manifest_url, e);
+				continue;
+			}
+		};
+		match install(&binary) {
+			Ok(exe) if cfg!(unix) => {
+				info!("Installed rustbucket {}, restarting into it", version);
+				return Installed { version, exe };
+			}
+			Ok(_) => {
+				info!("Installed rustbucket {}, it will run from the next start", version);
+				staged = Some(version);
+			}
+			Err(e) => error!("Failed to install rustbucket {}: {}", version, e),
+		}
+	}
+}
+
+// What a release artifact's signature covers.
+fn signed_message(version: &str, platform: &str, sha256: &str) -> String {
+	format!("rustbucket {} {} {}", version, platform, sha256.trim().to_ascii_lowercase())
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+	digest(&SHA256, data).as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// The body of a successful response, refusing any larger than `max` bytes.
+async fn download(client: &Client, url: &str, max: usize) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+	read_body(client.get(url).send().await?.error_for_status()?, max).await
+}
+
+// The newer release for this platform, downloaded and verified, if there is one.
+async fn check(manifest_url: &str, public_key: &[u8], current: &str, allow_prerelease: bool, client: &Client) -> Result<Option<(String, Vec<u8>)>, Box<dyn Error + Send + Sync>> {
+	let release: Release = serde_json::from_slice(&download(client, manifest_url, MAX_MANIFEST_BYTES).await?)?;
+	let Some(artifact) = release.artifacts.get(&platform()) else {
+		info!("Release {} has no build for {}", release.version, platform());
+		return Ok(None);
+	};
+	// The version is only trusted once its signature checks out
+	let signature = STANDARD.decode(artifact.signature.trim())?;
+	UnparsedPublicKey::new(&ED25519, public_key)
+		.verify(signed_message(&release.version, &platform(), &artifact.sha256).as_bytes(), &signature)
+		.map_err(|_| format!("the signature on release {} for {} does not match public_key", release.version, platform()))?;
+	if !is_newer(&release.version, current, allow_prerelease) {
+		return Ok(None);
+	}
+	info!("Downloading rustbucket {} from {}", release.version, artifact.url);
+	let binary = download(client, &artifact.url, MAX_BINARY_BYTES).await?;
+	if !sha256_hex(&binary).eq_ignore_ascii_case(artifact.sha256.trim()) {
+		return Err(format!("{} does not match the signed sha256 for release {}", artifact.url, release.version).into());
+	}
+	Ok(Some((release.version, binary)))
+}
+
+// Swap `binary` in for the running executable, keeping the old one as .old.
+fn install(binary: &[u8]) -> io::Result<PathBuf> {
+	let exe = std::env::current_exe()?;
+	let staged = exe.with_extension("new");
+	fs::write(&staged, binary)?;
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::PermissionsExt;
+		fs::set_permissions(&staged, fs::Permissions::from_mode(0o755))?;
+	}
+	// A running executable can be renamed everywhere, but only overwritten on Unix
+	let old = exe.with_extension("old");
+	let _ = fs::remove_file(&old);
+	fs::rename(&exe, &old)?;
+	fs::rename(&staged, &exe)?;
+	Ok(exe)
+}
+
+// Replace this process with the installed release. Only returns if that fails.
+#[cfg(unix)]
+pub fn restart(installed: &Installed) -> io::Error {
+	use std::os::unix::process::CommandExt;
+	let e = std::process::Command::new(&installed.exe)
+		.args(std::env::args_os().skip(1))
+		.env(UPDATED_FROM_ENV, VERSION)
+		.exec();
+	io::Error::new(e.kind(), format!("failed to start rustbucket {}: {}", installed.version, e))
+}
+
+#[cfg(not(unix))]
+pub fn restart(installed: &Installed) -> io::Error {
+	io::Error::other(format!("restart {} to run rustbucket {}", installed.exe.display(), installed.version))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::mock_http::{MockHttpServer, MockResponse};
+	use ring::rand::SystemRandom;
+	use ring::signature::{Ed25519KeyPair, KeyPair};
+
+	#[test]
+	fn versions_compare_numerically() {
+		assert!(is_newer("1.10.0", "1.9.3", false));
+		assert!(is_newer("v2.0.0", "1.99.99", false));
+		assert!(!is_newer("1.0.0", "1.0.0", false));
+		assert!(!is_newer("0.9.0", "1.0.0", false));
+		assert!(!is_newer("1.0", "1.0.0", false));
+		assert!(!is_newer("1.0.0+build.7", "1.0", false));
+		assert!(is_newer("1.0.1", "1.0", false));
+	}
+
+	#[test]
+	fn pre_releases_are_only_installed_when_allowed() {
+		assert!(!is_newer("1.0.1-rc.1", "1.0.0", false));
+		assert!(is_newer("1.0.1-rc.1", "1.0.0", true));
+		// A release candidate comes before its release
+		assert!(!is_newer("1.0.1-rc.1", "1.0.1", true));
+		assert!(is_newer("1.0.1", "1.0.1-rc.1", false));
+		assert!(is_newer("1.0.1-rc.10", "1.0.1-rc.9", true));
+		assert!(is_newer("1.0.1-rc.1", "1.0.1-beta.2", true));
+		assert!(is_newer("1.0.1-rc.1.1", "1.0.1-rc.1", true));
+	}
+
+	#[tokio::test]
+	async fn only_releases_signed_with_the_fleet_key_are_accepted() {
+		let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+		let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+		let binary = "#!/bin/sh\necho new release\n";
+		let server = MockHttpServer::start().await;
+		let sha256 = sha256_hex(binary.as_bytes());
+		let manifest = |version: &str, signed_version: &str| {
+			let signature = key.sign(signed_message(signed_version, &platform(), &sha256).as_bytes());
+			let artifact = serde_json::json!({ "url": format!("{}/rustbucket", server.base_url()), "sha256": sha256, "signature": STANDARD.encode(signature) });
+			MockResponse::new(200, &serde_json::json!({ "version": version, "artifacts": { platform(): artifact } }).to_string())
+		};
+		let manifest_url = format!("{}/release.json", server.base_url());
+		let client = Client::new();
+
+		server.respond_with(manifest("9.0.0", "9.0.0"));
+		server.respond_with(MockResponse::new(200, binary));
+		let (version, downloaded) = check(&manifest_url, key.public_key().as_ref(), VERSION, false, &client).await.unwrap().unwrap();
+		assert_eq!((version.as_str(), downloaded.as_slice()), ("9.0.0", binary.as_bytes()));
+
+		// An old signed build relabelled as a newer version is refused before any download
+		server.respond_with(manifest("9.9.9", "9.0.0"));
+		let error = check(&manifest_url, key.public_key().as_ref(), VERSION, false, &client).await.unwrap_err();
+		assert!(error.to_string().contains("does not match"), "{}", error);
+
+		// A binary other than the one signed for
+		server.respond_with(manifest("9.0.0", "9.0.0"));
+		server.respond_with(MockResponse::new(200, "#!/bin/sh\necho tampered\n"));
+		let error = check(&manifest_url, key.public_key().as_ref(), VERSION, false, &client).await.unwrap_err();
+		assert!(error.to_string().contains("signed sha256"), "{}", error);
+
+		// Nothing is downloaded once we're on the latest release
+		server.respond_with(manifest("9.0.0", "9.0.0"));
+		assert!(check(&manifest_url, key.public_key().as_ref(), "9.0.0", false, &client).await.unwrap().is_none());
+		assert_eq!(server.requests().len(), 6);
+	}
+}
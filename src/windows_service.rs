@@ -0,0 +1,246 @@
+// Run as a native Windows service, so a Windows host can pose as a Windows
+// target with rustbucket started at boot like any other service:
+//
+//     rustbucket service install     register it (from an elevated prompt)
+//     rustbucket service uninstall   stop and remove it
+//     rustbucket service run         what the service control manager starts
+//
+// The service works from the executable's directory, so Config.toml, logs and
+// certs live beside it. Warnings and errors also go to the Application event
+// log under the service's name, where Windows admins and their tooling look.
+use std::ffi::{c_void, OsStr};
+use std::fmt::Write as _;
+use std::io;
+use std::iter;
+use std::os::windows::ffi::OsStrExt;
+use std::panic;
+use std::ptr::{null, null_mut};
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::sync::LazyLock;
+use tokio::sync::Notify;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use windows_sys::Win32::Foundation::{HANDLE, ERROR_CALL_NOT_IMPLEMENTED, ERROR_FAILED_SERVICE_CONTROLLER_CONNECT, ERROR_SERVICE_SPECIFIC_ERROR, NO_ERROR};
+use windows_sys::Win32::System::EventLog::{RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE};
+use windows_sys::Win32::System::Services::*;
+use windows_sys::core::PWSTR;
+use crate::prelude::*;
+
+const SERVICE_NAME: &str = "rustbucket";
+const DISPLAY_NAME: &str = "Rustbucket";
+const DESCRIPTION: &str = "Rustbucket honeypot listeners";
+// The standard DELETE access right, needed to remove the service.
+const DELETE: u32 = 0x0001_0000;
+// How long the service control manager should wait on a start or stop before
+// assuming it hung. Stopping includes the shutdown grace period.
+const PENDING_WAIT_HINT_MS: u32 = 30_000;
+// Every message goes out under one event id; the text carries the detail.
+const EVENT_ID: u32 = 1;
+
+static STATUS: AtomicPtr<c_void> = AtomicPtr::new(null_mut());
+static RUNNING_AS_SERVICE: AtomicBool = AtomicBool::new(false);
+static STOP: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+// `rustbucket service <command>`.
+pub fn main(args: &[String]) -> io::Result<()> {
+	match args.first().map(String::as_str) {
+		Some("install") => install(),
+		Some("uninstall") => uninstall(),
+		Some("run") => dispatch(),
+		_ => Err(io::Error::other("usage: rustbucket service install|uninstall|run")),
+	}
+}
+
+// A service control manager handle, closed when dropped.
+struct ScHandle(SC_HANDLE);
+
+impl ScHandle {
+	fn new(handle: SC_HANDLE) -> io::Result<ScHandle> {
+		if handle.is_null() {
+			Err(io::Error::last_os_error())
+		} else {
+			Ok(ScHandle(handle))
+		}
+	}
+}
+
+impl Drop for ScHandle {
+	fn drop(&mut self) {
+		unsafe { CloseServiceHandle(self.0) };
+	}
+}
+
+fn wide(s: &str) -> Vec<u16> {
+	OsStr::new(s).encode_wide().chain(iter::once(0)).collect()
+}
+
+fn install() -> io::Result<()> {
+	let command = format!("\"{}\" service run", std::env::current_exe()?.display());
+	let (name, display_name, command) = (wide(SERVICE_NAME), wide(DISPLAY_NAME), wide(&command));
+	let mut description = wide(DESCRIPTION);
+	unsafe {
+		let manager = ScHandle::new(OpenSCManagerW(null(), null(), SC_MANAGER_CREATE_SERVICE))?;
+		let service = ScHandle::new(CreateServiceW(
+			manager.0,
+			name.as_ptr(),
+			display_name.as_ptr(),
+			SERVICE_ALL_ACCESS,
+			SERVICE_WIN32_OWN_PROCESS,
+			SERVICE_AUTO_START,
+			SERVICE_ERROR_NORMAL,
+			command.as_ptr(),
+			null(),
+			null_mut(),
+			null(),
+			null(),
+			null(),
+		))?;
+		let description = SERVICE_DESCRIPTIONW { lpDescription: description.as_mut_ptr() };
+		ChangeServiceConfig2W(service.0, SERVICE_CONFIG_DESCRIPTION, &description as *const _ as *const c_void);
+	}
+	println!("Installed the {} service. Start it with `sc start {}`.", SERVICE_NAME, SERVICE_NAME);
+	Ok(())
+}
+
+fn uninstall() -> io::Result<()> {
+	let name = wide(SERVICE_NAME);
+	unsafe {
+		let manager = ScHandle::new(OpenSCManagerW(null(), null(), SC_MANAGER_CONNECT))?;
+		let service = ScHandle::new(OpenServiceW(manager.0, name.as_ptr(), SERVICE_STOP | SERVICE_QUERY_STATUS | DELETE))?;
+		// Fails if it isn't running, which is fine
+		let mut status = std::mem::zeroed::<SERVICE_STATUS>();
+		ControlService(service.0, SERVICE_CONTROL_STOP, &mut status);
+		if DeleteService(service.0) == 0 {
+			return Err(io::Error::last_os_error());
+		}
+	}
+	println!("Removed the {} service.", SERVICE_NAME);
+	Ok(())
+}
+
+// Hand this thread to the service control manager until the service stops.
+fn dispatch() -> io::Result<()> {
+	let mut name = wide(SERVICE_NAME);
+	let table = [
+		SERVICE_TABLE_ENTRYW { lpServiceName: name.as_mut_ptr(), lpServiceProc: Some(service_main) },
+		SERVICE_TABLE_ENTRYW { lpServiceName: null_mut(), lpServiceProc: None },
+	];
+	if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+		let e = io::Error::last_os_error();
+		if e.raw_os_error() == Some(ERROR_FAILED_SERVICE_CONTROLLER_CONNECT as i32) {
+			return Err(io::Error::other(format!("`service run` is for the service control manager; use `sc start {}`", SERVICE_NAME)));
+		}
+		return Err(e);
+	}
+	Ok(())
+}
+
+unsafe extern "system" fn service_main(_argc: u32, _argv: *mut PWSTR) {
+	let name = wide(SERVICE_NAME);
+	let handle = RegisterServiceCtrlHandlerExW(name.as_ptr(), Some(control_handler), null());
+	if handle.is_null() {
+		return;
+	}
+	STATUS.store(handle, Ordering::SeqCst);
+	set_status(SERVICE_START_PENDING, NO_ERROR);
+	// Nothing may unwind out of here into the service control manager
+	let exit_code = match panic::catch_unwind(serve) {
+		Ok(Ok(())) => NO_ERROR,
+		Ok(Err(e)) => e.raw_os_error().map_or(ERROR_SERVICE_SPECIFIC_ERROR, |code| code as u32),
+		Err(_) => ERROR_SERVICE_SPECIFIC_ERROR,
+	};
+	set_status(SERVICE_STOPPED, exit_code);
+}
+
+fn serve() -> io::Result<()> {
+	// Services start in System32
+	if let Some(dir) = std::env::current_exe()?.parent() {
+		std::env::set_current_dir(dir)?;
+	}
+	RUNNING_AS_SERVICE.store(true, Ordering::SeqCst);
+	let runtime = tokio::runtime::Runtime::new()?;
+	set_status(SERVICE_RUNNING, NO_ERROR);
+	runtime.block_on(crate::run(stop_requested()))
+}
+
+async fn stop_requested() {
+	info!("Running as the {} service", SERVICE_NAME);
+	STOP.notified().await;
+	info!("Stopping at the request of the service control manager");
+}
+
+unsafe extern "system" fn control_handler(control: u32, _event_type: u32, _event_data: *mut c_void, _context: *mut c_void) -> u32 {
+	match control {
+		SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+			set_status(SERVICE_STOP_PENDING, NO_ERROR);
+			STOP.notify_one();
+			NO_ERROR
+		}
+		SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+		_ => ERROR_CALL_NOT_IMPLEMENTED,
+	}
+}
+
+fn set_status(state: SERVICE_STATUS_CURRENT_STATE, exit_code: u32) {
+	let pending = matches!(state, SERVICE_START_PENDING | SERVICE_STOP_PENDING);
+	let status = SERVICE_STATUS {
+		dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+		dwCurrentState: state,
+		dwControlsAccepted: if state == SERVICE_RUNNING { SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN } else { 0 },
+		dwWin32ExitCode: exit_code,
+		dwServiceSpecificExitCode: 0,
+		dwCheckPoint: 0,
+		dwWaitHint: if pending { PENDING_WAIT_HINT_MS } else { 0 },
+	};
+	unsafe { SetServiceStatus(STATUS.load(Ordering::SeqCst), &status) };
+}
+
+// Forwards warnings and errors, and the service's own start and stop
+// messages, to the Application event log.
+pub struct EventLog(HANDLE);
+
+// Event log handles may be used from any thread.
+unsafe impl Send for EventLog {}
+unsafe impl Sync for EventLog {}
+
+impl EventLog {
+	// Only when running as a service; interactively the console and log files are enough.
+	pub fn open() -> Option<EventLog> {
+		if !RUNNING_AS_SERVICE.load(Ordering::SeqCst) {
+			return None;
+		}
+		let handle = unsafe { RegisterEventSourceW(null(), wide(SERVICE_NAME).as_ptr()) };
+		(!handle.is_null()).then_some(EventLog(handle))
+	}
+}
+
+impl<S: Subscriber> Layer<S> for EventLog {
+	fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+		let metadata = event.metadata();
+		let kind = match *metadata.level() {
+			Level::ERROR => EVENTLOG_ERROR_TYPE,
+			Level::WARN => EVENTLOG_WARNING_TYPE,
+			Level::INFO if metadata.target() == module_path!() => EVENTLOG_INFORMATION_TYPE,
+			_ => return,
+		};
+		let mut message = Message(String::new());
+		event.record(&mut message);
+		let text = wide(&format!("{}: {}", metadata.target(), message.0));
+		let strings = [text.as_ptr()];
+		unsafe { ReportEventW(self.0, kind, 0, EVENT_ID, null_mut(), 1, 0, strings.as_ptr(), null()) };
+	}
+}
+
+// An event's message followed by any other fields, as `name=value`.
+struct Message(String);
+
+impl Visit for Message {
+	fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+		if field.name() == "message" {
+			let _ = write!(self.0, "{:?}", value);
+		} else {
+			let _ = write!(self.0, " {}={:?}", field.name(), value);
+		}
+	}
+}
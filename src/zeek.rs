@@ -0,0 +1,222 @@
+// Connection summaries in Zeek's conn.log schema, as TSV (with the usual
+// header) or JSON lines, so Zeek pipelines ingest rustbucket sessions without
+// any mapping. Fields the honeypot can't know, like packet counts, are unset.
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use time::macros::format_description;
+use time::OffsetDateTime;
+use crate::sinks::SessionSummary;
+
+const FIELDS: &str = "ts\tuid\tid.orig_h\tid.orig_p\tid.resp_h\tid.resp_p\tproto\tservice\tduration\torig_bytes\tresp_bytes\tconn_state\tlocal_orig\tlocal_resp\tmissed_bytes\thistory\torig_pkts\torig_ip_bytes\tresp_pkts\tresp_ip_bytes\ttunnel_parents";
+const TYPES: &str = "time\tstring\taddr\tport\taddr\tport\tenum\tstring\tinterval\tcount\tcount\tstring\tbool\tbool\tcount\tstring\tcount\tcount\tcount\tcount\tset[string]";
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ZeekFormat {
+	Tsv,
+	Json,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ZeekConfig {
+	#[serde(default)]
+	pub enabled: bool,
+	#[serde(default = "ZeekConfig::default_format")]
+	pub format: ZeekFormat,
+	#[serde(default = "ZeekConfig::default_path")]
+	pub path: String,
+}
+
+impl ZeekConfig {
+	fn default_format() -> ZeekFormat {
+		ZeekFormat::Json
+	}
+
+	fn default_path() -> String {
+		"./logs/conn.log".to_string()
+	}
+}
+
+impl Default for ZeekConfig {
+	fn default() -> Self {
+		ZeekConfig {
+			enabled: false,
+			format: Self::default_format(),
+			path: Self::default_path(),
+		}
+	}
+}
+
+// One conn.log entry.
+#[derive(Debug, Clone, PartialEq)]
+struct Conn {
+	ts: f64,
+	uid: String,
+	orig_h: String,
+	orig_p: u16,
+	resp_h: String,
+	resp_p: u16,
+	service: Option<String>,
+	duration: f64,
+	orig_bytes: u64,
+	resp_bytes: u64,
+}
+
+impl Conn {
+	fn from_session(session: &SessionSummary, ended_at: OffsetDateTime) -> Conn {
+		Conn {
+			ts: session.started_at.unix_timestamp_nanos() as f64 / 1e9,
+			uid: new_uid(),
+			orig_h: session.actor.ip().to_canonical().to_string(),
+			orig_p: session.actor.port(),
+			resp_h: session.local.ip().to_canonical().to_string(),
+			resp_p: session.local.port(),
+			service: Some(session.service.to_string()).filter(|s| s != "unknown"),
+			duration: (ended_at - session.started_at).as_seconds_f64().max(0.0),
+			orig_bytes: session.bytes_received(),
+			resp_bytes: session.bytes_sent(),
+		}
+	}
+
+	// Every session ran to a normal close from our side, so it is "SF".
+	fn tsv(&self) -> String {
+		format!(
+			"{:.6}\t{}\t{}\t{}\t{}\t{}\ttcp\t{}\t{:.6}\t{}\t{}\tSF\t-\t-\t0\t-\t-\t-\t-\t-\t-",
+			self.ts,
+			self.uid,
+			self.orig_h,
+			self.orig_p,
+			self.resp_h,
+			self.resp_p,
+			self.service.as_deref().unwrap_or("-"),
+			self.duration,
+			self.orig_bytes,
+			self.resp_bytes
+		)
+	}
+
+	// Zeek's JSON writer leaves unset fields out entirely.
+	fn json(&self) -> String {
+		let mut conn = Map::new();
+		conn.insert("ts".to_string(), json!(self.ts));
+		conn.insert("uid".to_string(), json!(self.uid));
+		conn.insert("id.orig_h".to_string(), json!(self.orig_h));
+		conn.insert("id.orig_p".to_string(), json!(self.orig_p));
+		conn.insert("id.resp_h".to_string(), json!(self.resp_h));
+		conn.insert("id.resp_p".to_string(), json!(self.resp_p));
+		conn.insert("proto".to_string(), json!("tcp"));
+		if let Some(service) = &self.service {
+			conn.insert("service".to_string(), json!(service));
+		}
+		conn.insert("duration".to_string(), json!(self.duration));
+		conn.insert("orig_bytes".to_string(), json!(self.orig_bytes));
+		conn.insert("resp_bytes".to_string(), json!(self.resp_bytes));
+		conn.insert("conn_state".to_string(), json!("SF"));
+		conn.insert("missed_bytes".to_string(), json!(0));
+		Value::Object(conn).to_string()
+	}
+}
+
+// Zeek uids are a "C" followed by random base62.
+fn new_uid() -> String {
+	let suffix: String = rand::thread_rng().sample_iter(&Alphanumeric).take(17).map(char::from).collect();
+	format!("C{}", suffix)
+}
+
+#[derive(Clone)]
+pub struct ConnLog {
+	format: ZeekFormat,
+	file: Arc<Mutex<File>>,
+}
+
+impl ConnLog {
+	pub fn open(config: &ZeekConfig) -> Result<Option<ConnLog>, Box<dyn Error>> {
+		if !config.enabled {
+			return Ok(None);
+		}
+		if let Some(parent) = Path::new(&config.path).parent() {
+			fs::create_dir_all(parent)?;
+		}
+		let mut file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+		if config.format == ZeekFormat::Tsv && file.metadata()?.len() == 0 {
+			let opened = OffsetDateTime::now_utc()
+				.format(format_description!("[year]-[month]-[day]-[hour]-[minute]-[second]"))?;
+			write!(
+				file,
+				"#separator \\x09\n#set_separator\t,\n#empty_field\t(empty)\n#unset_field\t-\n#path\tconn\n#open\t{}\n#fields\t{}\n#types\t{}\n",
+				opened, FIELDS, TYPES
+			)?;
+		}
+		Ok(Some(ConnLog {
+			format: config.format,
+			file: Arc::new(Mutex::new(file)),
+		}))
+	}
+
+	pub fn record(&self, session: &SessionSummary) -> std::io::Result<()> {
+		let conn = Conn::from_session(session, OffsetDateTime::now_utc());
+		let line = match self.format {
+			ZeekFormat::Tsv => conn.tsv(),
+			ZeekFormat::Json => conn.json(),
+		};
+		let mut file = self.file.lock().unwrap();
+		writeln!(file, "{}", line)?;
+		file.flush()
+	}
+
+	// Get everything written so far onto disk.
+	pub fn flush(&self) -> std::io::Result<()> {
+		self.file.lock().unwrap().sync_all()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use time::Duration;
+	use crate::events::{ResponseSource, SessionEvent};
+
+	#[test]
+	fn sessions_map_onto_the_conn_log_schema() {
+		let started_at = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+		let events = [
+			SessionEvent::BannerSent { banner: "220 (vsFTPd 3.0.3)".to_string() },
+			SessionEvent::Received { data: "USER root\r\n".to_string() },
+			SessionEvent::Responded { data: "331 Please specify the password.\r\n".to_string(), source: ResponseSource::Static },
+			SessionEvent::Closed,
+		];
+		let session = SessionSummary {
+			service: "ftp",
+			hostname: "ftp.example.com",
+			actor: "[::ffff:203.0.113.5]:51000".parse().unwrap(),
+			local: "192.0.2.10:21".parse().unwrap(),
+			started_at,
+			events: &events,
+		};
+		let mut conn = Conn::from_session(&session, started_at + Duration::milliseconds(1500));
+		assert!(conn.uid.starts_with('C') && conn.uid.len() == 18);
+		conn.uid = "Cabc".to_string();
+
+		assert_eq!(
+			conn.tsv(),
+			"1700000000.000000\tCabc\t203.0.113.5\t51000\t192.0.2.10\t21\ttcp\tftp\t1.500000\t11\t54\tSF\t-\t-\t0\t-\t-\t-\t-\t-\t-"
+		);
+		assert_eq!(conn.tsv().split('\t').count(), FIELDS.split('\t').count());
+		assert_eq!(FIELDS.split('\t').count(), TYPES.split('\t').count());
+
+		let json: Value = serde_json::from_str(&conn.json()).unwrap();
+		assert_eq!(json["id.orig_h"], "203.0.113.5");
+		assert_eq!(json["id.resp_p"], 21);
+		assert_eq!(json["service"], "ftp");
+		assert_eq!(json["orig_bytes"], 11);
+		assert_eq!(json["resp_bytes"], 54);
+		assert!(json.get("orig_pkts").is_none());
+	}
+}